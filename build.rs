@@ -0,0 +1,30 @@
+//! Embeds the git commit and build time for `--version` and the generated records.
+
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    // Honour SOURCE_DATE_EPOCH for reproducible builds.
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .map(|epoch| chrono::NaiveDateTime::from_timestamp(epoch, 0))
+        .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+
+    println!("cargo:rustc-env=SBL_GIT_COMMIT={}", commit);
+    println!(
+        "cargo:rustc-env=SBL_BUILD_TIME={}",
+        build_time.format("%Y-%m-%dT%H:%M:%SZ")
+    );
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -1,6 +1,9 @@
 use ::ssc_billing_logger::openstack;
 use ::ssc_billing_logger::radosgw;
 use ::ssc_billing_logger::records;
+use ::ssc_billing_logger::units::{
+    gigabyte_cost, Bytes, DiskRounding, Gigabytes, Megabytes, MemoryUnit, COST_DECIMAL_PLACES,
+};
 
 #[macro_use]
 extern crate failure;
@@ -8,21 +11,45 @@ extern crate failure;
 extern crate log;
 
 use chrono::{DateTime, Timelike, Utc};
-use num::ToPrimitive;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use url::Url;
 
+/// Crate version along with the git commit and time of the build, see `build.rs`.
+const BUILD_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("SBL_GIT_COMMIT"),
+    ", built ",
+    env!("SBL_BUILD_TIME"),
+    ")"
+);
+
 #[derive(Debug, StructOpt)]
-#[structopt(rename_all = "kebab_case")]
+#[structopt(rename_all = "kebab_case", version = BUILD_VERSION)]
 struct Opt {
-    #[structopt(short, long, parse(from_os_str))]
-    config: PathBuf,
+    /// Configuration file, may be given several times with later files overriding earlier ones
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless = "pretty-print-snapshot",
+        number_of_values = 1
+    )]
+    config: Vec<PathBuf>,
+
+    /// Print a summary of the given snapshot and exit, needs neither configuration nor network
+    #[structopt(long, parse(from_os_str))]
+    pretty_print_snapshot: Option<PathBuf>,
 
+    /// Point the service endpoints at `rewrite_host` of the configuration, `localhost` unless set
     #[structopt(long)]
     rewrite_host: bool,
 
@@ -32,521 +59,6389 @@ struct Opt {
     #[structopt(long, parse(from_os_str))]
     load_snapshot: Option<PathBuf>,
 
+    /// Snapshot from the previous run, used to bill instances deleted since then
+    #[structopt(long, parse(from_os_str))]
+    previous_snapshot: Option<PathBuf>,
+
     #[structopt(long)]
     dry_run: bool,
 
     #[structopt(long)]
     force: bool,
+
+    /// Neither read nor write the persistent state, for ad-hoc and testing runs
+    #[structopt(long)]
+    no_state: bool,
+
+    /// Rebuild the records for a single hour from the snapshot given by --load-snapshot, without
+    /// advancing the persistent state
+    #[structopt(long)]
+    regenerate: Option<DateTime<Utc>>,
+
+    /// Before the current hour, process every hour since the last processed one for which a
+    /// snapshot named <YYYYmmddTHHMMZ>.json exists in the given directory
+    #[structopt(long, parse(from_os_str))]
+    since_last: Option<PathBuf>,
+
+    /// Only bill the project with the given id or name, may be repeated. Needs --load-snapshot
+    /// and writes the records to a separate <hour>.partial-<project ids> file
+    #[structopt(long = "project", number_of_values = 1)]
+    projects: Vec<String>,
+
+    /// Rebuild the records of every snapshot (*.json or *.json.gz) in the given directory in order
+    /// of their time, without advancing the persistent state
+    #[structopt(long, parse(from_os_str))]
+    replay_dir: Option<PathBuf>,
+
+    /// Print the cost of each project by kind of resource, most useful with --dry-run
+    #[structopt(long)]
+    breakdown: bool,
+
+    /// Supplementary flavors by id, in the format of a snapshot's `flavors`, used for flavors
+    /// missing from the snapshot
+    #[structopt(long, parse(from_os_str))]
+    flavors_file: Option<PathBuf>,
+
+    /// Let flavors from --flavors-file replace those present in the snapshot
+    #[structopt(long)]
+    override_flavors: bool,
+
+    /// Check the record files written for the given day and pack them into
+    /// records/<YYYYmmdd>.tar.gz instead of generating records
+    #[structopt(long)]
+    archive_day: Option<chrono::NaiveDate>,
+
+    /// Print the merged configuration with defaults filled in and secrets masked, and exit
+    #[structopt(long)]
+    dump_config: bool,
+
+    /// Print the resource and rates each project resolves to instead of generating records
+    #[structopt(long)]
+    explain_costs: bool,
+
+    /// Format of the written records, either "xml", "xml-v2" or "csv"
+    #[structopt(long, default_value = "xml")]
+    format: records::v1::RecordFormat,
+
+    /// Write the records to separate files, "kind" writes compute records to <time>-compute.xml
+    /// and storage records to <time>-storage.xml
+    #[structopt(long)]
+    split_by: Option<SplitBy>,
+
+    /// Also bill the regions of `merge_regions` of the configuration and write the records of all
+    /// regions to one combined file per hour instead of one file per region
+    #[structopt(long)]
+    merge_regions: bool,
+
+    /// Keep running and bill every hour instead of once, serving the status of the runs as JSON
+    /// and Prometheus metrics over HTTP on the given address, such as 127.0.0.1:9187
+    #[structopt(long)]
+    serve: Option<std::net::SocketAddr>,
+
+    /// Do not print the JSON summary line of the run to stdout
+    #[structopt(long)]
+    no_summary: bool,
+
+    /// Log output format, either "text" or "json"
+    #[structopt(long, env = "LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format_err!("Unknown log format {:?}", s)),
+        }
+    }
+}
+
+/// Region the current run is for, included in JSON log lines once the configuration is loaded.
+static LOG_REGION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        use std::io::Write;
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "region": LOG_REGION.get(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     username: String,
     password: String,
+    /// Domain of both the user and the project, unless overridden below.
+    #[serde(default)]
     domain: String,
+    user_domain: Option<String>,
+    project_domain: Option<String>,
     project: String,
     keystone_url: Url,
 
+    #[serde(default)]
+    auth_flow: openstack::AuthFlow,
+
     site: String,
     resources: BTreeMap<String, String>,
     region: String,
     datadir: String,
-}
 
-type ResourceCosts = BTreeMap<String, Decimal>;
+    #[serde(default)]
+    billing_mode: BillingMode,
 
-#[derive(Debug, Deserialize)]
-pub struct RegionCosts {
-    #[serde(flatten)]
-    resources: BTreeMap<String, ResourceCosts>,
-}
+    #[serde(default)]
+    aggregate_storage: AggregateStorage,
 
-#[derive(Debug, Deserialize)]
-pub struct CostsFile {
-    regions: BTreeMap<String, RegionCosts>,
-}
+    #[serde(default)]
+    negative_values: NegativeValues,
 
-#[derive(Debug, Default)]
-pub struct ProjectBreakdown<'a> {
-    active: Vec<(Option<Decimal>, &'a openstack::nova::Server)>,
-    inert: Vec<(Option<Decimal>, &'a openstack::nova::Server)>,
-    volumes: Vec<(Option<Decimal>, &'a openstack::cinder::Volume)>,
-    images: Vec<(Option<Decimal>, &'a openstack::glance::Image)>,
-}
+    #[serde(default)]
+    on_locked: OnLocked,
 
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
-enum BillingCategory {
-    Active,
-    Inactive,
-    Unbilled,
-}
+    #[serde(default)]
+    records_layout: RecordsLayout,
 
-impl BillingCategory {
-    fn from_status(s: &str) -> BillingCategory {
-        match s {
-            "PAUSED" | "SUSPENDED" | "SOFT_SUSPENDED" | "SOFT_DELETED" | "SHUTOFF" => {
-                BillingCategory::Inactive
-            }
-            "DELETED" | "SHELVED" | "SHELVED_OFFLOADED" => BillingCategory::Unbilled,
-            _ => BillingCategory::Active,
-        }
-    }
-}
+    /// Extra `cr:` elements added to every record, e.g. a benchmark type required by the collector.
+    #[serde(default)]
+    record_extensions: BTreeMap<String, String>,
 
-struct CostLookup<'a> {
-    config: &'a Config,
-    domains: BTreeMap<String, String>,
-    region_costs: &'a RegionCosts,
-    projects: &'a openstack::NameMapping,
-}
+    /// Write a `<name>.xml.sha256` sidecar next to each record file.
+    #[serde(default)]
+    write_checksums: bool,
 
-impl<'a> CostLookup<'a> {
-    fn new(
-        config: &'a Config,
-        costs: &'a CostsFile,
-        domains: &'a openstack::keystone::Domains,
-        projects: &'a openstack::NameMapping,
-    ) -> Option<Self> {
-        let region_costs = costs.regions.get(&config.region)?;
-        let domains = domains
-            .domains
-            .iter()
-            .map(|d| (d.id.clone(), d.name.clone()))
-            .collect();
-        Some(Self {
-            config,
-            domains,
-            projects,
-            region_costs,
-        })
-    }
+    /// Mark each record file with a batch id, also listed in the manifest of the records dir.
+    #[serde(default)]
+    batch_ids: bool,
 
-    fn project_costs_by_id(&'a self, proj_id: &str) -> Option<ProjectCost> {
-        let proj = self.projects.get(proj_id)?;
-        let domain_name = self.domains.get(&proj.domain_id)?;
-        let resource = self.config.resources.get(domain_name)?;
-        let costs = self.region_costs.resources.get(resource)?;
-        Some(ProjectCost { resource, costs })
-    }
-}
+    /// Admin ops API to read bucket statistics from instead of running `radosgw-admin`.
+    radosgw_admin: Option<RadosgwAdmin>,
 
-struct ProjectCost<'a> {
-    pub resource: &'a String,
-    pub costs: &'a ResourceCosts,
-}
+    /// Further regions billed along with `region` by `--merge-regions`, each with the settings
+    /// that differ for it from the rest of the configuration, such as `service_region`.
+    #[serde(default)]
+    merge_regions: BTreeMap<String, serde_json::Value>,
 
-impl<'a> ProjectCost<'a> {
-    fn get(&self, kind: &str) -> Option<Decimal> {
-        self.costs.get(kind).cloned()
-    }
-}
+    /// radosgw usage categories that are not billed as object storage.
+    #[serde(default = "default_object_excluded_usage")]
+    object_excluded_usage: Vec<String>,
 
-#[derive(Debug, Deserialize, Serialize, Default)]
-struct PersistentState {
-    last_timepoint: Option<DateTime<Utc>>,
-}
+    /// Bill object buckets modified during the period only for the part of it after `mtime`.
+    #[serde(default)]
+    object_partial_hours: bool,
 
-#[derive(Debug)]
-struct PersistentStateFile {
-    filename: PathBuf,
-    state: PersistentState,
-}
+    post_write_hook: Option<PostWriteHook>,
 
-impl PersistentStateFile {
-    fn open<P: Into<PathBuf>>(datadir: P) -> Result<PersistentStateFile, failure::Error> {
-        let filename = datadir.into().join("logger-state/state.json");
-        let fh = File::open(&filename);
-        let state = fh
-            .ok()
-            .and_then(|fh| serde_json::from_reader(fh).ok())
-            .unwrap_or_default();
-        Ok(PersistentStateFile { filename, state })
-    }
+    /// Instances created less than this many seconds before the snapshot are not billed.
+    #[serde(default)]
+    compute_min_age: i64,
 
-    fn write(&self) -> Result<(), failure::Error> {
-        let contents = serde_json::to_vec_pretty(&self.state)?;
-        std::fs::write(&self.filename, &contents)?;
-        Ok(())
-    }
-}
+    /// Seconds below which the part of the hour that an instance deleted since the previous
+    /// snapshot was present for is not billed as is. Instances seen in a snapshot are billed for
+    /// the whole hour, and new ones are held back by `compute_min_age` instead.
+    #[serde(default)]
+    min_billable_duration: i64,
 
-const DEFAULT_USER: &str = "default";
-const DEFAULT_ZONE: &str = "default";
+    #[serde(default)]
+    below_min_duration: BelowMinDuration,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Snapshot {
-    version: usize,
-    datetime: DateTime<Utc>,
-    servers: Vec<openstack::nova::Server>,
-    flavors: openstack::Flavors,
-    images: Vec<openstack::glance::Image>,
-    volumes: Vec<openstack::cinder::Volume>,
-    object_bucket_stats: Option<Vec<radosgw::admin::BucketStats>>,
-    users: openstack::NameMapping,
-    projects: openstack::NameMapping,
-    domains: openstack::keystone::Domains,
-}
+    /// Availability zones whose instances and volumes are not billed.
+    #[serde(default)]
+    exclude_zones: Vec<String>,
 
-fn main() -> Result<(), failure::Error> {
-    env_logger::init();
+    /// Billing category by instance status, overriding the built-in ones.
+    #[serde(default)]
+    status_categories: BTreeMap<String, BillingCategory>,
 
-    let opt = Opt::from_args();
-    info!("Loading configuration from {:?}", &opt.config);
-    let cfg: Config = serde_json::from_reader(File::open(&opt.config)?)?;
-    let datadir = PathBuf::from(&cfg.datadir);
-    info!("Opening persistent state file in {}", &cfg.datadir);
-    let mut persistent_state = PersistentStateFile::open(&cfg.datadir)?;
+    /// Billing category of statuses that are neither built in nor in `status_categories`.
+    #[serde(default)]
+    unknown_status_category: BillingCategory,
 
-    let costs_path = datadir.join("logger-state/costs.json");
-    info!("Reading costs from {:?}", &costs_path);
-    let costs: CostsFile = serde_json::from_reader(File::open(&costs_path)?)?;
+    /// Cinder volume statuses that are not billed.
+    #[serde(default = "default_unbilled_volume_statuses")]
+    unbilled_volume_statuses: Vec<String>,
 
-    let now = Utc::now();
-    let this_run_datetime = now.date().and_hms(now.hour(), 0, 0);
-    if !opt.force {
-        if let Some(last_run) = persistent_state.state.last_timepoint {
-            if last_run == this_run_datetime {
-                return Ok(());
-            }
-        }
-    }
+    /// Rules for instances carrying the given Nova server tags.
+    #[serde(default)]
+    server_tags: BTreeMap<String, TagRule>,
 
-    let snap = if let Some(snap_path) = opt.load_snapshot {
-        let snap: Snapshot =
-            serde_json::from_str(&std::fs::read_to_string(snap_path).unwrap()).unwrap();
-        if snap.version < 3 {
-            bail!("Snapshot version predates domains, exiting.");
-        }
-        snap
-    } else {
-        let credentials = openstack::Credentials {
-            username: cfg.username.clone(),
-            password: cfg.password.clone(),
-            domain: cfg.domain.clone(),
-            project: cfg.project.clone(),
-        };
+    #[serde(default)]
+    memory_unit: MemoryUnit,
 
-        let session = openstack::Session::new(
-            &credentials,
-            &cfg.keystone_url,
-            &cfg.region,
-            opt.rewrite_host,
-        )?;
+    #[serde(default)]
+    allocated_disk_rounding: DiskRounding,
 
-        let servers = session.servers()?;
-        let flavors = session.flavors()?;
-        let images = session.images()?;
-        let volumes = session.volumes()?;
-        let object_bucket_stats = radosgw::admin::bucket_stats();
-
-        let users = session.user_mappings()?;
-        let projects = session.project_mappings()?;
-        let domains = session.domains()?;
-
-        let snap = Snapshot {
-            version: 3,
-            datetime: this_run_datetime,
-            servers,
-            flavors,
-            images,
-            volumes,
-            object_bucket_stats: object_bucket_stats.ok(),
-            users,
-            projects,
-            domains,
-        };
+    /// Resource whose costs apply to servers whose project no longer exists in Keystone.
+    orphan_resource: Option<String>,
 
-        if let Some(snap_path) = opt.save_snapshot {
-            std::fs::write(snap_path, &serde_json::to_string_pretty(&snap).unwrap()).unwrap();
-        }
+    /// Project name reported for servers whose project no longer exists, defaults to the
+    /// project id.
+    orphan_project_name: Option<String>,
 
-        snap
-    };
-    let this_run_datetime = snap.datetime;
+    /// Project id that object buckets whose owner is not a Keystone project are billed to.
+    unowned_bucket_project: Option<String>,
 
-    let cost_lookup = CostLookup::new(&cfg, &costs, &snap.domains, &snap.projects)
-        .ok_or(format_err!("Could not construct costs lookup."))?;
+    /// Project id that images without an owner are billed to, they are skipped if unset.
+    ownerless_image_project: Option<String>,
 
-    let mut object_bucket_sizes = BTreeMap::new();
-    if let Some(stats) = &snap.object_bucket_stats {
-        let kb_to_gb = Decimal::from(1u32) / Decimal::from(1024u32.pow(2));
-        for s in stats {
-            if !s.usage.is_empty() {
-                let gb_sum = s.usage.iter().fold(Decimal::from(0u32), |sum, u| {
-                    sum + Decimal::from(u.1.size_kb) * kb_to_gb
-                });
-                object_bucket_sizes.insert(s.id.clone(), (s, gb_sum));
-            }
-        }
-    }
-    debug!("{:?}", object_bucket_sizes);
+    /// Flavor extra spec holding the number of GPUs, such as `resources:VGPU`, billed with the
+    /// `compute.per_gpu` cost. Flavors are not billed for GPUs if unset.
+    gpu_count_key: Option<String>,
 
-    let start_time = this_run_datetime
-        .with_minute(0)
-        .unwrap()
-        .with_second(0)
-        .unwrap()
-        .with_nanosecond(0)
-        .unwrap();
-    let duration = chrono::Duration::hours(1);
-    let end_time = start_time + duration;
+    /// Decimal separator of CSV records and the `--breakdown` table, `.` or `,`.
+    #[serde(default)]
+    decimal_separator: records::DecimalSeparator,
 
-    // Operator test project - "SNIC 2018/10-1"
-    let _op_servers = snap
-        .servers
-        .iter()
-        .filter(|srv| srv.tenant_id == "7d4b838241d9486e972bf1b371cc8718");
+    /// `cr:StorageType` by kind of storage (`volumes`, `images` or `objects`) or by Cinder volume
+    /// type as `volumes.<type>`, overriding the defaults.
+    #[serde(default)]
+    storage_type_map: BTreeMap<String, String>,
 
-    let mut used_os_volume_discount: BTreeMap<String, u64> = BTreeMap::new();
+    /// Template for the reported `cr:Resource`, where `{site}`, `{region}` and `{resource}` are
+    /// replaced with the site, region and resource of the costs file. Defaults to the resource.
+    resource_template: Option<String>,
 
-    let mut v1_compute_records: Vec<records::v1::CloudComputeRecord> = Vec::new();
-    let mut v1_storage_records: Vec<records::v1::CloudStorageRecord> = Vec::new();
+    /// Regions that the costs file is expected to contain. Other regions in the costs file are
+    /// reported as errors, which catches stale and misspelt regions.
+    expected_regions: Option<BTreeSet<String>>,
 
-    info!("Processing servers");
-    'server_loop: for server in &snap.servers {
-        use openstack::nova;
+    /// Path of the Keystone API below `keystone_url`, appended unless the URL already ends in it.
+    #[serde(default = "default_keystone_api_path")]
+    keystone_api_path: String,
 
-        if server.zone.is_none() {
-            warn!("Skipping server instance {} due to no zone", server.id);
-            continue 'server_loop;
-        }
+    /// User-Agent sent with OpenStack requests, defaults to `ssc-billing-logger/<version>`.
+    user_agent: Option<String>,
 
-        if server.zone.as_ref().unwrap().is_empty() {
-            warn!("Skipping server instance {} due to empty zone", server.id);
-            continue 'server_loop;
-        }
+    /// Host that `--rewrite-host` points the service endpoints at, defaults to `localhost`.
+    rewrite_host: Option<String>,
 
-        let user = snap.users.get(&server.user_id);
-        let project = snap.projects.get(&server.tenant_id);
-        let flavor = snap.flavors.get(&server.flavor.id);
-        let proj_costs = cost_lookup.project_costs_by_id(&server.tenant_id);
+    /// Ports that `--rewrite-host` gives the endpoints of the services `nova`, `cinder`, `glance`
+    /// and `swift`, services not listed keep their port.
+    #[serde(default)]
+    rewrite_ports: BTreeMap<String, u16>,
 
-        let image_backed = match &server.image {
-            nova::Image::StringRep(x) => x != "",
-            nova::Image::ObjectRep { id } => id != "",
-        };
-        let volume_backed = !image_backed && !server.attached_volumes.is_empty();
+    /// Seconds after which an OpenStack request is given up, zero for no limit.
+    #[serde(default)]
+    request_timeout_secs: u64,
 
-        // debug!(
-        //     "user: {:?}, project: {:?}, flavour: {:?}",
-        //     user, project, flavor
-        // );
-        // debug!("{:?}", server);
+    /// Attempts made at most for OpenStack requests that fail to connect or get a 5xx response.
+    #[serde(default = "default_request_attempts")]
+    request_attempts: u32,
 
-        if let (Some(user), Some(project), Some(flavor), Some(proj_costs)) =
-            (user, project, flavor, proj_costs)
-        {
-            let cost = proj_costs.get(&flavor.name);
+    /// Milliseconds before the first retry of a failed request, doubled for each further one.
+    #[serde(default = "default_retry_delay_ms")]
+    retry_delay_ms: u64,
 
-            let _billing_category = BillingCategory::from_status(server.status.as_ref());
+    /// Region whose service endpoints are used, defaults to `region`.
+    service_region: Option<String>,
 
-            if volume_backed {
-                used_os_volume_discount.insert(server.attached_volumes[0].id.clone(), flavor.disk);
-            }
+    /// Catalog interface whose service endpoints are used.
+    #[serde(default = "default_endpoint_interface")]
+    endpoint_interface: String,
 
-            let create_time = Utc::now();
+    /// Multiplier applied to every computed cost, e.g. 0.5 during a discount period.
+    #[serde(default = "default_cost_multiplier")]
+    cost_multiplier: Decimal,
 
-            if let Some(cost) = cost {
-                if !cost.is_zero() {
-                    let allocated_disk = flavor.disk * 1024u64.pow(3);
-                    let allocated_cpu: Decimal = flavor.vcpus.into();
-                    let allocated_memory = flavor.ram;
+    /// Additional multipliers applied to costs for the given resources.
+    #[serde(default)]
+    resource_multipliers: BTreeMap<String, Decimal>,
 
-                    use records::v1::{CloudComputeRecord, CloudRecordCommon};
+    /// Overhead in percent added on top of every cost after the multipliers.
+    #[serde(default)]
+    overhead_percent: Decimal,
 
-                    let cr = CloudComputeRecord {
-                        common: CloudRecordCommon {
-                            create_time: create_time,
-                            site: cfg.site.clone(),
-                            project: project.name,
-                            user: user.name,
-                            instance_id: server.id.clone(),
-                            start_time,
-                            end_time,
-                            duration,
-                            region: cfg.region.clone(),
-                            resource: proj_costs.resource.clone(),
-                            zone: server.zone.clone().unwrap(),
-                            cost,
-                            allocated_disk,
-                        },
-                        flavour: flavor.name.clone(),
-                        allocated_cpu,
-                        allocated_memory,
-                        used_cpu: None,
-                        used_memory: None,
-                        used_network_up: None,
-                        used_network_down: None,
-                        iops: None,
-                    };
-                    v1_compute_records.push(cr);
-                }
-            }
-        }
-    }
+    /// Overhead in percent for the given resources, replacing `overhead_percent` for them.
+    #[serde(default)]
+    resource_overhead_percent: BTreeMap<String, Decimal>,
+}
 
-    info!("Processing volumes");
-    for volume in &snap.volumes {
-        use records::v1::{CloudRecordCommon, CloudStorageRecord};
-        let mut process_volume = || -> Option<CloudStorageRecord> {
-            let proj_costs = cost_lookup.project_costs_by_id(&volume.tenant_id)?;
-            let gig_rate = proj_costs.get("storage.block");
-            let discount = *used_os_volume_discount.get(&volume.id).unwrap_or(&0);
-            let actual_gigs = volume.size;
-            let discount_gigs = volume.size.saturating_sub(discount);
-            {
-                let dv = used_os_volume_discount.get_mut(&volume.id)?;
-                *dv = dv.saturating_sub(actual_gigs);
-            }
-            let cost = gig_rate.map(|r| Decimal::from(discount_gigs) * r);
-            let user = snap.users.get(&volume.user_id)?;
-            let project = snap.projects.get(&volume.tenant_id)?;
+/// Fields of `Config` masked by `--dump-config`, as JSON pointers.
+const SECRET_CONFIG_FIELDS: [&str; 2] = ["/password", "/radosgw_admin/secret_key"];
 
-            let create_time = Utc::now();
-            let allocated_disk = actual_gigs * 1024u64.pow(3);
+/// Endpoint and keys of a radosgw user with `buckets=read` admin caps.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct RadosgwAdmin {
+    endpoint: Url,
+    access_key: String,
+    secret_key: String,
+}
 
-            let cost = cost?;
-            if !cost.is_zero() {
-                let sr = CloudStorageRecord {
-                    common: CloudRecordCommon {
-                        create_time: create_time,
-                        site: cfg.site.clone(),
-                        project: project.name,
-                        user: user.name,
-                        instance_id: volume.id.clone(),
-                        start_time,
-                        end_time,
-                        duration,
-                        region: cfg.region.clone(),
-                        resource: proj_costs.resource.clone(),
-                        zone: volume.availability_zone.clone(),
-                        cost,
-                        allocated_disk,
-                    },
-                    file_count: 0,
-                    storage_type: "Block".to_owned(),
-                };
-                Some(sr)
-            } else {
-                None
-            }
-        };
-        process_volume().map(|sr| v1_storage_records.push(sr));
-    }
-
-    info!("Processing images");
-    for image in &snap.images {
-        use records::v1::{CloudRecordCommon, CloudStorageRecord};
-        let process_image = || -> Option<CloudStorageRecord> {
-            let bytes = image.size?;
-            let owner = image.owner.as_ref()?;
-            let proj_costs = cost_lookup.project_costs_by_id(owner)?;
-            let gig_rate = proj_costs.get("storage.block");
-            let cost = gig_rate.map(|r| Decimal::from(bytes) / Decimal::from(1024u64.pow(3)) * r);
-            let project = snap.projects.get(owner)?;
-
-            // Not all images have an user name associated with them, only an owning project.
-            let user_name: &str = image
-                .owner_user_name
-                .as_ref()
-                .and_then(|user_name| {
-                    if snap.users.has_name_in_domain(user_name, &project.domain_id) {
-                        Some(user_name.as_ref())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap_or(DEFAULT_USER);
+fn default_object_excluded_usage() -> Vec<String> {
+    radosgw::admin::DEFAULT_EXCLUDED_USAGE_CATEGORIES
+        .iter()
+        .map(|c| c.to_string())
+        .collect()
+}
 
-            let create_time = Utc::now();
-            let allocated_disk = bytes;
+fn default_request_attempts() -> u32 {
+    openstack::RetryPolicy::default().max_attempts
+}
 
-            if let Some(cost) = cost {
-                if !cost.is_zero() {
-                    let sr = CloudStorageRecord {
-                        common: CloudRecordCommon {
-                            create_time: create_time,
-                            site: cfg.site.clone(),
+fn default_retry_delay_ms() -> u64 {
+    openstack::RetryPolicy::default().base_delay.as_millis() as u64
+}
+
+fn default_unbilled_volume_statuses() -> Vec<String> {
+    [
+        "creating",
+        "deleting",
+        "error",
+        "error_deleting",
+        "error_backing-up",
+        "error_restoring",
+        "error_extending",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+fn default_cost_multiplier() -> Decimal {
+    Decimal::from(1)
+}
+
+fn default_endpoint_interface() -> String {
+    openstack::DEFAULT_ENDPOINT_INTERFACE.to_owned()
+}
+
+fn default_keystone_api_path() -> String {
+    openstack::DEFAULT_KEYSTONE_API_PATH.to_owned()
+}
+
+impl Config {
+    fn user_domain(&self) -> &str {
+        self.user_domain.as_deref().unwrap_or(&self.domain)
+    }
+
+    fn project_domain(&self) -> &str {
+        self.project_domain.as_deref().unwrap_or(&self.domain)
+    }
+
+    fn service_region(&self) -> &str {
+        self.service_region.as_deref().unwrap_or(&self.region)
+    }
+
+    fn retry_policy(&self) -> openstack::RetryPolicy {
+        openstack::RetryPolicy {
+            max_attempts: self.request_attempts,
+            base_delay: std::time::Duration::from_millis(self.retry_delay_ms),
+        }
+    }
+
+    /// The admin ops API if `radosgw_admin` is set, otherwise `radosgw-admin`.
+    fn bucket_stats_source(&self) -> Result<radosgw::admin::BucketStatsSource, failure::Error> {
+        use radosgw::admin::{AdminApi, BucketStatsSource};
+        Ok(match &self.radosgw_admin {
+            Some(admin) => BucketStatsSource::AdminApi(AdminApi::new(
+                &admin.endpoint,
+                &admin.access_key,
+                &admin.secret_key,
+                self.request_timeout(),
+            )?),
+            None => BucketStatsSource::Subprocess,
+        })
+    }
+
+    /// Where `--rewrite-host` points the service endpoints.
+    fn host_rewrite(&self) -> openstack::HostRewrite {
+        let default = openstack::HostRewrite::default();
+        openstack::HostRewrite {
+            host: self.rewrite_host.clone().unwrap_or(default.host),
+            ports: self
+                .rewrite_ports
+                .iter()
+                .map(|(service, port)| (service.clone(), *port))
+                .collect(),
+        }
+    }
+
+    fn request_timeout(&self) -> Option<std::time::Duration> {
+        match self.request_timeout_secs {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        }
+    }
+
+    /// Number of GPUs of a flavor according to `gpu_count_key`.
+    fn gpu_count(&self, flavor: &openstack::nova::Flavor) -> u64 {
+        self.gpu_count_key
+            .as_deref()
+            .and_then(|key| flavor.extra_spec_count(key))
+            .unwrap_or(0)
+    }
+
+    /// The billing category of an instance status, compared regardless of case, or `None` if the
+    /// status is not known.
+    fn billing_category(&self, status: &str) -> Option<BillingCategory> {
+        let status = status.to_uppercase();
+        let configured = self
+            .status_categories
+            .iter()
+            .find(|(name, _)| name.to_uppercase() == status)
+            .map(|(_, category)| *category);
+        configured.or_else(|| BillingCategory::from_status(&status))
+    }
+
+    /// The billing category of an instance status, falling back to `unknown_status_category`.
+    fn billed_as(&self, status: &str) -> BillingCategory {
+        self.billing_category(status)
+            .unwrap_or(self.unknown_status_category)
+    }
+
+    fn zone_excluded(&self, zone: &str) -> bool {
+        self.exclude_zones.iter().any(|z| z == zone)
+    }
+
+    /// The tag rule applying to a server. `noncharge` takes precedence over resource rules, of
+    /// which the first in the order of the server's tags applies.
+    fn server_tag_rule(&self, server: &openstack::nova::Server) -> Option<&TagRule> {
+        let rules: Vec<&TagRule> = server
+            .tags
+            .iter()
+            .filter_map(|tag| self.server_tags.get(tag))
+            .collect();
+        rules
+            .iter()
+            .find(|rule| ***rule == TagRule::NonCharge)
+            .or_else(|| rules.first())
+            .cloned()
+    }
+
+    fn cost_multiplier_for(&self, resource: &str) -> Decimal {
+        let resource_multiplier = self
+            .resource_multipliers
+            .get(resource)
+            .cloned()
+            .unwrap_or_else(default_cost_multiplier);
+        self.cost_multiplier * resource_multiplier
+    }
+
+    fn overhead_factor_for(&self, resource: &str) -> Decimal {
+        let percent = self
+            .resource_overhead_percent
+            .get(resource)
+            .unwrap_or(&self.overhead_percent);
+        Decimal::from(1) + percent / Decimal::from(100)
+    }
+
+    /// Loads and merges the given configuration files in order, see `merge_json`.
+    fn load(paths: &[PathBuf]) -> Result<Config, failure::Error> {
+        let mut merged = serde_json::Value::Object(Default::default());
+        for path in paths {
+            info!("Loading configuration from {:?}", path);
+            let value: serde_json::Value = serde_json::from_reader(File::open(path)?)?;
+            merge_json(&mut merged, value);
+        }
+        Ok(serde_json::from_value(merged)?)
+    }
+
+    /// The configuration with defaults filled in and secrets masked, as printed by `--dump-config`.
+    fn redacted_json(&self) -> Result<serde_json::Value, failure::Error> {
+        let mut value = serde_json::to_value(self)?;
+        let redact = |value: &mut serde_json::Value| {
+            for pointer in SECRET_CONFIG_FIELDS.iter() {
+                if let Some(secret) = value.pointer_mut(pointer) {
+                    *secret = serde_json::json!("<redacted>");
+                }
+            }
+        };
+        redact(&mut value);
+        if let Some(serde_json::Value::Object(regions)) = value.get_mut("merge_regions") {
+            regions.values_mut().for_each(redact);
+        }
+        Ok(value)
+    }
+
+    /// Checks the configuration for problems that would otherwise surface as confusing failures
+    /// later in the run, reporting all of them at once.
+    fn validate(&self) -> Result<(), failure::Error> {
+        let mut problems = Vec::new();
+        let required: [(&str, &str); 8] = [
+            ("username", &self.username),
+            ("password", &self.password),
+            ("user_domain", self.user_domain()),
+            ("project_domain", self.project_domain()),
+            ("project", &self.project),
+            ("site", &self.site),
+            ("region", &self.region),
+            ("datadir", &self.datadir),
+        ];
+        for (name, value) in required.iter() {
+            if value.trim().is_empty() {
+                problems.push(format!("`{}` is empty", name));
+            }
+        }
+        if !["http", "https"].contains(&self.keystone_url.scheme()) {
+            problems.push(format!(
+                "`keystone_url` has unsupported scheme {:?}",
+                self.keystone_url.scheme()
+            ));
+        }
+        if self.keystone_url.host().is_none() {
+            problems.push("`keystone_url` has no host".to_owned());
+        }
+        if self.resources.is_empty() {
+            problems.push("`resources` is empty".to_owned());
+        }
+        for service in self.rewrite_ports.keys() {
+            if !openstack::REWRITTEN_SERVICES.contains(&service.as_str()) {
+                problems.push(format!(
+                    "`rewrite_ports` has unknown service {:?}, expected one of {}",
+                    service,
+                    openstack::REWRITTEN_SERVICES.join(", ")
+                ));
+            }
+        }
+        if !self.datadir.trim().is_empty() {
+            match std::fs::metadata(&self.datadir) {
+                Ok(meta) if !meta.is_dir() => {
+                    problems.push(format!("`datadir` {} is not a directory", self.datadir))
+                }
+                Ok(meta) if meta.permissions().readonly() => {
+                    problems.push(format!("`datadir` {} is not writable", self.datadir))
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("`datadir` {}: {}", self.datadir, e)),
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid configuration: {}", problems.join("; "));
+        }
+        Ok(())
+    }
+
+    /// The `cr:StorageType` reported for a kind of storage, and for volumes their Cinder type.
+    fn storage_type(&self, kind: StorageKind, volume_type: Option<&str>) -> String {
+        volume_type
+            .and_then(|typ| {
+                self.storage_type_map
+                    .get(&format!("{}.{}", kind.name(), typ))
+            })
+            .or_else(|| self.storage_type_map.get(kind.name()))
+            .map(String::as_str)
+            .unwrap_or_else(|| kind.default_storage_type())
+            .to_owned()
+    }
+
+    /// The configurations of the regions of `merge_regions`, each being this configuration with
+    /// the region and its settings from `merge_regions` applied.
+    fn merged_region_configs(&self) -> Result<Vec<Config>, failure::Error> {
+        let mut base = serde_json::to_value(self)?;
+        merge_json(&mut base, serde_json::json!({ "service_region": null }));
+        if let serde_json::Value::Object(base) = &mut base {
+            base.remove("merge_regions");
+        }
+        let mut configs = Vec::new();
+        for (region, overlay) in &self.merge_regions {
+            let mut value = base.clone();
+            merge_json(&mut value, serde_json::json!({ "region": region }));
+            merge_json(&mut value, overlay.clone());
+            let cfg: Config = serde_json::from_value(value)
+                .map_err(|e| format_err!("Invalid `merge_regions` entry {:?}: {}", region, e))?;
+            configs.push(cfg);
+        }
+        Ok(configs)
+    }
+
+    /// The `cr:Resource` reported for a resource of the costs file in `region`.
+    fn render_resource(&self, region: &str, resource: &str) -> String {
+        match &self.resource_template {
+            Some(template) => template
+                .replace("{site}", &self.site)
+                .replace("{region}", region)
+                .replace("{resource}", resource),
+            None => resource.to_owned(),
+        }
+    }
+
+    /// Checks that the costs file has costs for `region` and, if `expected_regions` is set, that
+    /// both agree with it.
+    fn check_regions(&self, costs: &CostsFile) -> Result<(), failure::Error> {
+        let list = |regions: &mut dyn Iterator<Item = &String>| {
+            regions.map(String::as_str).collect::<Vec<_>>().join(", ")
+        };
+        let mut problems = Vec::new();
+        if !costs.regions.contains_key(&self.region) {
+            problems.push(format!(
+                "`region` {:?} is not in the costs file, which has [{}]",
+                self.region,
+                list(&mut costs.regions.keys())
+            ));
+        }
+        if let Some(expected) = &self.expected_regions {
+            if !expected.contains(&self.region) {
+                problems.push(format!(
+                    "`region` {:?} is not among `expected_regions` [{}]",
+                    self.region,
+                    list(&mut expected.iter())
+                ));
+            }
+            let mut unexpected = costs.regions.keys().filter(|r| !expected.contains(*r));
+            let unexpected = list(&mut unexpected);
+            if !unexpected.is_empty() {
+                problems.push(format!(
+                    "costs file has regions [{}] not among `expected_regions` [{}]",
+                    unexpected,
+                    list(&mut expected.iter())
+                ));
+            }
+        }
+        if !problems.is_empty() {
+            bail!(
+                "Regions do not match the costs file: {}",
+                problems.join("; ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Merges `overlay` onto `base`. Objects such as `resources` are merged key by key, any other value
+/// in `overlay` replaces the one in `base`.
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    use serde_json::Value;
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Applies the configured cost multipliers and then the overhead to already computed record costs,
+/// rounding the result again to `COST_DECIMAL_PLACES` decimal places.
+fn apply_cost_multipliers<'a, I>(cfg: &Config, records: I)
+where
+    I: IntoIterator<Item = &'a mut records::v1::CloudRecordCommon>,
+{
+    for common in records {
+        let factor =
+            cfg.cost_multiplier_for(&common.resource) * cfg.overhead_factor_for(&common.resource);
+        common.cost = (common.cost * factor).round_dp(COST_DECIMAL_PLACES);
+    }
+}
+
+/// What a Nova server tag does to the billing of the tagged instance.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum TagRule {
+    /// The instance is not billed.
+    #[serde(rename = "noncharge")]
+    NonCharge,
+    /// The instance is billed as the given resource instead of that of its project.
+    Resource(String),
+}
+
+/// Command run after the records have been written, with `{path}` in any argument replaced by the
+/// path of the written file.
+#[derive(Debug, Deserialize, Serialize)]
+struct PostWriteHook {
+    command: Vec<String>,
+
+    #[serde(default)]
+    fatal: bool,
+}
+
+impl PostWriteHook {
+    fn run(&self, path: &std::path::Path) -> Result<(), failure::Error> {
+        let path = path.to_string_lossy();
+        let mut argv = self.command.iter().map(|arg| arg.replace("{path}", &path));
+        let program = argv
+            .next()
+            .ok_or_else(|| format_err!("Post-write hook has an empty command"))?;
+        info!("Running post-write hook {:?}", program);
+        let status = subprocess::Exec::cmd(&program)
+            .args(&argv.collect::<Vec<_>>())
+            .join()?;
+        if !status.success() {
+            bail!("Post-write hook {:?} exited with {:?}", program, status);
+        }
+        info!("Post-write hook {:?} exited with {:?}", program, status);
+        Ok(())
+    }
+}
+
+/// What compute and volume usage is billed on.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum BillingMode {
+    /// Running instances and existing volumes.
+    #[default]
+    Usage,
+    /// The compute and volume quota allocated to each project.
+    Quota,
+}
+
+/// What to do with records that would have a negative cost or allocation.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum NegativeValues {
+    /// Fail the run.
+    #[default]
+    Fail,
+    /// Leave the record out and warn.
+    Skip,
+}
+
+/// What happens to instances present for less than `min_billable_duration`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum BelowMinDuration {
+    /// Leave the instance out.
+    #[default]
+    Skip,
+    /// Bill the instance for `min_billable_duration`.
+    BillMinimum,
+}
+
+/// What a run does when another run holds the lock.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum OnLocked {
+    /// Log that another run is in progress and exit successfully.
+    #[default]
+    Exit,
+    /// Wait for the other run to finish.
+    Wait,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum AggregateStorage {
+    #[default]
+    PerVolume,
+    PerProject,
+}
+
+/// How the records of an hour are split into several files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SplitBy {
+    /// Compute records in one file and storage records in another.
+    Kind,
+}
+
+impl std::str::FromStr for SplitBy {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kind" => Ok(SplitBy::Kind),
+            _ => Err(format_err!("Unknown split {:?}, expected \"kind\"", s)),
+        }
+    }
+}
+
+/// Path of the `part` of the record file `path` when split, such as `20190213T1200Z-compute.xml`
+/// for `20190213T1200Z.xml`.
+fn split_path_for(path: &std::path::Path, part: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let (stem, rest) = match name.find('.') {
+        Some(i) => name.split_at(i),
+        None => (name.as_ref(), ""),
+    };
+    path.with_file_name(format!("{}-{}{}", stem, part, rest))
+}
+
+/// Parts of a record file split by `SplitBy::Kind`.
+const SPLIT_KIND_PARTS: [&str; 2] = ["compute", "storage"];
+
+/// How record files are laid out beneath `records/`.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+enum RecordsLayout {
+    /// `records/<YYYYmmddTHHMMZ>.xml`
+    #[default]
+    Flat,
+    /// `records/<YYYY>/<mm>/<dd>/<YYYYmmddTHHMMZ>.xml`
+    Dated,
+}
+
+impl RecordsLayout {
+    fn path_for(self, records_dir: &std::path::Path, timepoint: DateTime<Utc>) -> PathBuf {
+        let leaf_name = format!("{}.xml", timepoint.format("%Y%m%dT%H%MZ"));
+        match self {
+            RecordsLayout::Flat => records_dir.join(leaf_name),
+            RecordsLayout::Dated => records_dir
+                .join(timepoint.format("%Y/%m/%d").to_string())
+                .join(leaf_name),
+        }
+    }
+}
+
+type ResourceCosts = BTreeMap<String, Decimal>;
+
+/// Prefix of compute rates keyed by flavor id rather than by flavor name.
+const FLAVOR_ID_PREFIX: &str = "id:";
+
+/// Key of a rate in the costs file. Compute rates are keyed by flavor name or by flavor id as
+/// `id:<flavor id>`, storage and quota rates by the fixed keys defined here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CostKind<'a> {
+    Flavor(&'a str),
+    BlockStorage,
+    /// Per gigabyte and hour of a volume attached to an instance, instead of `BlockStorage`.
+    BlockStorageAttached,
+    /// Per gigabyte and hour of a volume not attached to any instance, instead of `BlockStorage`.
+    BlockStorageDetached,
+    ObjectStorage,
+    /// Per core of compute quota and hour.
+    QuotaCore,
+    /// Per gigabyte of RAM quota and hour.
+    QuotaRam,
+    /// Per gigabyte of volume quota and hour.
+    QuotaBlock,
+    /// Per object in a bucket and hour, on top of the size based cost.
+    ObjectCount,
+    /// Per GPU of a flavor and hour, on top of the flavor's rate.
+    Gpu,
+}
+
+impl<'a> CostKind<'a> {
+    const FIXED: [CostKind<'static>; 9] = [
+        CostKind::BlockStorage,
+        CostKind::BlockStorageAttached,
+        CostKind::BlockStorageDetached,
+        CostKind::ObjectStorage,
+        CostKind::QuotaCore,
+        CostKind::QuotaRam,
+        CostKind::QuotaBlock,
+        CostKind::ObjectCount,
+        CostKind::Gpu,
+    ];
+
+    fn key(self) -> &'a str {
+        match self {
+            CostKind::Flavor(name) => name,
+            CostKind::BlockStorage => "storage.block",
+            CostKind::BlockStorageAttached => "storage.block.attached",
+            CostKind::BlockStorageDetached => "storage.block.detached",
+            CostKind::ObjectStorage => "storage.object",
+            CostKind::QuotaCore => "quota.core",
+            CostKind::QuotaRam => "quota.ram",
+            CostKind::QuotaBlock => "quota.block",
+            CostKind::ObjectCount => "storage.object.per_object",
+            CostKind::Gpu => "compute.per_gpu",
+        }
+    }
+
+    fn from_key(key: &'a str) -> CostKind<'a> {
+        CostKind::FIXED
+            .iter()
+            .find(|kind| kind.key() == key)
+            .cloned()
+            .unwrap_or(CostKind::Flavor(key))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegionCosts {
+    #[serde(flatten)]
+    resources: BTreeMap<String, ResourceCosts>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CostsFile {
+    regions: BTreeMap<String, RegionCosts>,
+}
+
+/// Costs file as written on disk, with the costs left unparsed so that a malformed value can be
+/// reported with its full path.
+#[derive(Debug, Deserialize)]
+struct RawCostsFile {
+    regions: BTreeMap<String, BTreeMap<String, BTreeMap<String, serde_json::Value>>>,
+}
+
+impl CostsFile {
+    fn from_reader<R: std::io::Read>(reader: R) -> Result<CostsFile, failure::Error> {
+        let raw: RawCostsFile = serde_json::from_reader(reader)?;
+        let mut regions = BTreeMap::new();
+        for (region, raw_resources) in raw.regions {
+            let mut resources = BTreeMap::new();
+            for (resource, raw_costs) in raw_resources {
+                let mut costs = ResourceCosts::new();
+                for (kind, value) in raw_costs {
+                    let cost = parse_cost(&value).ok_or_else(|| {
+                        format_err!(
+                            "Invalid cost {} at regions.{}.{}.{}",
+                            value,
+                            region,
+                            resource,
+                            kind
+                        )
+                    })?;
+                    if (kind.starts_with("storage.")
+                        || kind.starts_with("quota.")
+                        || kind.starts_with("compute."))
+                        && CostKind::from_key(&kind) == CostKind::Flavor(&kind)
+                    {
+                        let known: Vec<&str> =
+                            CostKind::FIXED.iter().map(|kind| kind.key()).collect();
+                        warn!(
+                            "Unknown cost kind at regions.{}.{}.{}, expected one of {}",
+                            region,
+                            resource,
+                            kind,
+                            known.join(", ")
+                        );
+                    }
+                    costs.insert(kind, cost);
+                }
+                resources.insert(resource, costs);
+            }
+            regions.insert(region, RegionCosts { resources });
+        }
+        Ok(CostsFile { regions })
+    }
+}
+
+fn parse_cost(value: &serde_json::Value) -> Option<Decimal> {
+    use std::str::FromStr;
+    let text = match value {
+        serde_json::Value::Number(n) => n.to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        _ => return None,
+    };
+    Decimal::from_str(&text)
+        .or_else(|_| Decimal::from_scientific(&text))
+        .ok()
+}
+
+/// Source of the resource that projects are billed as and of the rates of each resource.
+/// `CostLookup` is built on it so that pricing can come from elsewhere than the costs file.
+trait CostSource {
+    /// Resource that the given project in the named domain is billed as.
+    fn resource_for_project(&self, project_id: &str, domain_name: &str) -> Option<String>;
+
+    /// Rate of one kind of cost for a resource.
+    fn rate_for_kind(&self, resource: &str, kind: CostKind) -> Option<Decimal>;
+
+    /// All rates of a resource by key, `None` if the resource has no costs at all.
+    fn rates(&self, resource: &str) -> Option<Vec<(String, Decimal)>>;
+
+    fn has_resource(&self, resource: &str) -> bool {
+        self.rates(resource).is_some()
+    }
+}
+
+/// Costs of one region of the costs file, with resources assigned by domain through the
+/// `resources` of the configuration.
+struct FileCostSource<'a> {
+    resources: &'a BTreeMap<String, String>,
+    region_costs: &'a RegionCosts,
+}
+
+impl<'a> CostSource for FileCostSource<'a> {
+    fn resource_for_project(&self, _project_id: &str, domain_name: &str) -> Option<String> {
+        self.resources.get(domain_name).cloned()
+    }
+
+    fn rate_for_kind(&self, resource: &str, kind: CostKind) -> Option<Decimal> {
+        self.region_costs
+            .resources
+            .get(resource)?
+            .get(kind.key())
+            .cloned()
+    }
+
+    fn rates(&self, resource: &str) -> Option<Vec<(String, Decimal)>> {
+        let costs = self.region_costs.resources.get(resource)?;
+        Some(costs.iter().map(|(k, v)| (k.clone(), *v)).collect())
+    }
+
+    fn has_resource(&self, resource: &str) -> bool {
+        self.region_costs.resources.contains_key(resource)
+    }
+}
+
+/// Costs of the resources of a project within one hour. Entries without a cost were not billed.
+#[derive(Debug, Default)]
+pub struct ProjectBreakdown<'a> {
+    project: String,
+    active: Vec<(Option<Decimal>, &'a openstack::nova::Server)>,
+    inert: Vec<(Option<Decimal>, &'a openstack::nova::Server)>,
+    volumes: Vec<(Option<Decimal>, &'a openstack::cinder::Volume)>,
+    images: Vec<(Option<Decimal>, &'a openstack::glance::Image)>,
+    objects: Vec<(Option<Decimal>, &'a radosgw::admin::BucketStats)>,
+}
+
+impl<'a> ProjectBreakdown<'a> {
+    fn sum<T>(entries: &[(Option<Decimal>, T)]) -> Decimal {
+        entries.iter().filter_map(|(cost, _)| *cost).sum()
+    }
+
+    fn compute_cost(&self) -> Decimal {
+        Self::sum(&self.active) + Self::sum(&self.inert)
+    }
+
+    fn volume_cost(&self) -> Decimal {
+        Self::sum(&self.volumes)
+    }
+
+    fn image_cost(&self) -> Decimal {
+        Self::sum(&self.images)
+    }
+
+    fn object_cost(&self) -> Decimal {
+        Self::sum(&self.objects)
+    }
+
+    fn total_cost(&self) -> Decimal {
+        self.compute_cost() + self.volume_cost() + self.image_cost() + self.object_cost()
+    }
+
+    /// The entry for `project_id`, created with the given project name if missing.
+    fn entry<'b>(
+        breakdowns: &'b mut BTreeMap<String, ProjectBreakdown<'a>>,
+        project_id: &str,
+        project: &str,
+    ) -> &'b mut ProjectBreakdown<'a> {
+        breakdowns
+            .entry(project_id.to_owned())
+            .or_insert_with(|| ProjectBreakdown {
+                project: project.to_owned(),
+                ..Default::default()
+            })
+    }
+}
+
+/// Warns about projects with active instances that were not billed anything for compute, which
+/// usually means that their flavors are missing from the costs file.
+fn check_breakdowns(
+    breakdowns: &BTreeMap<String, ProjectBreakdown>,
+    warnings: &mut WarningSummary,
+) {
+    for (project_id, breakdown) in breakdowns {
+        if !breakdown.active.is_empty() && breakdown.compute_cost().is_zero() {
+            warn!(
+                "Project {} ({}) has {} active instances but no compute cost",
+                breakdown.project,
+                project_id,
+                breakdown.active.len()
+            );
+            warnings.add(
+                "projects with active instances but no compute cost",
+                breakdown.project.as_str(),
+            );
+        }
+    }
+}
+
+/// Prints a table of the costs of each project, most expensive first.
+fn print_breakdown(
+    breakdowns: &BTreeMap<String, ProjectBreakdown>,
+    separator: records::DecimalSeparator,
+) {
+    let mut projects: Vec<_> = breakdowns.iter().collect();
+    projects.sort_by_key(|(_, breakdown)| std::cmp::Reverse(breakdown.total_cost()));
+    let width = projects
+        .iter()
+        .map(|(_, breakdown)| breakdown.project.chars().count())
+        .chain(std::iter::once("project".len()))
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:<width$} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "project",
+        "compute",
+        "volumes",
+        "images",
+        "objects",
+        "total",
+        width = width
+    );
+    for (_, breakdown) in projects {
+        println!(
+            "{:<width$} {:>12} {:>12} {:>12} {:>12} {:>12}",
+            breakdown.project,
+            separator.format(&breakdown.compute_cost()),
+            separator.format(&breakdown.volume_cost()),
+            separator.format(&breakdown.image_cost()),
+            separator.format(&breakdown.object_cost()),
+            separator.format(&breakdown.total_cost()),
+            width = width
+        );
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+enum BillingCategory {
+    #[default]
+    Active,
+    Inactive,
+    Unbilled,
+}
+
+impl BillingCategory {
+    /// The category of a known upper-case Nova status.
+    fn from_status(s: &str) -> Option<BillingCategory> {
+        match s {
+            "ACTIVE" | "REBOOT" | "HARD_REBOOT" | "PASSWORD" | "REBUILD" | "RESCUE" | "RESIZE"
+            | "VERIFY_RESIZE" | "REVERT_RESIZE" | "MIGRATING" => Some(BillingCategory::Active),
+            "PAUSED" | "SUSPENDED" | "SOFT_SUSPENDED" | "SOFT_DELETED" | "SHUTOFF" | "STOPPED" => {
+                Some(BillingCategory::Inactive)
+            }
+            "DELETED" | "SHELVED" | "SHELVED_OFFLOADED" | "ERROR" | "BUILD" => {
+                Some(BillingCategory::Unbilled)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct CostLookup<'a> {
+    config: &'a Config,
+    domains: BTreeMap<String, String>,
+    source: Box<dyn CostSource + 'a>,
+    projects: &'a openstack::NameMapping,
+}
+
+impl<'a> CostLookup<'a> {
+    /// Looks up costs in the configured region of the costs file, `None` if it has no such region.
+    fn new(
+        config: &'a Config,
+        costs: &'a CostsFile,
+        domains: &'a openstack::keystone::Domains,
+        projects: &'a openstack::NameMapping,
+    ) -> Option<Self> {
+        let source = FileCostSource {
+            resources: &config.resources,
+            region_costs: costs.regions.get(&config.region)?,
+        };
+        Some(Self::with_source(
+            config,
+            Box::new(source),
+            domains,
+            projects,
+        ))
+    }
+
+    fn with_source(
+        config: &'a Config,
+        source: Box<dyn CostSource + 'a>,
+        domains: &'a openstack::keystone::Domains,
+        projects: &'a openstack::NameMapping,
+    ) -> Self {
+        let domains = domains
+            .domains
+            .iter()
+            .map(|d| (d.id.clone(), d.name.clone()))
+            .collect();
+        Self {
+            config,
+            domains,
+            projects,
+            source,
+        }
+    }
+
+    /// Costs of `resource`, if the source has any for it.
+    fn resource_costs(&'a self, resource: String) -> Option<ProjectCost<'a>> {
+        if !self.source.has_resource(&resource) {
+            return None;
+        }
+        Some(ProjectCost {
+            resource,
+            source: self.source.as_ref(),
+        })
+    }
+
+    fn project_costs_by_id(&'a self, proj_id: &str) -> Option<ProjectCost<'a>> {
+        let proj = self.projects.get(proj_id)?;
+        let domain_name = self.domains.get(&proj.domain_id)?;
+        let resource = self.source.resource_for_project(proj_id, domain_name)?;
+        self.resource_costs(resource)
+    }
+
+    /// Resolves the reported project name and costs for a project id. Projects that are missing
+    /// from Keystone, e.g. deleted while their instances linger, are billed as
+    /// `orphan_project_name` against `orphan_resource`.
+    fn resolve_project(&'a self, proj_id: &str) -> Option<(String, ProjectCost<'a>)> {
+        match self.projects.get(proj_id) {
+            Some(proj) => Some((proj.name, self.project_costs_by_id(proj_id)?)),
+            None => {
+                let resource = self.config.orphan_resource.clone()?;
+                let costs = self.resource_costs(resource)?;
+                let name = self
+                    .config
+                    .orphan_project_name
+                    .clone()
+                    .unwrap_or_else(|| proj_id.to_owned());
+                Some((name, costs))
+            }
+        }
+    }
+
+    /// Prints the domain, resource and rates every known project resolves to, and why projects
+    /// that do not resolve fail to do so.
+    fn explain(&self) {
+        let mut projects: Vec<_> = self.projects.iter().collect();
+        projects.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        for (proj_id, proj) in projects {
+            let domain_name = match self.domains.get(&proj.domain_id) {
+                Some(name) => name,
+                None => {
+                    println!(
+                        "UNRESOLVED {} ({}): unknown domain {}",
+                        proj.name, proj_id, proj.domain_id
+                    );
+                    continue;
+                }
+            };
+            let resource = match self.source.resource_for_project(proj_id, domain_name) {
+                Some(resource) => resource,
+                None => {
+                    println!(
+                        "UNRESOLVED {} ({}): domain {} has no resource",
+                        proj.name, proj_id, domain_name
+                    );
+                    continue;
+                }
+            };
+            let costs = match self.source.rates(&resource) {
+                Some(costs) => costs,
+                None => {
+                    println!(
+                        "UNRESOLVED {} ({}): resource {} has no costs in region {}",
+                        proj.name, proj_id, resource, self.config.region
+                    );
+                    continue;
+                }
+            };
+            println!(
+                "{} ({}) -> domain {} -> resource {}",
+                proj.name, proj_id, domain_name, resource
+            );
+            for (kind, rate) in costs {
+                println!("    {} = {}", kind, rate);
+            }
+        }
+    }
+}
+
+struct ProjectCost<'a> {
+    pub resource: String,
+    source: &'a dyn CostSource,
+}
+
+impl<'a> ProjectCost<'a> {
+    fn get(&self, kind: CostKind) -> Option<Decimal> {
+        self.source.rate_for_kind(&self.resource, kind)
+    }
+
+    /// Rate of a flavor, preferring a rate keyed by its id over one keyed by its name.
+    fn flavor_rate(&self, flavor: &openstack::nova::Flavor) -> Option<Decimal> {
+        let id_key = format!("{}{}", FLAVOR_ID_PREFIX, flavor.id);
+        self.get(CostKind::Flavor(&id_key))
+            .or_else(|| self.get(CostKind::Flavor(&flavor.name)))
+    }
+
+    /// Rate of an instance of a flavor with `gpus` GPUs, adding the per-GPU rate if there is one.
+    fn compute_rate(&self, flavor: &openstack::nova::Flavor, gpus: u64) -> Option<Decimal> {
+        let rate = self.flavor_rate(flavor)?;
+        match self.get(CostKind::Gpu) {
+            Some(gpu_rate) if gpus > 0 => Some(rate + gpu_rate * Decimal::from(gpus)),
+            _ => Some(rate),
+        }
+    }
+
+    /// Rate of a volume depending on whether it is attached to an instance, falling back to the
+    /// plain block storage rate.
+    fn volume_rate(&self, attached: bool) -> Option<Decimal> {
+        let kind = if attached {
+            CostKind::BlockStorageAttached
+        } else {
+            CostKind::BlockStorageDetached
+        };
+        self.get(kind).or_else(|| self.get(CostKind::BlockStorage))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+struct PersistentState {
+    last_timepoint: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct PersistentStateFile {
+    filename: Option<PathBuf>,
+    state: PersistentState,
+}
+
+impl PersistentStateFile {
+    fn open<P: Into<PathBuf>>(datadir: P) -> Result<PersistentStateFile, failure::Error> {
+        let filename = datadir.into().join("logger-state/state.json");
+        let fh = File::open(&filename);
+        let state = fh
+            .ok()
+            .and_then(|fh| serde_json::from_reader(fh).ok())
+            .unwrap_or_default();
+        Ok(PersistentStateFile {
+            filename: Some(filename),
+            state,
+        })
+    }
+
+    /// State that starts out empty and is never written to disk.
+    fn detached() -> PersistentStateFile {
+        PersistentStateFile {
+            filename: None,
+            state: PersistentState::default(),
+        }
+    }
+
+    fn write(&self) -> Result<(), failure::Error> {
+        if let Some(filename) = &self.filename {
+            let contents = serde_json::to_vec_pretty(&self.state)?;
+            std::fs::write(filename, &contents)?;
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive lock on `logger-state/lock` that keeps runs from writing records and state
+/// concurrently. The lock is released when dropped or when the process exits.
+#[derive(Debug)]
+struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Takes the lock, returning `None` if another run holds it and `on_locked` says to exit.
+    fn acquire<P: Into<PathBuf>>(
+        datadir: P,
+        on_locked: OnLocked,
+    ) -> Result<Option<RunLock>, failure::Error> {
+        use std::io::Write;
+        let path = datadir.into().join("logger-state/lock");
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                let holder = std::fs::read_to_string(&path).unwrap_or_default();
+                match on_locked {
+                    OnLocked::Exit => {
+                        info!(
+                            "Another run (pid {}) is in progress, exiting",
+                            holder.trim()
+                        );
+                        return Ok(None);
+                    }
+                    OnLocked::Wait => {
+                        info!("Waiting for another run (pid {}) to finish", holder.trim());
+                        file.lock()?;
+                    }
+                }
+            }
+            Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Some(RunLock { _file: file }))
+    }
+}
+
+/// Collects data-quality warnings by category so that they can be summarised at the end of a run.
+#[derive(Debug, Default)]
+struct WarningSummary {
+    categories: BTreeMap<&'static str, BTreeSet<String>>,
+}
+
+impl WarningSummary {
+    fn add<S: Into<String>>(&mut self, category: &'static str, item: S) {
+        self.categories
+            .entry(category)
+            .or_default()
+            .insert(item.into());
+    }
+
+    fn log(&self) {
+        if self.categories.is_empty() {
+            return;
+        }
+        let summary = self
+            .categories
+            .iter()
+            .map(|(category, items)| {
+                let items: Vec<&str> = items.iter().map(String::as_str).collect();
+                format!("{} {}: [{}]", items.len(), category, items.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        warn!("Warning summary: {}", summary);
+    }
+}
+
+/// Elapsed wall-clock time of each external call made during a run.
+#[derive(Debug, Default)]
+struct Timings {
+    calls: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl Timings {
+    fn time<T, F: FnOnce() -> T>(&mut self, name: &'static str, f: F) -> T {
+        let start = std::time::Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        debug!("{} took {:.3}s", name, elapsed.as_secs_f64());
+        self.calls.push((name, elapsed));
+        result
+    }
+
+    fn total(&self) -> std::time::Duration {
+        self.calls.iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    fn log(&self) {
+        if self.calls.is_empty() {
+            return;
+        }
+        let summary = self
+            .calls
+            .iter()
+            .map(|(name, elapsed)| format!("{} {:.3}s", name, elapsed.as_secs_f64()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!(
+            "API calls took {:.3}s: {}",
+            self.total().as_secs_f64(),
+            summary
+        );
+    }
+}
+
+const DEFAULT_USER: &str = "default";
+const DEFAULT_ZONE: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Snapshot {
+    version: usize,
+    datetime: DateTime<Utc>,
+    servers: Vec<openstack::nova::Server>,
+    flavors: openstack::Flavors,
+    images: Vec<openstack::glance::Image>,
+    volumes: Vec<openstack::cinder::Volume>,
+    object_bucket_stats: Option<Vec<radosgw::admin::BucketStats>>,
+    users: openstack::NameMapping,
+    projects: openstack::NameMapping,
+    domains: openstack::keystone::Domains,
+
+    deleted_servers: Vec<openstack::nova::Server>,
+
+    /// Quota by project id, only retrieved when billing on quota.
+    quotas: BTreeMap<String, openstack::quota::ProjectQuota>,
+
+    /// Stages whose data could not be fetched and were left empty.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    failed_stages: BTreeSet<Stage>,
+}
+
+impl Snapshot {
+    /// Ids of the projects given by id or name, failing for any that is not in the snapshot.
+    fn project_ids(&self, selectors: &[String]) -> Result<BTreeSet<String>, failure::Error> {
+        let mut ids = BTreeSet::new();
+        for selector in selectors {
+            let matching: Vec<&String> = self
+                .projects
+                .iter()
+                .filter(|(id, project)| *id == selector || project.name == *selector)
+                .map(|(id, _)| id)
+                .collect();
+            if matching.is_empty() {
+                bail!("No project {:?} in the snapshot", selector);
+            }
+            ids.extend(matching.into_iter().cloned());
+        }
+        Ok(ids)
+    }
+
+    /// Leaves out everything not billed to one of `project_ids`. Images and buckets without an
+    /// owning project are kept if their catch-all project is among them.
+    fn restrict_to_projects(&mut self, cfg: &Config, project_ids: &BTreeSet<String>) {
+        let selected = |id: Option<&str>| id.is_some_and(|id| project_ids.contains(id));
+        self.servers
+            .retain(|srv| project_ids.contains(&srv.tenant_id));
+        self.deleted_servers
+            .retain(|srv| project_ids.contains(&srv.tenant_id));
+        self.volumes.retain(|vol| selected(vol.owner()));
+        let ownerless_image_project = cfg.ownerless_image_project.as_deref();
+        self.images
+            .retain(|image| selected(image.owner.as_deref().or(ownerless_image_project)));
+        let projects = &self.projects;
+        if let Some(stats) = &mut self.object_bucket_stats {
+            stats.retain(|stat| match projects.get(&stat.owner) {
+                Some(_) => project_ids.contains(&stat.owner),
+                None => selected(cfg.unowned_bucket_project.as_deref()),
+            });
+        }
+        self.quotas.retain(|id, _| project_ids.contains(id));
+    }
+}
+
+/// Independently fallible parts of a run. A stage that fails is logged and left out, while the
+/// records of the other stages are still written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Stage {
+    Servers,
+    Volumes,
+    Images,
+    Objects,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Servers => "servers",
+            Stage::Volumes => "volumes",
+            Stage::Images => "images",
+            Stage::Objects => "objects",
+        }
+    }
+}
+
+/// Unwraps the data fetched for `stage`, logging a failure and adding the stage to `failed`.
+fn stage_data<T: Default>(
+    stage: Stage,
+    result: Result<T, failure::Error>,
+    failed: &mut BTreeSet<Stage>,
+) -> T {
+    result.unwrap_or_else(|e| {
+        error!("Could not fetch {}, not billing them: {}", stage.name(), e);
+        failed.insert(stage);
+        T::default()
+    })
+}
+
+/// Runs the processing of `stage`, logging a failure and adding the stage to `failed`. Records
+/// that the stage produced before failing are kept.
+fn run_stage<F>(stage: Stage, failed: &mut BTreeSet<Stage>, f: F)
+where
+    F: FnOnce() -> Result<(), failure::Error>,
+{
+    info!("Processing {}", stage.name());
+    if let Err(e) = f() {
+        error!("Failed to process {}: {}", stage.name(), e);
+        failed.insert(stage);
+    }
+}
+
+/// Version of the snapshots written by this logger, older versions down to 3 are migrated when
+/// loaded. Version 4 made `deleted_servers` and `quotas` mandatory.
+const SNAPSHOT_VERSION: usize = 4;
+
+/// Fills in the fields that version 3 snapshots may lack.
+fn migrate_snapshot_v3(snap: &mut serde_json::Map<String, serde_json::Value>) {
+    snap.entry("deleted_servers")
+        .or_insert_with(|| serde_json::json!([]));
+    snap.entry("quotas")
+        .or_insert_with(|| serde_json::json!({}));
+}
+
+impl Snapshot {
+    /// Reads a snapshot, decompressing it first if the path ends in `.gz`.
+    fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Snapshot, failure::Error> {
+        let path = path.as_ref();
+        let contents = if is_gzip_path(path) {
+            gunzip_file(path)?
+        } else {
+            std::fs::read(path)?
+        };
+        Snapshot::from_json(serde_json::from_slice(&contents)?)
+    }
+
+    /// Parses a snapshot of the current or an older version, migrating older ones.
+    fn from_json(mut value: serde_json::Value) -> Result<Snapshot, failure::Error> {
+        let snap = value
+            .as_object_mut()
+            .ok_or(format_err!("Snapshot is not a JSON object"))?;
+        let version = snap
+            .get("version")
+            .and_then(|v| v.as_u64())
+            .ok_or(format_err!("Snapshot has no version"))? as usize;
+        if version < 3 {
+            bail!("Snapshot version predates domains, exiting.");
+        }
+        if version > SNAPSHOT_VERSION {
+            bail!(
+                "Snapshot version {} is newer than the supported version {}",
+                version,
+                SNAPSHOT_VERSION
+            );
+        }
+        for from in version..SNAPSHOT_VERSION {
+            match from {
+                3 => migrate_snapshot_v3(snap),
+                _ => unreachable!(),
+            }
+            debug!("Migrated snapshot from version {} to {}", from, from + 1);
+        }
+        snap.insert("version".to_owned(), SNAPSHOT_VERSION.into());
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the snapshot as JSON, compressed with gzip if the path ends in `.gz`.
+    fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), failure::Error> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec_pretty(self)?;
+        let contents = if is_gzip_path(path) {
+            gzip(json)?
+        } else {
+            json
+        };
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Human readable overview of the snapshot contents.
+    fn summary(&self) -> String {
+        let mut statuses: BTreeMap<&str, usize> = BTreeMap::new();
+        for server in &self.servers {
+            *statuses.entry(server.status.as_str()).or_default() += 1;
+        }
+        let statuses = statuses
+            .iter()
+            .map(|(status, count)| format!("{} {}", count, status))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let volume_gigabytes: u64 = self.volumes.iter().map(|vol| vol.size).sum();
+        let image_bytes: Bytes = self
+            .images
+            .iter()
+            .filter_map(|img| img.size)
+            .map(Bytes)
+            .sum();
+        let buckets = match &self.object_bucket_stats {
+            Some(stats) => {
+                let size: Bytes = stats
+                    .iter()
+                    .filter_map(|s| {
+                        s.billable_size(radosgw::admin::DEFAULT_EXCLUDED_USAGE_CATEGORIES)
+                    })
+                    .sum();
+                format!("{} ({} bytes)", stats.len(), size)
+            }
+            None => "not available".to_owned(),
+        };
+        let mut lines = vec![
+            format!("version:          {}", self.version),
+            format!("datetime:         {}", self.datetime.to_rfc3339()),
+            format!("servers:          {} ({})", self.servers.len(), statuses),
+            format!("deleted servers:  {}", self.deleted_servers.len()),
+            format!("flavors:          {}", self.flavors.len()),
+            format!(
+                "volumes:          {} ({} GB)",
+                self.volumes.len(),
+                volume_gigabytes
+            ),
+            format!(
+                "images:           {} ({} bytes)",
+                self.images.len(),
+                image_bytes
+            ),
+            format!("object buckets:   {}", buckets),
+            format!("users:            {}", self.users.iter().count()),
+            format!("projects:         {}", self.projects.iter().count()),
+            format!("domains:          {}", self.domains.domains.len()),
+        ];
+        if !self.quotas.is_empty() {
+            lines.push(format!("project quotas:   {}", self.quotas.len()));
+        }
+        lines.join("\n")
+    }
+}
+
+fn is_gzip_path(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Compresses `data` with gzip.
+fn gzip(data: Vec<u8>) -> Result<Vec<u8>, failure::Error> {
+    use std::io::Write;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses the gzip file at `path`.
+fn gunzip_file(path: &std::path::Path) -> Result<Vec<u8>, failure::Error> {
+    use std::io::Read;
+    let mut contents = Vec::new();
+    GzDecoder::new(File::open(path)?)
+        .read_to_end(&mut contents)
+        .map_err(|e| format_err!("Could not decompress {:?}: {}", path, e))?;
+    Ok(contents)
+}
+
+/// Servers that were billable in the previous snapshot but have since disappeared or been deleted.
+fn vanished_servers<'a>(
+    cfg: &Config,
+    previous: &'a [openstack::nova::Server],
+    current: &[openstack::nova::Server],
+) -> Vec<&'a openstack::nova::Server> {
+    let still_present: BTreeSet<&str> = current
+        .iter()
+        .filter(|srv| cfg.billed_as(&srv.status) != BillingCategory::Unbilled)
+        .map(|srv| srv.id.as_str())
+        .collect();
+    previous
+        .iter()
+        .filter(|srv| cfg.billed_as(&srv.status) != BillingCategory::Unbilled)
+        .filter(|srv| !still_present.contains(srv.id.as_str()))
+        .collect()
+}
+
+/// Root volume of a boot-from-volume instance along with the number of gigabytes of it that are
+/// already covered by the compute record. Unbilled instances, such as shelved ones, have no compute
+/// record to cover their root volume so get no discount.
+fn root_volume_discount(
+    cfg: &Config,
+    server: &openstack::nova::Server,
+    flavor: &openstack::nova::Flavor,
+) -> Option<(String, u64)> {
+    use openstack::nova;
+    if cfg.billed_as(&server.status) == BillingCategory::Unbilled {
+        return None;
+    }
+    let image_backed = match &server.image {
+        nova::Image::StringRep(x) => !x.is_empty(),
+        nova::Image::ObjectRep { id } => !id.is_empty(),
+    };
+    if image_backed {
+        return None;
+    }
+    let root_volume = server.attached_volumes.first()?;
+    Some((root_volume.id.clone(), flavor.disk))
+}
+
+/// Billable gigabytes of a volume of `size` gigabytes, less the part of it covered by the
+/// remaining root volume discount in `discounts`. The discount taken is at most the size of the
+/// volume and is used up, so that it is only granted once.
+fn discounted_volume_gigs(
+    discounts: &mut BTreeMap<String, u64>,
+    volume_id: &str,
+    size: u64,
+) -> u64 {
+    match discounts.get_mut(volume_id) {
+        Some(remaining) => {
+            let discount = (*remaining).min(size);
+            *remaining -= discount;
+            size - discount
+        }
+        None => size,
+    }
+}
+
+/// The `cr:GPUCount` extension of compute records of instances with GPUs.
+fn gpu_extensions(gpus: u64) -> Vec<(String, String)> {
+    if gpus == 0 {
+        return Vec::new();
+    }
+    vec![("GPUCount".to_owned(), gpus.to_string())]
+}
+
+/// Volumes attached to servers that are missing from the Cinder volume listing and thus would go
+/// unbilled, as pairs of server and volume id.
+fn unlisted_attached_volumes<'a>(
+    servers: &'a [openstack::nova::Server],
+    volumes: &[openstack::cinder::Volume],
+) -> Vec<(&'a openstack::nova::Server, &'a str)> {
+    let listed: BTreeSet<&str> = volumes.iter().map(|vol| vol.id.as_str()).collect();
+    servers
+        .iter()
+        .flat_map(|srv| srv.attached_volumes.iter().map(move |vol| (srv, vol)))
+        .filter(|(_, vol)| !listed.contains(vol.id.as_str()))
+        .map(|(srv, vol)| (srv, vol.id.as_str()))
+        .collect()
+}
+
+/// Object buckets with billable usage whose owner is not a Keystone project, written to
+/// `logger-state/unowned-buckets.json` by runs that persist state.
+#[derive(Debug, Serialize)]
+struct UnownedBucketReport<'a> {
+    datetime: DateTime<Utc>,
+    count: usize,
+    total_bytes: u64,
+    buckets: Vec<UnownedBucket<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnownedBucket<'a> {
+    region: &'a str,
+    id: &'a str,
+    bucket: &'a str,
+    owner: &'a str,
+    bytes: u64,
+    /// Catch-all project the bucket was billed to, if any.
+    billed_to: Option<&'a str>,
+}
+
+impl<'a> UnownedBucketReport<'a> {
+    /// The unowned buckets of the snapshots of all regions billed by a run.
+    fn new(regions: &[(&'a Config, &'a Snapshot)]) -> UnownedBucketReport<'a> {
+        let buckets: Vec<UnownedBucket> = regions
+            .iter()
+            .flat_map(|&(cfg, snap)| {
+                snap.object_bucket_stats
+                    .iter()
+                    .flatten()
+                    .filter(move |stat| snap.projects.get(&stat.owner).is_none())
+                    .filter_map(move |stat| {
+                        let bytes = stat.billable_size(&cfg.object_excluded_usage)?;
+                        Some(UnownedBucket {
+                            region: &cfg.region,
+                            id: &stat.id,
+                            bucket: &stat.bucket,
+                            owner: &stat.owner,
+                            bytes: bytes.0,
+                            billed_to: cfg.unowned_bucket_project.as_deref(),
+                        })
+                    })
+            })
+            .collect();
+        UnownedBucketReport {
+            datetime: regions[0].1.datetime,
+            count: buckets.len(),
+            total_bytes: buckets.iter().map(|b| b.bytes).sum(),
+            buckets,
+        }
+    }
+}
+
+/// Whether an instance was created too shortly before it was seen at `seen_at` to be billed.
+fn within_grace_period(
+    created: Option<DateTime<Utc>>,
+    seen_at: DateTime<Utc>,
+    min_age: chrono::Duration,
+) -> bool {
+    match created {
+        Some(created) => created > seen_at - min_age,
+        None => false,
+    }
+}
+
+/// Fraction of the period starting at `billed_until` that an instance terminated at
+/// `terminated_at` was present for, along with the end of that presence.
+fn present_fraction(
+    billed_until: DateTime<Utc>,
+    duration: chrono::Duration,
+    terminated_at: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, Decimal)> {
+    if terminated_at <= billed_until {
+        return None;
+    }
+    let present_until = std::cmp::min(terminated_at, billed_until + duration);
+    let present = (present_until - billed_until).num_seconds();
+    let fraction = Decimal::from(present) / Decimal::from(duration.num_seconds());
+    Some((present_until, fraction))
+}
+
+/// Fraction of the period starting at `start_time` that something created at `created` was
+/// present for, along with the start of that presence, or `None` if it was created before the
+/// period or after it.
+fn created_fraction(
+    start_time: DateTime<Utc>,
+    duration: chrono::Duration,
+    created: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, Decimal)> {
+    let end_time = start_time + duration;
+    if created <= start_time || created >= end_time {
+        return None;
+    }
+    let present = (end_time - created).num_seconds();
+    let fraction = Decimal::from(present) / Decimal::from(duration.num_seconds());
+    Some((created, fraction))
+}
+
+/// Applies `min_billable_duration` to a presence from `present_fraction`, returning `None` if the
+/// instance is not to be billed. Only deleted instances are billed for part of a period, so this is
+/// the only presence it applies to.
+fn apply_min_billable_duration(
+    cfg: &Config,
+    billed_until: DateTime<Utc>,
+    duration: chrono::Duration,
+    (present_until, fraction): (DateTime<Utc>, Decimal),
+) -> Option<(DateTime<Utc>, Decimal)> {
+    let min = std::cmp::min(
+        chrono::Duration::seconds(cfg.min_billable_duration),
+        duration,
+    );
+    if present_until - billed_until >= min {
+        return Some((present_until, fraction));
+    }
+    match cfg.below_min_duration {
+        BelowMinDuration::Skip => None,
+        BelowMinDuration::BillMinimum => {
+            let fraction = Decimal::from(min.num_seconds()) / Decimal::from(duration.num_seconds());
+            Some((billed_until + min, fraction))
+        }
+    }
+}
+
+/// Adds the flavors from `extra` that are missing from `flavors`, or all of them if `overwrite`
+/// is set, returning the number of flavors added or replaced.
+fn merge_flavors(
+    flavors: &mut openstack::Flavors,
+    extra: &openstack::Flavors,
+    overwrite: bool,
+) -> usize {
+    let mut merged = 0;
+    for (id, flavor) in extra {
+        if overwrite || !flavors.contains_key(id) {
+            flavors.insert(id.clone(), flavor.clone());
+            merged += 1;
+        }
+    }
+    merged
+}
+
+fn tmp_path_for(path: &std::path::Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    tmp_path.into()
+}
+
+/// Moves the fully written `tmp_path` over `path` with a rename, so that readers never see a
+/// partially written file. Returns false and removes `tmp_path` if `path` already has the same
+/// contents.
+fn replace_if_changed(
+    tmp_path: &std::path::Path,
+    path: &std::path::Path,
+) -> Result<bool, failure::Error> {
+    if files_equal(tmp_path, path).unwrap_or(false) {
+        std::fs::remove_file(tmp_path)?;
+        return Ok(false);
+    }
+    std::fs::rename(tmp_path, path)?;
+    Ok(true)
+}
+
+/// Batch id of the record file `path`, the region followed by the name of the file without its
+/// extension, such as `north-1/20190213T1200Z`.
+fn batch_id_for(region: &str, path: &std::path::Path) -> String {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    format!("{}/{}", region, stem)
+}
+
+/// What `manifest.json` of a records dir lists for each record file in it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct ManifestEntry {
+    batch_id: String,
+    records: usize,
+    total_cost: Decimal,
+    /// SHA-256 of the file as lowercase hex, missing in manifests of older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Adds or replaces the entry of the record file `path` in the manifest next to it.
+fn record_in_manifest(path: &std::path::Path, entry: ManifestEntry) -> Result<(), failure::Error> {
+    let manifest_path = path.with_file_name(MANIFEST_FILENAME);
+    let mut manifest: BTreeMap<String, ManifestEntry> = match File::open(&manifest_path) {
+        Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| format_err!("Reading {:?}: {}", manifest_path, e))?,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    manifest.insert(name.into_owned(), entry);
+    let tmp = tmp_path_for(&manifest_path);
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&manifest)?)?;
+    std::fs::rename(&tmp, &manifest_path)?;
+    Ok(())
+}
+
+/// Path of the SHA-256 sidecar written next to `path`.
+fn checksum_path_for(path: &std::path::Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+/// SHA-256 of the contents of `path` as lowercase hex.
+fn sha256_hex(path: &std::path::Path) -> Result<String, failure::Error> {
+    let digest = openssl::sha::sha256(&std::fs::read(path)?);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Writes `sha256` as the checksum of `path` to its sidecar in the format of `sha256sum`,
+/// replacing any different previous sidecar with a rename. The file itself need not exist yet, so
+/// that the sidecar can be in place before the file is published.
+fn write_checksum_sidecar(path: &std::path::Path, sha256: &str) -> Result<PathBuf, failure::Error> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let line = format!("{}  {}\n", sha256, name);
+    let sidecar = checksum_path_for(path);
+    if std::fs::read_to_string(&sidecar).ok().as_deref() != Some(line.as_str()) {
+        let tmp = tmp_path_for(&sidecar);
+        std::fs::write(&tmp, line)?;
+        std::fs::rename(&tmp, &sidecar)?;
+    }
+    Ok(sidecar)
+}
+
+/// Record files of the hours of `day` that exist in `records_dir`, in order, including the
+/// parts of hours written split by kind.
+fn day_record_files(
+    records_dir: &std::path::Path,
+    layout: RecordsLayout,
+    day: chrono::NaiveDate,
+) -> Vec<PathBuf> {
+    (0..24)
+        .map(|hour| DateTime::<Utc>::from_utc(day.and_hms(hour, 0, 0), Utc))
+        .map(|timepoint| layout.path_for(records_dir, timepoint))
+        .flat_map(|path| {
+            let parts = SPLIT_KIND_PARTS
+                .iter()
+                .map(|part| split_path_for(&path, part))
+                .collect::<Vec<_>>();
+            std::iter::once(path).chain(parts)
+        })
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn check_well_formed_xml(path: &std::path::Path) -> Result<(), failure::Error> {
+    let reader = xml::reader::EventReader::new(std::io::BufReader::new(File::open(path)?));
+    for event in reader {
+        if let Err(e) = event {
+            bail!("Record file {:?} is not well-formed XML: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Packs the record files of `day` into `<records_dir>/<YYYYmmdd>.tar.gz` after checking that
+/// each of them is well-formed, returning the path of the archive.
+fn archive_day(
+    records_dir: &std::path::Path,
+    layout: RecordsLayout,
+    day: chrono::NaiveDate,
+) -> Result<PathBuf, failure::Error> {
+    let files = day_record_files(records_dir, layout, day);
+    if files.is_empty() {
+        bail!("No record files for {} in {:?}", day, records_dir);
+    }
+    for path in &files {
+        check_well_formed_xml(path)?;
+    }
+
+    let archive = records_dir.join(format!("{}.tar.gz", day.format("%Y%m%d")));
+    let tmp = tmp_path_for(&archive);
+    if let Err(e) = write_tar_gz(&tmp, records_dir, &files) {
+        let _ = std::fs::remove_file(&tmp);
+        bail!("Could not archive {} to {:?}: {}", day, tmp, e);
+    }
+    std::fs::rename(&tmp, &archive)?;
+    info!("Archived {} record files to {:?}", files.len(), archive);
+    Ok(archive)
+}
+
+/// Writes `files` to the gzip-compressed tar archive `path`, named relative to `base`.
+fn write_tar_gz(
+    path: &std::path::Path,
+    base: &std::path::Path,
+    files: &[PathBuf],
+) -> std::io::Result<()> {
+    let mut builder =
+        tar::Builder::new(GzEncoder::new(File::create(path)?, Compression::default()));
+    for file in files {
+        builder.append_path_with_name(file, file.strip_prefix(base).unwrap_or(file))?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn files_equal(a: &std::path::Path, b: &std::path::Path) -> std::io::Result<bool> {
+    use std::io::Read;
+    if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let mut a = std::io::BufReader::new(File::open(a)?);
+    let mut b = std::io::BufReader::new(File::open(b)?);
+    let mut buf_a = [0u8; 8192];
+    let mut buf_b = [0u8; 8192];
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// `cr:StorageType` of Cinder volumes.
+const BLOCK_STORAGE_TYPE: &str = "Block";
+/// `cr:StorageType` of Glance images.
+const IMAGE_STORAGE_TYPE: &str = "Image";
+/// `cr:StorageType` of object storage buckets.
+const OBJECT_STORAGE_TYPE: &str = "Object";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageKind {
+    Volumes,
+    Images,
+    Objects,
+}
+
+impl StorageKind {
+    fn name(self) -> &'static str {
+        match self {
+            StorageKind::Volumes => "volumes",
+            StorageKind::Images => "images",
+            StorageKind::Objects => "objects",
+        }
+    }
+
+    /// `cr:StorageType` reported unless overridden by `storage_type_map`.
+    fn default_storage_type(self) -> &'static str {
+        match self {
+            StorageKind::Volumes => BLOCK_STORAGE_TYPE,
+            StorageKind::Images => IMAGE_STORAGE_TYPE,
+            StorageKind::Objects => OBJECT_STORAGE_TYPE,
+        }
+    }
+}
+
+/// Receives records as they are produced, applies the cost multipliers and streams them to the
+/// output, or storage records to a separate output if one is given. Only storage records that are
+/// aggregated per project are held until `finish`, grouped by kind and region.
+struct RecordSink<'a, W: std::io::Write> {
+    cfg: &'a Config,
+    format: records::v1::RecordFormat,
+    writer: records::v1::FormatWriter<W>,
+    storage_writer: Option<records::v1::FormatWriter<W>>,
+    aggregated: Vec<((StorageKind, String), Vec<records::v1::CloudStorageRecord>)>,
+    /// IDs of the records written so far, kept to catch duplicates if they are checked for.
+    record_ids: Option<BTreeSet<String>>,
+    total_cost: Decimal,
+    storage_cost: Decimal,
+    total_allocated_bytes: u64,
+}
+
+impl<'a, W: std::io::Write> RecordSink<'a, W> {
+    #[cfg(test)]
+    fn new(cfg: &'a Config, output: W) -> Result<Self, failure::Error> {
+        RecordSink::with_format(cfg, output, records::v1::RecordFormat::Xml, None)
+    }
+
+    fn with_format(
+        cfg: &'a Config,
+        output: W,
+        format: records::v1::RecordFormat,
+        batch_id: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        Ok(RecordSink {
+            cfg,
+            format,
+            writer: RecordSink::open_writer(cfg, output, format, batch_id)?,
+            storage_writer: None,
+            aggregated: Vec::new(),
+            record_ids: None,
+            total_cost: Decimal::default(),
+            storage_cost: Decimal::default(),
+            total_allocated_bytes: 0,
+        })
+    }
+
+    /// Writes the storage records to `output` instead of along with the compute records.
+    fn with_storage_output(
+        mut self,
+        output: W,
+        batch_id: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        self.storage_writer = Some(RecordSink::open_writer(
+            self.cfg,
+            output,
+            self.format,
+            batch_id,
+        )?);
+        Ok(self)
+    }
+
+    /// Fails on records whose ID was already written, for records of several regions that must
+    /// not clash.
+    fn with_unique_record_ids(mut self) -> Self {
+        self.record_ids = Some(BTreeSet::new());
+        self
+    }
+
+    fn open_writer(
+        cfg: &Config,
+        output: W,
+        format: records::v1::RecordFormat,
+        batch_id: Option<&str>,
+    ) -> Result<records::v1::FormatWriter<W>, failure::Error> {
+        let mut writer = format.writer(output, cfg.decimal_separator, batch_id)?;
+        writer.comment(&format!("ssc-billing-logger {}", BUILD_VERSION))?;
+        Ok(writer)
+    }
+
+    fn prepare(&self, common: &mut records::v1::CloudRecordCommon) {
+        apply_cost_multipliers(self.cfg, std::iter::once(&mut *common));
+        common.allocated_disk = self
+            .cfg
+            .allocated_disk_rounding
+            .round(common.allocated_disk);
+        common.resource = self.cfg.render_resource(&common.region, &common.resource);
+        common.extensions.extend(
+            self.cfg
+                .record_extensions
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+    }
+
+    /// Checks that none of the given values of a record are negative, failing or skipping the
+    /// record as configured by `negative_values` if they are.
+    fn accept(&self, record_id: &str, values: &[(&str, Decimal)]) -> Result<bool, failure::Error> {
+        let negative: Vec<String> = values
+            .iter()
+            .filter(|(_, value)| *value < Decimal::default())
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect();
+        if negative.is_empty() {
+            return Ok(true);
+        }
+        match self.cfg.negative_values {
+            NegativeValues::Fail => {
+                bail!("Record {} has negative {}", record_id, negative.join(", "))
+            }
+            NegativeValues::Skip => {
+                warn!(
+                    "Skipping record {} with negative {}",
+                    record_id,
+                    negative.join(", ")
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Writes a compute record, returning its cost after multipliers or `None` if it was skipped.
+    fn compute(
+        &mut self,
+        mut cr: records::v1::CloudComputeRecord,
+    ) -> Result<Option<Decimal>, failure::Error> {
+        self.prepare(&mut cr.common);
+        if !has_positive_duration(&cr.record_id(), &cr.common) {
+            return Ok(None);
+        }
+        let values = [
+            ("cost", cr.common.cost),
+            ("allocated CPU", cr.allocated_cpu),
+            ("allocated memory", cr.allocated_memory),
+        ];
+        if !self.accept(&cr.record_id(), &values)? {
+            return Ok(None);
+        }
+        check_unique_record_id(&mut self.record_ids, cr.record_id())?;
+        self.writer.write(&cr)?;
+        self.add_totals(&cr.common);
+        Ok(Some(cr.common.cost))
+    }
+
+    fn storage(
+        &mut self,
+        kind: StorageKind,
+        mut sr: records::v1::CloudStorageRecord,
+    ) -> Result<Option<Decimal>, failure::Error> {
+        self.prepare(&mut sr.common);
+        if !has_positive_duration(&sr.record_id(), &sr.common) {
+            return Ok(None);
+        }
+        if !self.accept(&sr.record_id(), &[("cost", sr.common.cost)])? {
+            return Ok(None);
+        }
+        let cost = sr.common.cost;
+        self.add_totals(&sr.common);
+        self.storage_cost += cost;
+        match self.cfg.aggregate_storage {
+            AggregateStorage::PerVolume => {
+                check_unique_record_id(&mut self.record_ids, sr.record_id())?;
+                self.storage_writer
+                    .as_mut()
+                    .unwrap_or(&mut self.writer)
+                    .write(&sr)?
+            }
+            AggregateStorage::PerProject => {
+                let key = (kind, sr.common.region.clone());
+                match self.aggregated.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, records)) => records.push(sr),
+                    None => self.aggregated.push((key, vec![sr])),
+                }
+            }
+        }
+        Ok(Some(cost))
+    }
+
+    fn add_totals(&mut self, common: &records::v1::CloudRecordCommon) {
+        self.total_cost += common.cost;
+        self.total_allocated_bytes += common.allocated_disk.0;
+    }
+
+    /// Total cost and allocated disk of the accepted records.
+    fn totals(&self) -> (Decimal, u64) {
+        (self.total_cost, self.total_allocated_bytes)
+    }
+
+    /// Total cost of the accepted storage records.
+    fn storage_cost(&self) -> Decimal {
+        self.storage_cost
+    }
+
+    /// Writes any aggregated records, closes the document and returns the output along with the
+    /// number of records written, for sinks without a separate storage output.
+    #[cfg(test)]
+    fn finish(self) -> Result<(W, usize), failure::Error> {
+        Ok(self.finish_outputs()?.remove(0))
+    }
+
+    /// Writes any aggregated records, closes the documents and returns each output along with the
+    /// number of records written to it, the separate storage output last if there is one.
+    fn finish_outputs(mut self) -> Result<Vec<(W, usize)>, failure::Error> {
+        let storage_writer = self.storage_writer.as_mut().unwrap_or(&mut self.writer);
+        for ((kind, _), batch) in std::mem::take(&mut self.aggregated) {
+            for sr in records::v1::aggregate_storage_per_project(batch, kind.name()) {
+                check_unique_record_id(&mut self.record_ids, sr.record_id())?;
+                storage_writer.write(&sr)?;
+            }
+        }
+        let mut outputs = Vec::new();
+        for writer in std::iter::once(self.writer).chain(self.storage_writer) {
+            let count = writer.count();
+            outputs.push((writer.finish()?, count));
+        }
+        Ok(outputs)
+    }
+}
+
+/// Adds `record_id` to `seen` if it is kept, failing if it is already there.
+fn check_unique_record_id(
+    seen: &mut Option<BTreeSet<String>>,
+    record_id: String,
+) -> Result<(), failure::Error> {
+    if let Some(seen) = seen {
+        if seen.contains(&record_id) {
+            bail!("Duplicate record ID {} in the same output", record_id);
+        }
+        seen.insert(record_id);
+    }
+    Ok(())
+}
+
+/// The period of `duration` starting at the full hour of `datetime`, failing if it is empty.
+fn billing_period(
+    datetime: DateTime<Utc>,
+    duration: chrono::Duration,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), failure::Error> {
+    let start_time = datetime
+        .with_minute(0)
+        .unwrap()
+        .with_second(0)
+        .unwrap()
+        .with_nanosecond(0)
+        .unwrap();
+    let end_time = start_time + duration;
+    if start_time >= end_time {
+        bail!(
+            "Billing period from {} to {} is not positive, check the clock and snapshot times",
+            start_time,
+            end_time
+        );
+    }
+    Ok((start_time, end_time))
+}
+
+/// Whether a record covers a positive period, which the collector requires.
+fn has_positive_duration(record_id: &str, common: &records::v1::CloudRecordCommon) -> bool {
+    if common.start_time < common.end_time && common.duration > chrono::Duration::zero() {
+        return true;
+    }
+    warn!(
+        "Skipping record {} with non-positive duration from {} to {}",
+        record_id, common.start_time, common.end_time
+    );
+    false
+}
+
+/// Builds one compute and one volume storage record per project from its allocated quota,
+/// priced with the `quota.*` costs of the project's resource.
+fn quota_records(
+    cfg: &Config,
+    snap: &Snapshot,
+    cost_lookup: &CostLookup,
+    start_time: DateTime<Utc>,
+    duration: chrono::Duration,
+    warnings: &mut WarningSummary,
+) -> (
+    Vec<records::v1::CloudComputeRecord>,
+    Vec<records::v1::CloudStorageRecord>,
+) {
+    use records::v1::{CloudComputeRecord, CloudRecordCommon, CloudStorageRecord};
+
+    let mut computes = Vec::new();
+    let mut storages = Vec::new();
+    for (project_id, quota) in &snap.quotas {
+        let (project_name, proj_costs) = match cost_lookup.resolve_project(project_id) {
+            Some(resolved) => resolved,
+            None => {
+                warnings.add("quotas skipped: no costs for project", project_id.as_str());
+                continue;
+            }
+        };
+        if quota.cores < 0 || quota.ram < 0 || quota.gigabytes < 0 {
+            warn!("Skipping unlimited quota of project {}", project_id);
+            warnings.add("quotas skipped: unlimited", project_id.as_str());
+            continue;
+        }
+        let cores = quota.cores as u64;
+        let ram = Megabytes(quota.ram as u64);
+        let gigabytes = Gigabytes(quota.gigabytes as u64);
+
+        let common =
+            |instance_id: String, cost: Decimal, allocated_disk: Bytes| CloudRecordCommon {
+                create_time: snap.datetime,
+                site: cfg.site.clone(),
+                project: project_name.clone(),
+                user: DEFAULT_USER.to_owned(),
+                instance_id,
+                start_time,
+                end_time: start_time + duration,
+                duration,
+                region: cfg.region.clone(),
+                resource: proj_costs.resource.clone(),
+                zone: DEFAULT_ZONE.to_owned(),
+                cost,
+                allocated_disk,
+                extensions: Vec::new(),
+            };
+
+        let core_cost =
+            proj_costs.get(CostKind::QuotaCore).unwrap_or_default() * Decimal::from(cores);
+        let ram_cost = proj_costs
+            .get(CostKind::QuotaRam)
+            .map(|r| gigabyte_cost(ram.into(), r))
+            .unwrap_or_default();
+        let compute_cost = core_cost + ram_cost;
+        if !compute_cost.is_zero() {
+            computes.push(CloudComputeRecord {
+                common: common(
+                    format!("quota-compute-{}", project_id),
+                    compute_cost,
+                    Bytes(0),
+                ),
+                flavour: "quota".to_owned(),
+                allocated_cpu: Decimal::from(cores),
+                allocated_memory: cfg.memory_unit.convert(ram),
+                used_cpu: None,
+                used_memory: None,
+                used_network_up: None,
+                used_network_down: None,
+                iops: None,
+            });
+        }
+
+        let block_cost = proj_costs
+            .get(CostKind::QuotaBlock)
+            .map(|r| gigabyte_cost(gigabytes.into(), r))
+            .unwrap_or_default();
+        if !block_cost.is_zero() {
+            storages.push(CloudStorageRecord {
+                common: common(
+                    format!("quota-volumes-{}", project_id),
+                    block_cost,
+                    gigabytes.into(),
+                ),
+                file_count: 0,
+                storage_type: cfg.storage_type(StorageKind::Volumes, None),
+            });
+        }
+    }
+    (computes, storages)
+}
+
+/// Retrieves the current state of the cloud from the OpenStack APIs and radosgw.
+fn fetch_snapshot(
+    opt: &Opt,
+    cfg: &Config,
+    this_run_datetime: DateTime<Utc>,
+    timings: &mut Timings,
+) -> Result<Snapshot, failure::Error> {
+    let credentials = openstack::Credentials {
+        username: cfg.username.clone(),
+        password: cfg.password.clone(),
+        user_domain: cfg.user_domain().to_owned(),
+        project_domain: cfg.project_domain().to_owned(),
+        project: cfg.project.clone(),
+        auth_flow: cfg.auth_flow,
+    };
+
+    let host_rewrite = cfg.host_rewrite();
+    let session = timings.time("keystone auth", || {
+        openstack::Session::new(
+            &credentials,
+            &cfg.keystone_url,
+            &cfg.keystone_api_path,
+            cfg.service_region(),
+            &cfg.endpoint_interface,
+            Some(&host_rewrite).filter(|_| opt.rewrite_host),
+            cfg.user_agent
+                .as_deref()
+                .unwrap_or(openstack::DEFAULT_USER_AGENT),
+            cfg.request_timeout(),
+        )
+        .map(|session| session.with_retry_policy(cfg.retry_policy()))
+    })?;
+
+    let mut failed_stages = BTreeSet::new();
+    let servers = stage_data(
+        Stage::Servers,
+        timings.time("nova servers", || session.servers()),
+        &mut failed_stages,
+    );
+    let flavors = stage_data(
+        Stage::Servers,
+        timings.time("nova flavors", || session.flavors()),
+        &mut failed_stages,
+    );
+    let images = stage_data(
+        Stage::Images,
+        timings.time("glance images", || session.images()),
+        &mut failed_stages,
+    );
+    let volumes = stage_data(
+        Stage::Volumes,
+        timings.time("cinder volumes", || session.volumes()),
+        &mut failed_stages,
+    );
+    let object_bucket_stats = timings.time("radosgw bucket stats", || {
+        cfg.bucket_stats_source()?.bucket_stats()
+    });
+    let deleted_servers = timings
+        .time("nova deleted servers", || {
+            session.deleted_servers(this_run_datetime - chrono::Duration::hours(1))
+        })
+        .unwrap_or_else(|e| {
+            warn!("Could not retrieve deleted servers: {}", e);
+            Vec::new()
+        });
+
+    let users = timings.time("keystone users", || session.user_mappings())?;
+    let projects = timings.time("keystone projects", || session.project_mappings())?;
+    let domains = timings.time("keystone domains", || session.domains())?;
+
+    let mut quotas = BTreeMap::new();
+    if cfg.billing_mode == BillingMode::Quota {
+        timings.time("project quotas", || -> Result<(), failure::Error> {
+            for (project_id, _) in projects.iter() {
+                quotas.insert(project_id.clone(), session.project_quota(project_id)?);
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(Snapshot {
+        version: SNAPSHOT_VERSION,
+        datetime: this_run_datetime,
+        servers,
+        flavors,
+        images,
+        volumes,
+        object_bucket_stats: object_bucket_stats.ok(),
+        users,
+        projects,
+        domains,
+        deleted_servers,
+        quotas,
+        failed_stages,
+    })
+}
+
+/// Processes the hours between the last processed one and `this_run_datetime` from snapshots
+/// named `<YYYYmmddTHHMMZ>.json` or `<YYYYmmddTHHMMZ>.json.gz` in `snapshot_dir`, advancing the
+/// persistent state for each.
+fn catch_up(
+    opt: &Opt,
+    cfg: &Config,
+    costs: &CostsFile,
+    snapshot_dir: &std::path::Path,
+    this_run_datetime: DateTime<Utc>,
+    persistent_state: &mut PersistentStateFile,
+) -> Result<(), failure::Error> {
+    let last_timepoint = match persistent_state.state.last_timepoint {
+        Some(t) => t,
+        None => {
+            info!("No previously processed hour, nothing to catch up");
+            return Ok(());
+        }
+    };
+    let snapshot_path = |t: DateTime<Utc>| {
+        let plain = snapshot_dir.join(format!("{}.json", t.format("%Y%m%dT%H%MZ")));
+        let compressed = snapshot_dir.join(format!("{}.json.gz", t.format("%Y%m%dT%H%MZ")));
+        if !plain.exists() && compressed.exists() {
+            compressed
+        } else {
+            plain
+        }
+    };
+
+    let hour = chrono::Duration::hours(1);
+    let mut previous = Snapshot::load(snapshot_path(last_timepoint)).ok();
+    let mut timepoint = last_timepoint + hour;
+    while timepoint < this_run_datetime {
+        let path = snapshot_path(timepoint);
+        if path.exists() {
+            info!("Catching up on {} from {:?}", timepoint, path);
+            let snap = Snapshot::load(&path)?;
+            if snap.datetime != timepoint {
+                bail!("Snapshot {:?} is for {}", path, snap.datetime);
+            }
+            process_snapshot(opt, cfg, costs, &snap, previous.as_ref(), persistent_state)?;
+            previous = Some(snap);
+        } else {
+            warn!("No snapshot for {} at {:?}, leaving a gap", timepoint, path);
+            previous = None;
+        }
+        timepoint = timepoint + hour;
+    }
+    Ok(())
+}
+
+/// Hours rebuilt by `replay_dir`, and the snapshots that failed with their errors.
+#[derive(Debug, Default)]
+struct ReplaySummary {
+    processed: usize,
+    failed: Vec<(PathBuf, String)>,
+}
+
+/// Rebuilds the records of every snapshot in `dir` in order of their time. A snapshot that fails
+/// to load or process is logged and skipped, and the others are still processed.
+fn replay_dir(
+    opt: &Opt,
+    cfg: &Config,
+    costs: &CostsFile,
+    dir: &std::path::Path,
+    persistent_state: &mut PersistentStateFile,
+) -> Result<ReplaySummary, failure::Error> {
+    let mut summary = ReplaySummary::default();
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.to_string_lossy();
+        if !name.ends_with(".json") && !name.ends_with(".json.gz") {
+            continue;
+        }
+        // Only the time is kept, so that a month of snapshots need not fit in memory at once.
+        match Snapshot::load(&path) {
+            Ok(snap) => paths.push((snap.datetime, path)),
+            Err(e) => {
+                error!("Could not load snapshot {:?}: {}", path, e);
+                summary.failed.push((path, e.to_string()));
+            }
+        }
+    }
+    paths.sort();
+    info!("Replaying {} snapshots from {:?}", paths.len(), dir);
+
+    let hour = chrono::Duration::hours(1);
+    let mut previous: Option<Snapshot> = None;
+    for (i, (datetime, path)) in paths.into_iter().enumerate() {
+        info!(
+            "Replaying {} from {:?} ({} of the snapshots)",
+            datetime,
+            path,
+            i + 1
+        );
+        let result = Snapshot::load(&path).and_then(|snap| {
+            let prev = previous
+                .take()
+                .filter(|prev| prev.datetime + hour == snap.datetime);
+            let result = process_snapshot(opt, cfg, costs, &snap, prev.as_ref(), persistent_state);
+            previous = Some(snap);
+            result
+        });
+        match result {
+            Ok(_) => summary.processed += 1,
+            Err(e) => {
+                error!("Could not replay {} from {:?}: {}", datetime, path, e);
+                summary.failed.push((path, e.to_string()));
+            }
+        }
+    }
+    info!(
+        "Replayed {} hours, {} snapshots failed",
+        summary.processed,
+        summary.failed.len()
+    );
+    Ok(summary)
+}
+
+/// Produces the records for the hour of `snap` into `sink`, returning the cost of each project
+/// by project id. `previous` is an earlier snapshot used to bill instances deleted since then.
+/// Stages that fail are added to `failed_stages` without failing the others.
+fn build_records<'s, W: std::io::Write>(
+    cfg: &Config,
+    costs: &CostsFile,
+    snap: &'s Snapshot,
+    previous: Option<&'s Snapshot>,
+    sink: &mut RecordSink<W>,
+    warnings: &mut WarningSummary,
+    failed_stages: &mut BTreeSet<Stage>,
+) -> Result<BTreeMap<String, ProjectBreakdown<'s>>, failure::Error> {
+    // Also the `create_time` of every record, so that records built again from a saved snapshot
+    // come out the same.
+    let this_run_datetime = snap.datetime;
+
+    let cost_lookup = CostLookup::new(cfg, costs, &snap.domains, &snap.projects)
+        .ok_or(format_err!("Could not construct costs lookup."))?;
+
+    let mut object_bucket_sizes = BTreeMap::new();
+    if let Some(stats) = &snap.object_bucket_stats {
+        for s in stats {
+            if let Some(size) = s.billable_size(&cfg.object_excluded_usage) {
+                object_bucket_sizes.insert(s.id.clone(), (s, size));
+            }
+        }
+    }
+    debug!("{:?}", object_bucket_sizes);
+
+    let duration = chrono::Duration::hours(1);
+    let (start_time, end_time) = billing_period(this_run_datetime, duration)?;
+
+    // Operator test project - "SNIC 2018/10-1"
+    let _op_servers = snap
+        .servers
+        .iter()
+        .filter(|srv| srv.tenant_id == "7d4b838241d9486e972bf1b371cc8718");
+
+    let mut used_os_volume_discount: BTreeMap<String, u64> = BTreeMap::new();
+
+    let mut breakdowns: BTreeMap<String, ProjectBreakdown> = BTreeMap::new();
+    let compute_min_age = chrono::Duration::seconds(cfg.compute_min_age);
+
+    // When billing on quota, the quota records replace those of individual servers and volumes.
+    let (billed_servers, billed_volumes, previous) = match cfg.billing_mode {
+        BillingMode::Usage => (&snap.servers[..], &snap.volumes[..], previous),
+        BillingMode::Quota => {
+            info!("Processing project quotas");
+            let (computes, storages) =
+                quota_records(cfg, snap, &cost_lookup, start_time, duration, warnings);
+            for cr in computes {
+                sink.compute(cr)?;
+            }
+            for sr in storages {
+                sink.storage(StorageKind::Volumes, sr)?;
+            }
+            (&[][..], &[][..], None)
+        }
+    };
+
+    for (server, volume_id) in unlisted_attached_volumes(billed_servers, billed_volumes) {
+        warn!(
+            "Volume {} attached to server instance {} is missing from the volume listing and is not billed",
+            volume_id, server.id
+        );
+        warnings.add("attached volumes missing from volume listing", volume_id);
+    }
+
+    let attached_volumes: BTreeMap<&str, &str> = billed_servers
+        .iter()
+        .flat_map(|srv| {
+            srv.attached_volumes
+                .iter()
+                .map(move |vol| (vol.id.as_str(), srv.id.as_str()))
+        })
+        .collect();
+
+    run_stage(Stage::Servers, failed_stages, || {
+        'server_loop: for server in billed_servers {
+            if server.zone.is_none() {
+                warn!("Skipping server instance {} due to no zone", server.id);
+                warnings.add("servers skipped: no zone", server.id.as_str());
+                continue 'server_loop;
+            }
+
+            if server.zone.as_ref().unwrap().is_empty() {
+                warn!("Skipping server instance {} due to empty zone", server.id);
+                warnings.add("servers skipped: empty zone", server.id.as_str());
+                continue 'server_loop;
+            }
+
+            if cfg.zone_excluded(server.zone.as_ref().unwrap()) {
+                debug!(
+                    "Skipping server instance {} in excluded zone {}",
+                    server.id,
+                    server.zone.as_ref().unwrap()
+                );
+                continue 'server_loop;
+            }
+
+            if within_grace_period(server.created, snap.datetime, compute_min_age) {
+                debug!(
+                    "Skipping server instance {} created at {:?}",
+                    server.id, server.created
+                );
+                continue 'server_loop;
+            }
+
+            if snap.projects.get(&server.tenant_id).is_none() {
+                warn!(
+                    "Server instance {} belongs to unknown project {}",
+                    server.id, server.tenant_id
+                );
+                warnings.add("servers with unknown project", server.tenant_id.as_str());
+            }
+
+            let user = snap.users.get(&server.user_id);
+            let flavor = snap.flavors.get(&server.flavor.id);
+            let mut resolved = cost_lookup.resolve_project(&server.tenant_id);
+            match cfg.server_tag_rule(server) {
+                Some(TagRule::NonCharge) => {
+                    debug!("Not billing server instance {} tagged noncharge", server.id);
+                    continue 'server_loop;
+                }
+                Some(TagRule::Resource(resource)) => {
+                    resolved = resolved.and_then(|(name, _)| {
+                        Some((name, cost_lookup.resource_costs(resource.clone())?))
+                    });
+                }
+                None => {}
+            }
+
+            // debug!(
+            //     "user: {:?}, project: {:?}, flavour: {:?}",
+            //     user, project, flavor
+            // );
+            // debug!("{:?}", server);
+
+            if let (Some(user), Some(flavor), Some((project_name, proj_costs))) =
+                (user, flavor, resolved)
+            {
+                let gpus = cfg.gpu_count(flavor);
+                let cost = proj_costs.compute_rate(flavor, gpus);
+
+                let billing_category = match cfg.billing_category(&server.status) {
+                    Some(category) => category,
+                    None => {
+                        warnings.add(
+                            "servers with unknown status",
+                            format!("{} ({})", server.id, server.status),
+                        );
+                        cfg.unknown_status_category
+                    }
+                };
+                if billing_category == BillingCategory::Unbilled {
+                    debug!(
+                        "Not billing server instance {} with status {}",
+                        server.id, server.status
+                    );
+                    continue 'server_loop;
+                }
+
+                if let Some((volume_id, gigs)) = root_volume_discount(cfg, server, flavor) {
+                    used_os_volume_discount.insert(volume_id, gigs);
+                }
+
+                let create_time = this_run_datetime;
+
+                if let Some(cost) = cost {
+                    if !cost.is_zero() {
+                        let allocated_disk = flavor.allocated_disk();
+                        let allocated_cpu: Decimal = flavor.vcpus.into();
+                        let allocated_memory = cfg.memory_unit.convert(Megabytes(flavor.ram));
+
+                        use records::v1::{CloudComputeRecord, CloudRecordCommon};
+
+                        let cr = CloudComputeRecord {
+                            common: CloudRecordCommon {
+                                create_time,
+                                site: cfg.site.clone(),
+                                project: project_name,
+                                user: user.name,
+                                instance_id: server.id.clone(),
+                                start_time,
+                                end_time,
+                                duration,
+                                region: cfg.region.clone(),
+                                resource: proj_costs.resource.clone(),
+                                zone: server.zone.clone().unwrap(),
+                                cost,
+                                allocated_disk,
+                                extensions: gpu_extensions(gpus),
+                            },
+                            flavour: flavor.name.clone(),
+                            allocated_cpu,
+                            allocated_memory,
+                            used_cpu: None,
+                            used_memory: None,
+                            used_network_up: None,
+                            used_network_down: None,
+                            iops: None,
+                        };
+                        let project = cr.common.project.clone();
+                        let cost = sink.compute(cr)?;
+                        let breakdown =
+                            ProjectBreakdown::entry(&mut breakdowns, &server.tenant_id, &project);
+                        match billing_category {
+                            BillingCategory::Active => breakdown.active.push((cost, server)),
+                            _ => breakdown.inert.push((cost, server)),
+                        }
+                        continue 'server_loop;
+                    }
+                } else {
+                    warnings.add("flavors without cost", flavor.name.as_str());
+                }
+                let breakdown =
+                    ProjectBreakdown::entry(&mut breakdowns, &server.tenant_id, &project_name);
+                match billing_category {
+                    BillingCategory::Active => breakdown.active.push((None, server)),
+                    _ => breakdown.inert.push((None, server)),
+                }
+            } else if flavor.is_none() {
+                warnings.add("servers skipped: unknown flavor", server.id.as_str());
+            } else {
+                warnings.add(
+                    "servers skipped: unknown user, project or costs",
+                    server.id.as_str(),
+                );
+            }
+        }
+
+        if let Some(prev) = previous {
+            info!("Processing servers deleted since {}", prev.datetime);
+            let billed_until = prev.datetime + duration;
+            for server in vanished_servers(cfg, &prev.servers, &snap.servers) {
+                let zone = server
+                    .zone
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_ZONE.to_owned());
+                if cfg.zone_excluded(&zone) {
+                    debug!(
+                        "Skipping deleted server instance {} in excluded zone {}",
+                        server.id, zone
+                    );
+                    continue;
+                }
+                let terminated_at = snap
+                    .deleted_servers
+                    .iter()
+                    .chain(snap.servers.iter())
+                    .find(|srv| srv.id == server.id)
+                    .and_then(|srv| srv.terminated_at);
+                let terminated_at = match terminated_at {
+                    Some(t) => t,
+                    None => {
+                        warnings.add(
+                            "deleted servers skipped: unknown termination time",
+                            server.id.as_str(),
+                        );
+                        continue;
+                    }
+                };
+                let (present_until, fraction) =
+                    match present_fraction(billed_until, duration, terminated_at) {
+                        Some(p) => p,
+                        None => continue,
+                    };
+                let (present_until, fraction) = match apply_min_billable_duration(
+                    cfg,
+                    billed_until,
+                    duration,
+                    (present_until, fraction),
+                ) {
+                    Some(p) => p,
+                    None => {
+                        debug!(
+                            "Skipping deleted server instance {} present for less than {}s",
+                            server.id, cfg.min_billable_duration
+                        );
+                        continue;
+                    }
+                };
+
+                let user = prev.users.get(&server.user_id);
+                let flavor = prev.flavors.get(&server.flavor.id);
+                let mut resolved = cost_lookup.resolve_project(&server.tenant_id);
+                match cfg.server_tag_rule(server) {
+                    Some(TagRule::NonCharge) => continue,
+                    Some(TagRule::Resource(resource)) => {
+                        resolved = resolved.and_then(|(name, _)| {
+                            Some((name, cost_lookup.resource_costs(resource.clone())?))
+                        });
+                    }
+                    None => {}
+                }
+                if let (Some(user), Some(flavor), Some((project_name, proj_costs))) =
+                    (user, flavor, resolved)
+                {
+                    let gpus = cfg.gpu_count(flavor);
+                    let cost = match proj_costs.compute_rate(flavor, gpus) {
+                        Some(cost) if !cost.is_zero() => cost * fraction,
+                        _ => continue,
+                    };
+
+                    use records::v1::{CloudComputeRecord, CloudRecordCommon};
+
+                    let cr = CloudComputeRecord {
+                        common: CloudRecordCommon {
+                            create_time: this_run_datetime,
+                            site: cfg.site.clone(),
+                            project: project_name,
+                            user: user.name,
+                            instance_id: server.id.clone(),
+                            start_time: billed_until,
+                            end_time: present_until,
+                            duration: present_until - billed_until,
+                            region: cfg.region.clone(),
+                            resource: proj_costs.resource.clone(),
+                            zone,
+                            cost,
+                            allocated_disk: flavor.allocated_disk(),
+                            extensions: gpu_extensions(gpus),
+                        },
+                        flavour: flavor.name.clone(),
+                        allocated_cpu: flavor.vcpus.into(),
+                        allocated_memory: cfg.memory_unit.convert(Megabytes(flavor.ram)),
+                        used_cpu: None,
+                        used_memory: None,
+                        used_network_up: None,
+                        used_network_down: None,
+                        iops: None,
+                    };
+                    let project = cr.common.project.clone();
+                    let cost = sink.compute(cr)?;
+                    ProjectBreakdown::entry(&mut breakdowns, &server.tenant_id, &project)
+                        .active
+                        .push((cost, server));
+                } else {
+                    warnings.add(
+                        "deleted servers skipped: unknown user, project, flavor or costs",
+                        server.id.as_str(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    run_stage(Stage::Volumes, failed_stages, || {
+        for volume in billed_volumes {
+            use records::v1::{CloudRecordCommon, CloudStorageRecord};
+            let mut process_volume = || -> Option<CloudStorageRecord> {
+                if cfg.zone_excluded(&volume.availability_zone) {
+                    debug!(
+                        "Skipping volume {} in excluded zone {}",
+                        volume.id, volume.availability_zone
+                    );
+                    return None;
+                }
+                if cfg
+                    .unbilled_volume_statuses
+                    .iter()
+                    .any(|s| s.eq_ignore_ascii_case(&volume.status))
+                {
+                    debug!(
+                        "Skipping volume {} with status {}",
+                        volume.id, volume.status
+                    );
+                    return None;
+                }
+                let owner = volume.owner();
+                if owner.is_none() {
+                    warn!("Skipping volume {} with no owning project", volume.id);
+                    warnings.add("volumes skipped: no owning project", volume.id.as_str());
+                }
+                let owner = owner?;
+                let proj_costs = cost_lookup.project_costs_by_id(owner);
+                if proj_costs.is_none() {
+                    warnings.add("volumes skipped: no costs for project", volume.id.as_str());
+                }
+                let proj_costs = proj_costs?;
+                let attached_to = attached_volumes.get(volume.id.as_str());
+                if let Some(server_id) = attached_to {
+                    trace!("Volume {} is attached to {}", volume.id, server_id);
+                }
+                let gig_rate = proj_costs.volume_rate(attached_to.is_some());
+                let actual_gigs = volume.size;
+                let discount_gigs =
+                    discounted_volume_gigs(&mut used_os_volume_discount, &volume.id, actual_gigs);
+                let cost = gig_rate.map(|r| gigabyte_cost(Gigabytes(discount_gigs).into(), r));
+                let user = snap.users.get(&volume.user_id)?;
+                let project = snap.projects.get(owner)?;
+
+                let create_time = this_run_datetime;
+                let allocated_disk = Gigabytes(actual_gigs).into();
+
+                let cost = cost?;
+                if !cost.is_zero() {
+                    let sr = CloudStorageRecord {
+                        common: CloudRecordCommon {
+                            create_time,
+                            site: cfg.site.clone(),
                             project: project.name,
-                            user: user_name.to_owned(),
-                            instance_id: image.id.clone(),
+                            user: user.name,
+                            instance_id: volume.id.clone(),
                             start_time,
                             end_time,
                             duration,
                             region: cfg.region.clone(),
                             resource: proj_costs.resource.clone(),
-                            zone: DEFAULT_ZONE.to_owned(),
+                            zone: volume.availability_zone.clone(),
                             cost,
                             allocated_disk,
+                            extensions: Vec::new(),
                         },
                         file_count: 0,
-                        storage_type: "Block".to_owned(),
+                        storage_type: cfg
+                            .storage_type(StorageKind::Volumes, volume.volume_type.as_deref()),
                     };
-                    return Some(sr);
+                    Some(sr)
+                } else {
+                    None
+                }
+            };
+            if let Some(sr) = process_volume() {
+                let project = sr.common.project.clone();
+                let cost = sink.storage(StorageKind::Volumes, sr)?;
+                let project_id = volume.owner().unwrap_or_default();
+                ProjectBreakdown::entry(&mut breakdowns, project_id, &project)
+                    .volumes
+                    .push((cost, volume));
+            }
+        }
+
+        Ok(())
+    });
+
+    run_stage(Stage::Images, failed_stages, || {
+        let mut ownerless = 0;
+        for image in &snap.images {
+            use records::v1::{CloudRecordCommon, CloudStorageRecord};
+            let owner = match (&image.owner, &cfg.ownerless_image_project) {
+                (Some(owner), _) => owner.as_str(),
+                (None, catch_all) => {
+                    ownerless += 1;
+                    match catch_all {
+                        Some(catch_all) => catch_all.as_str(),
+                        None => {
+                            debug!("Skipping image {} with no owner", image.id);
+                            continue;
+                        }
+                    }
+                }
+            };
+            let process_image = || -> Option<CloudStorageRecord> {
+                let bytes = image.size?;
+                let proj_costs = cost_lookup.project_costs_by_id(owner)?;
+                let gig_rate = proj_costs.get(CostKind::BlockStorage);
+                let cost = gig_rate.map(|r| gigabyte_cost(Bytes(bytes), r));
+                let project = snap.projects.get(owner)?;
+
+                // Not all images have an user name associated with them, only an owning project.
+                let user_name: &str = image
+                    .owner_user_name
+                    .as_ref()
+                    .and_then(|user_name| {
+                        if snap.users.has_name_in_domain(user_name, &project.domain_id) {
+                            Some(user_name.as_ref())
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or(DEFAULT_USER);
+
+                let create_time = this_run_datetime;
+                let allocated_disk = Bytes(bytes);
+
+                if let Some(cost) = cost {
+                    if !cost.is_zero() {
+                        let sr = CloudStorageRecord {
+                            common: CloudRecordCommon {
+                                create_time,
+                                site: cfg.site.clone(),
+                                project: project.name,
+                                user: user_name.to_owned(),
+                                instance_id: image.id.clone(),
+                                start_time,
+                                end_time,
+                                duration,
+                                region: cfg.region.clone(),
+                                resource: proj_costs.resource.clone(),
+                                zone: DEFAULT_ZONE.to_owned(),
+                                cost,
+                                allocated_disk,
+                                extensions: Vec::new(),
+                            },
+                            file_count: 0,
+                            storage_type: cfg.storage_type(StorageKind::Images, None),
+                        };
+                        return Some(sr);
+                    }
+                }
+                None
+            };
+            if let Some(sr) = process_image() {
+                let project = sr.common.project.clone();
+                let cost = sink.storage(StorageKind::Images, sr)?;
+                ProjectBreakdown::entry(&mut breakdowns, owner, &project)
+                    .images
+                    .push((cost, image));
+            }
+        }
+        if ownerless > 0 {
+            match &cfg.ownerless_image_project {
+                Some(catch_all) => warn!(
+                    "{} images without owner billed to project {}",
+                    ownerless, catch_all
+                ),
+                None => warn!("{} images without owner not billed", ownerless),
+            }
+        }
+
+        Ok(())
+    });
+
+    run_stage(Stage::Objects, failed_stages, || {
+        for (stat, size) in object_bucket_sizes.values() {
+            use records::v1::{CloudRecordCommon, CloudStorageRecord};
+            // Buckets whose owner is not a project are billed to the catch-all project, if any.
+            let owner = match (snap.projects.get(&stat.owner), &cfg.unowned_bucket_project) {
+                (Some(_), _) => stat.owner.as_str(),
+                (None, Some(catch_all)) => catch_all.as_str(),
+                (None, None) => {
+                    warnings.add("object buckets skipped: unknown owner", stat.id.as_str());
+                    continue;
+                }
+            };
+            let mut process_object_bucket = || -> Option<CloudStorageRecord> {
+                let project = snap.projects.get(owner);
+                if project.is_none() {
+                    warnings.add(
+                        "object buckets skipped: unknown catch-all project",
+                        stat.id.as_str(),
+                    );
+                }
+                let project = project?;
+                let proj_costs = cost_lookup.project_costs_by_id(owner)?;
+                let gig_rate = proj_costs.get(CostKind::ObjectStorage)?;
+                let mut cost = gigabyte_cost(*size, gig_rate);
+                let mut file_count = 0;
+                if let Some(object_rate) = proj_costs.get(CostKind::ObjectCount) {
+                    file_count = stat.billable_objects(&cfg.object_excluded_usage);
+                    cost += Decimal::from(file_count) * object_rate;
+                }
+                if cost.is_zero() {
+                    return None;
+                }
+                let create_time = this_run_datetime;
+                let (start_time, fraction) = if cfg.object_partial_hours {
+                    stat.modified_at()
+                        .and_then(|mtime| created_fraction(start_time, duration, mtime))
+                        .unwrap_or((start_time, Decimal::ONE))
+                } else {
+                    (start_time, Decimal::ONE)
+                };
+
+                let sr = CloudStorageRecord {
+                    common: CloudRecordCommon {
+                        create_time,
+                        site: cfg.site.clone(),
+                        project: project.name,
+                        user: DEFAULT_USER.to_owned(),
+                        instance_id: stat.id.clone(),
+                        start_time,
+                        end_time,
+                        duration: end_time - start_time,
+                        region: cfg.region.clone(),
+                        resource: proj_costs.resource.clone(),
+                        zone: DEFAULT_ZONE.to_owned(),
+                        cost: (cost * fraction).round_dp(COST_DECIMAL_PLACES),
+                        allocated_disk: *size,
+                        extensions: Vec::new(),
+                    },
+                    file_count,
+                    storage_type: cfg.storage_type(StorageKind::Objects, None),
+                };
+                Some(sr)
+            };
+            if let Some(sr) = process_object_bucket() {
+                let project = sr.common.project.clone();
+                let cost = sink.storage(StorageKind::Objects, sr)?;
+                ProjectBreakdown::entry(&mut breakdowns, owner, &project)
+                    .objects
+                    .push((cost, *stat));
+            }
+        }
+
+        Ok(())
+    });
+
+    debug!("total images: {}", snap.images.len());
+    debug!("total volumes: {}", snap.volumes.len());
+    debug!("used OS volumes: {}", used_os_volume_discount.len());
+
+    check_breakdowns(&breakdowns, warnings);
+    Ok(breakdowns)
+}
+
+/// Produces and writes the records for the hour of `snap`, advancing the persistent state unless
+/// regenerating. `previous` is an earlier snapshot used to bill instances deleted since then.
+fn process_snapshot(
+    opt: &Opt,
+    cfg: &Config,
+    costs: &CostsFile,
+    snap: &Snapshot,
+    previous: Option<&Snapshot>,
+    persistent_state: &mut PersistentStateFile,
+) -> Result<RunSummary, failure::Error> {
+    process_regions(opt, costs, &[(cfg, snap)], previous, persistent_state)
+}
+
+/// Like `process_snapshot`, but writes the records of the snapshots of several regions, each
+/// with its configuration, to the same files. The first region's configuration decides where
+/// and how the records are written, and `previous` belongs to it.
+fn process_regions(
+    opt: &Opt,
+    costs: &CostsFile,
+    regions: &[(&Config, &Snapshot)],
+    previous: Option<&Snapshot>,
+    persistent_state: &mut PersistentStateFile,
+) -> Result<RunSummary, failure::Error> {
+    let (cfg, snap) = regions[0];
+    let this_run_datetime = snap.datetime;
+    let region_names: Vec<&str> = regions.iter().map(|(cfg, _)| cfg.region.as_str()).collect();
+    let region_name = region_names.join("+");
+
+    let mut xml_filename = cfg
+        .records_layout
+        .path_for(
+            &PathBuf::from(&cfg.datadir).join("records"),
+            this_run_datetime,
+        )
+        .with_extension(opt.format.extension());
+    let restricted;
+    let (snap, previous) = if opt.projects.is_empty() {
+        (snap, previous)
+    } else {
+        let project_ids = snap.project_ids(&opt.projects)?;
+        info!("Only billing projects {:?}", project_ids);
+        let mut snap = snap.clone();
+        snap.restrict_to_projects(cfg, &project_ids);
+        let previous = previous.map(|prev| {
+            let mut prev = prev.clone();
+            prev.restrict_to_projects(cfg, &project_ids);
+            prev
+        });
+        restricted = (snap, previous);
+        // A separate file, so that the complete records of the hour are left as they are.
+        let ids: Vec<&str> = project_ids.iter().map(String::as_str).collect();
+        xml_filename = xml_filename.with_extension(format!(
+            "partial-{}.{}",
+            ids.join("+"),
+            opt.format.extension()
+        ));
+        (&restricted.0, restricted.1.as_ref())
+    };
+    let xml_filenames: Vec<PathBuf> = match opt.split_by {
+        None => vec![xml_filename],
+        Some(SplitBy::Kind) => SPLIT_KIND_PARTS
+            .iter()
+            .map(|part| split_path_for(&xml_filename, part))
+            .collect(),
+    };
+    let xml_dir = xml_filenames[0].parent().unwrap();
+    if !opt.dry_run {
+        info!("Writing records to {:?}", &xml_dir);
+        std::fs::create_dir_all(xml_dir)?;
+    }
+    let open_output = |path: &PathBuf| -> Result<Box<dyn std::io::Write>, failure::Error> {
+        Ok(if opt.dry_run {
+            Box::new(std::io::sink())
+        } else {
+            Box::new(std::io::BufWriter::new(File::create(tmp_path_for(path))?))
+        })
+    };
+    let batch_ids: Vec<Option<String>> = xml_filenames
+        .iter()
+        .map(|path| {
+            if cfg.batch_ids {
+                Some(batch_id_for(&region_name, path))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut sink = RecordSink::with_format(
+        cfg,
+        open_output(&xml_filenames[0])?,
+        opt.format,
+        batch_ids[0].as_deref(),
+    )?;
+    if let Some(storage_filename) = xml_filenames.get(1) {
+        sink = sink.with_storage_output(open_output(storage_filename)?, batch_ids[1].as_deref())?;
+    }
+    if regions.len() > 1 {
+        sink = sink.with_unique_record_ids();
+    }
+    let mut warnings = WarningSummary::default();
+    let mut failed_stages = snap.failed_stages.clone();
+    let mut breakdowns = vec![build_records(
+        cfg,
+        costs,
+        snap,
+        previous,
+        &mut sink,
+        &mut warnings,
+        &mut failed_stages,
+    )?];
+    for (region_cfg, region_snap) in &regions[1..] {
+        if region_snap.datetime != this_run_datetime {
+            bail!(
+                "Snapshot of region {} is for {} rather than {}",
+                region_cfg.region,
+                region_snap.datetime,
+                this_run_datetime
+            );
+        }
+        failed_stages.extend(region_snap.failed_stages.iter().cloned());
+        breakdowns.push(build_records(
+            region_cfg,
+            costs,
+            region_snap,
+            None,
+            &mut sink,
+            &mut warnings,
+            &mut failed_stages,
+        )?);
+    }
+
+    let (total_cost, total_allocated_bytes) = sink.totals();
+    let file_costs = if xml_filenames.len() > 1 {
+        vec![total_cost - sink.storage_cost(), sink.storage_cost()]
+    } else {
+        vec![total_cost]
+    };
+    let mut file_counts = Vec::new();
+    for (mut output, count) in sink.finish_outputs()? {
+        output.flush()?;
+        file_counts.push(count);
+    }
+    let record_count: usize = file_counts.iter().sum();
+    info!("Produced {} records", record_count);
+    if record_count == 0 && !opt.dry_run {
+        // The empty document still gets written, so that a missing file always means that the
+        // hour was not processed rather than that there was nothing to bill.
+        info!(
+            "Nothing to bill for {}, writing an empty record file",
+            this_run_datetime
+        );
+    }
+
+    warnings.log();
+
+    if opt.breakdown {
+        for (region, breakdowns) in region_names.iter().zip(&breakdowns) {
+            if regions.len() > 1 {
+                println!("{}:", region);
+            }
+            print_breakdown(breakdowns, cfg.decimal_separator);
+        }
+    }
+
+    let unowned = UnownedBucketReport::new(regions);
+    if unowned.count > 0 {
+        info!(
+            "{} object buckets with {} bytes have no owning project",
+            unowned.count, unowned.total_bytes
+        );
+    }
+
+    if !opt.dry_run {
+        let mut written_files = Vec::new();
+        for (i, xml_filename) in xml_filenames.iter().enumerate() {
+            // The sidecar is in place before the file, so that a published file always has one.
+            let tmp = tmp_path_for(xml_filename);
+            // Reading the file again is only worth it if the checksum is kept somewhere.
+            let sha256 = if cfg.write_checksums || batch_ids[i].is_some() {
+                Some(sha256_hex(&tmp)?)
+            } else {
+                None
+            };
+            if let Some(sha256) = sha256.as_ref().filter(|_| cfg.write_checksums) {
+                let sidecar = write_checksum_sidecar(xml_filename, sha256)?;
+                debug!("Wrote checksum to {:?}", sidecar);
+            }
+
+            let written = replace_if_changed(&tmp, xml_filename)?;
+            if written {
+                written_files.push(xml_filename);
+            } else {
+                info!("{:?} is already up to date", xml_filename);
+            }
+
+            if let Some(batch_id) = &batch_ids[i] {
+                let entry = ManifestEntry {
+                    batch_id: batch_id.clone(),
+                    records: file_counts[i],
+                    total_cost: file_costs[i],
+                    sha256,
+                };
+                record_in_manifest(xml_filename, entry)?;
+            }
+        }
+
+        // A partial hour is neither remembered nor handed on, so that it is processed again.
+        if !failed_stages.is_empty() {
+            warn!(
+                "Not persisting state or running hooks for {} since stages failed",
+                this_run_datetime
+            );
+        } else if opt.regenerate.is_none()
+            && opt.replay_dir.is_none()
+            && opt.projects.is_empty()
+            && !opt.no_state
+        {
+            info!("Persisting state");
+            persistent_state.state.last_timepoint = Some(this_run_datetime);
+            persistent_state.write()?;
+
+            let report_path = PathBuf::from(&cfg.datadir).join("logger-state/unowned-buckets.json");
+            std::fs::write(&report_path, serde_json::to_vec_pretty(&unowned)?)?;
+        }
+
+        if let Some(hook) = cfg
+            .post_write_hook
+            .as_ref()
+            .filter(|_| failed_stages.is_empty())
+        {
+            for xml_filename in written_files {
+                if let Err(e) = hook.run(xml_filename) {
+                    if hook.fatal {
+                        return Err(e);
+                    }
+                    error!("{}", e);
+                }
+            }
+        }
+    }
+
+    if !failed_stages.is_empty() {
+        let names: Vec<&str> = failed_stages.iter().map(|stage| stage.name()).collect();
+        bail!(
+            "Records for {} were written without the failed stages: {}",
+            this_run_datetime,
+            names.join(", ")
+        );
+    }
+    Ok(RunSummary {
+        records: record_count,
+        total_cost,
+        total_allocated_bytes,
+        timepoint: this_run_datetime,
+        region: region_name,
+        duration_ms: 0,
+    })
+}
+
+/// Outcome of a run, printed as a single JSON line to stdout for the calling pipeline.
+#[derive(Debug, Clone, Serialize)]
+struct RunSummary {
+    records: usize,
+    total_cost: Decimal,
+    total_allocated_bytes: u64,
+    timepoint: DateTime<Utc>,
+    region: String,
+    /// Filled in by `main` for the whole run, including fetching the snapshot.
+    duration_ms: u64,
+}
+
+fn main() -> Result<(), failure::Error> {
+    let started = std::time::Instant::now();
+    let opt = Opt::from_args();
+    init_logging(opt.log_format);
+    debug!("ssc-billing-logger {}", BUILD_VERSION);
+
+    if let Some(snap_path) = &opt.pretty_print_snapshot {
+        println!("{}", Snapshot::load(snap_path)?.summary());
+        return Ok(());
+    }
+
+    let cfg = Config::load(&opt.config)?;
+    if opt.dump_config {
+        println!("{}", serde_json::to_string_pretty(&cfg.redacted_json()?)?);
+        return Ok(());
+    }
+    cfg.validate()?;
+    let _ = LOG_REGION.set(cfg.region.clone());
+    let datadir = PathBuf::from(&cfg.datadir);
+
+    if let Some(day) = opt.archive_day {
+        archive_day(&datadir.join("records"), cfg.records_layout, day)?;
+        return Ok(());
+    }
+
+    if let Some(addr) = &opt.serve {
+        return serve(&opt, &cfg, addr);
+    }
+
+    if let Some(mut summary) = run(&opt, &cfg)? {
+        if !opt.no_summary {
+            summary.duration_ms = started.elapsed().as_millis() as u64;
+            println!("{}", serde_json::to_string(&summary)?);
+        }
+    }
+    Ok(())
+}
+
+/// Status of the runs of `--serve`, as served over HTTP.
+#[derive(Debug, Default, Serialize)]
+struct ServiceStatus {
+    runs: u64,
+    failures: u64,
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    last_error_time: Option<DateTime<Utc>>,
+    /// Summary of the last run that billed an hour.
+    last_run: Option<RunSummary>,
+}
+
+impl ServiceStatus {
+    fn record(&mut self, at: DateTime<Utc>, result: Result<Option<RunSummary>, failure::Error>) {
+        self.runs += 1;
+        match result {
+            Ok(summary) => {
+                self.last_success = Some(at);
+                if summary.is_some() {
+                    self.last_run = summary;
+                }
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.last_error = Some(e.to_string());
+                self.last_error_time = Some(at);
+            }
+        }
+    }
+
+    /// The status in the Prometheus text exposition format.
+    fn metrics(&self) -> String {
+        let mut metrics = vec![
+            (
+                "runs_total",
+                "counter",
+                "Billing runs",
+                self.runs.to_string(),
+            ),
+            (
+                "failures_total",
+                "counter",
+                "Billing runs that failed",
+                self.failures.to_string(),
+            ),
+        ];
+        if let Some(at) = self.last_success {
+            metrics.push((
+                "last_success_timestamp_seconds",
+                "gauge",
+                "Time of the last successful run",
+                at.timestamp().to_string(),
+            ));
+        }
+        if let Some(run) = &self.last_run {
+            metrics.push((
+                "last_records",
+                "gauge",
+                "Records written by the last run",
+                run.records.to_string(),
+            ));
+            metrics.push((
+                "last_total_cost",
+                "gauge",
+                "Total cost of the records of the last run",
+                run.total_cost.to_string(),
+            ));
+            metrics.push((
+                "last_timepoint_seconds",
+                "gauge",
+                "Hour billed by the last run",
+                run.timepoint.timestamp().to_string(),
+            ));
+        }
+        let mut text = String::new();
+        for (name, typ, help, value) in metrics {
+            text += &format!(
+                "# HELP ssc_billing_logger_{name} {help}\n\
+                 # TYPE ssc_billing_logger_{name} {typ}\n\
+                 ssc_billing_logger_{name} {value}\n",
+                name = name,
+                typ = typ,
+                help = help,
+                value = value
+            );
+        }
+        text
+    }
+
+    /// Status code, content type and body of the response to a request for `path`.
+    fn http_response(&self, path: &str) -> (&'static str, &'static str, String) {
+        match path {
+            "/" | "/status" => (
+                "200 OK",
+                "application/json",
+                serde_json::to_string(self).unwrap_or_default(),
+            ),
+            "/metrics" => ("200 OK", "text/plain; version=0.0.4", self.metrics()),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+        }
+    }
+}
+
+/// Answers HTTP requests for the status on `listener` until the process exits.
+fn serve_status(
+    listener: std::net::TcpListener,
+    status: std::sync::Arc<std::sync::Mutex<ServiceStatus>>,
+) {
+    use std::io::{BufRead, Read, Write};
+    // Requests are answered one at a time, so a client that stalls must not hold up the others.
+    let timeout = Some(std::time::Duration::from_secs(5));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Could not accept status connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            warn!("Could not set timeouts on status connection: {}", e);
+            continue;
+        }
+        let mut request_line = String::new();
+        let mut reader = std::io::BufReader::new((&stream).take(8192));
+        if reader.read_line(&mut request_line).is_err() {
+            continue;
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+        // A run that panicked while recording leaves the status poisoned but still readable.
+        let (code, content_type, body) = status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .http_response(path);
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code,
+            content_type,
+            body.len(),
+            body
+        );
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            warn!("Could not answer status request: {}", e);
+        }
+    }
+}
+
+/// Runs the logger at the start of every hour, serving the status of the runs on `addr`. A failed
+/// run is logged and recorded in the status, and the next hour is still run.
+fn serve(opt: &Opt, cfg: &Config, addr: &std::net::SocketAddr) -> Result<(), failure::Error> {
+    if opt.load_snapshot.is_some()
+        || opt.regenerate.is_some()
+        || opt.replay_dir.is_some()
+        || opt.explain_costs
+        || !opt.projects.is_empty()
+    {
+        bail!(
+            "--serve bills the current hour and cannot be combined with --load-snapshot, \
+             --regenerate, --replay-dir, --explain-costs or --project"
+        );
+    }
+    let listener = std::net::TcpListener::bind(addr)?;
+    info!("Serving status on http://{}/", addr);
+    let status = std::sync::Arc::new(std::sync::Mutex::new(ServiceStatus::default()));
+    let served = status.clone();
+    std::thread::spawn(move || serve_status(listener, served));
+
+    loop {
+        let started = std::time::Instant::now();
+        let result = run(opt, cfg).map(|summary| {
+            summary.map(|mut summary| {
+                summary.duration_ms = started.elapsed().as_millis() as u64;
+                summary
+            })
+        });
+        if let Err(e) = &result {
+            error!("Run failed: {}", e);
+        }
+        status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(Utc::now(), result);
+
+        let now = Utc::now();
+        let next_hour = now.date().and_hms(now.hour(), 0, 0) + chrono::Duration::hours(1);
+        let wait = (next_hour - now).to_std().unwrap_or_default();
+        info!("Next run at {}", next_hour);
+        std::thread::sleep(wait);
+    }
+}
+
+/// One run of the logger as configured by the command line, returning the summary of the hour
+/// that was billed, if any.
+fn run(opt: &Opt, cfg: &Config) -> Result<Option<RunSummary>, failure::Error> {
+    let datadir = PathBuf::from(&cfg.datadir);
+    let _lock = if opt.dry_run {
+        None
+    } else {
+        match RunLock::acquire(&datadir, cfg.on_locked)? {
+            Some(lock) => Some(lock),
+            None => return Ok(None),
+        }
+    };
+    let mut persistent_state = if opt.no_state {
+        info!("Not using persistent state");
+        PersistentStateFile::detached()
+    } else {
+        info!("Opening persistent state file in {}", &cfg.datadir);
+        PersistentStateFile::open(&cfg.datadir)?
+    };
+
+    let costs_path = datadir.join("logger-state/costs.json");
+    info!("Reading costs from {:?}", &costs_path);
+    let costs = CostsFile::from_reader(File::open(&costs_path)?)?;
+    cfg.check_regions(&costs)?;
+
+    if opt.regenerate.is_some() && opt.load_snapshot.is_none() {
+        bail!("--regenerate requires a saved snapshot passed with --load-snapshot");
+    }
+    if opt.regenerate.is_some() && opt.since_last.is_some() {
+        bail!("--regenerate cannot be combined with --since-last");
+    }
+    if !opt.projects.is_empty() && opt.load_snapshot.is_none() {
+        bail!("--project requires a saved snapshot passed with --load-snapshot");
+    }
+    if !opt.projects.is_empty() && opt.since_last.is_some() {
+        bail!("--project cannot be combined with --since-last");
+    }
+    if opt.replay_dir.is_some()
+        && (opt.load_snapshot.is_some() || opt.regenerate.is_some() || opt.since_last.is_some())
+    {
+        bail!("--replay-dir cannot be combined with --load-snapshot, --regenerate or --since-last");
+    }
+    if opt.no_state && opt.since_last.is_some() {
+        bail!("--since-last needs the persistent state and cannot be combined with --no-state");
+    }
+    if opt.merge_regions
+        && (opt.load_snapshot.is_some()
+            || opt.since_last.is_some()
+            || opt.replay_dir.is_some()
+            || !opt.projects.is_empty())
+    {
+        bail!(
+            "--merge-regions fetches a snapshot of every region and cannot be combined with \
+             --load-snapshot, --since-last, --replay-dir or --project"
+        );
+    }
+    let region_cfgs = if opt.merge_regions {
+        if cfg.merge_regions.is_empty() {
+            bail!("--merge-regions needs `merge_regions` in the configuration");
+        }
+        cfg.merged_region_configs()?
+    } else {
+        Vec::new()
+    };
+    for region_cfg in &region_cfgs {
+        region_cfg.check_regions(&costs)?;
+    }
+
+    if let Some(dir) = &opt.replay_dir {
+        let summary = replay_dir(opt, cfg, &costs, dir, &mut persistent_state)?;
+        if !summary.failed.is_empty() {
+            let paths: Vec<String> = summary
+                .failed
+                .iter()
+                .map(|(path, _)| path.display().to_string())
+                .collect();
+            bail!("Could not replay {}", paths.join(", "));
+        }
+        return Ok(None);
+    }
+
+    let now = Utc::now();
+    let this_run_datetime = now.date().and_hms(now.hour(), 0, 0);
+    if !opt.force && opt.regenerate.is_none() && !opt.explain_costs {
+        if let Some(last_run) = persistent_state.state.last_timepoint {
+            if last_run == this_run_datetime {
+                return Ok(None);
+            }
+        }
+    }
+
+    if let Some(snapshot_dir) = &opt.since_last {
+        catch_up(
+            opt,
+            cfg,
+            &costs,
+            snapshot_dir,
+            this_run_datetime,
+            &mut persistent_state,
+        )?;
+    }
+
+    let mut timings = Timings::default();
+    let mut snap = if let Some(snap_path) = &opt.load_snapshot {
+        let snap = Snapshot::load(snap_path)?;
+        if let Some(timepoint) = opt.regenerate {
+            if snap.datetime != timepoint {
+                bail!(
+                    "Snapshot is for {} but --regenerate asked for {}",
+                    snap.datetime,
+                    timepoint
+                );
+            }
+        }
+        snap
+    } else {
+        let snap = fetch_snapshot(opt, cfg, this_run_datetime, &mut timings)?;
+
+        if let Some(snap_path) = &opt.save_snapshot {
+            snap.save(snap_path)?;
+        }
+
+        snap
+    };
+    let mut region_snaps = Vec::new();
+    for region_cfg in &region_cfgs {
+        region_snaps.push(fetch_snapshot(
+            opt,
+            region_cfg,
+            this_run_datetime,
+            &mut timings,
+        )?);
+    }
+    let mut previous = match &opt.previous_snapshot {
+        Some(prev_path) => Some(Snapshot::load(prev_path)?),
+        None => None,
+    };
+
+    if let Some(flavors_path) = &opt.flavors_file {
+        let extra: openstack::Flavors = serde_json::from_reader(File::open(flavors_path)?)?;
+        let merged = merge_flavors(&mut snap.flavors, &extra, opt.override_flavors);
+        info!("Merged {} flavors from {:?}", merged, flavors_path);
+        if let Some(prev) = &mut previous {
+            merge_flavors(&mut prev.flavors, &extra, opt.override_flavors);
+        }
+        for region_snap in &mut region_snaps {
+            merge_flavors(&mut region_snap.flavors, &extra, opt.override_flavors);
+        }
+    }
+
+    if opt.explain_costs {
+        let cost_lookup = CostLookup::new(cfg, &costs, &snap.domains, &snap.projects)
+            .ok_or(format_err!("Could not construct costs lookup."))?;
+        cost_lookup.explain();
+        return Ok(None);
+    }
+
+    let mut regions = vec![(cfg, &snap)];
+    regions.extend(region_cfgs.iter().zip(&region_snaps));
+    let summary = process_regions(
+        opt,
+        &costs,
+        &regions,
+        previous.as_ref(),
+        &mut persistent_state,
+    )?;
+
+    timings.log();
+    info!("All done!");
+    Ok(Some(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(id: &str, status: &str) -> openstack::nova::Server {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "user_id": "u",
+            "tenant_id": "t",
+            "flavor": {"id": "f"},
+            "image": "",
+            "status": status,
+            "os-extended-volumes:volumes_attached": [],
+        }))
+        .unwrap()
+    }
+
+    fn flavor() -> openstack::nova::Flavor {
+        serde_json::from_value(serde_json::json!({
+            "id": "f",
+            "name": "ssc.small",
+            "vcpus": 1,
+            "ram": 2048,
+            "disk": 20,
+        }))
+        .unwrap()
+    }
+
+    fn volume_backed_server(status: &str) -> openstack::nova::Server {
+        let mut srv = server("bfv", status);
+        srv.attached_volumes =
+            serde_json::from_value(serde_json::json!([{"id": "root-vol"}])).unwrap();
+        srv
+    }
+
+    #[test]
+    fn root_volume_discount_for_active_boot_from_volume() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let discount = root_volume_discount(&cfg, &volume_backed_server("ACTIVE"), &flavor());
+        assert_eq!(discount, Some(("root-vol".to_owned(), 20)));
+    }
+
+    #[test]
+    fn root_volume_discount_granted_once() {
+        // Flavor root disk of 20 GB against volumes smaller than, equal to and larger than it.
+        for &(size, billable) in &[(10, 0), (20, 0), (50, 30)] {
+            let mut discounts = BTreeMap::new();
+            discounts.insert("root-vol".to_owned(), 20);
+            assert_eq!(
+                discounted_volume_gigs(&mut discounts, "root-vol", size),
+                billable
+            );
+            assert_eq!(discounts["root-vol"], 20 - size.min(20));
+        }
+
+        let mut discounts = BTreeMap::new();
+        discounts.insert("root-vol".to_owned(), 20);
+        assert_eq!(discounted_volume_gigs(&mut discounts, "data-vol", 50), 50);
+        assert_eq!(discounts["root-vol"], 20);
+    }
+
+    #[test]
+    fn no_root_volume_discount_when_shelved() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        for status in &["SHELVED", "SHELVED_OFFLOADED"] {
+            let discount = root_volume_discount(&cfg, &volume_backed_server(status), &flavor());
+            assert_eq!(discount, None);
+        }
+    }
+
+    #[test]
+    fn no_root_volume_discount_for_image_backed() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let mut srv = volume_backed_server("ACTIVE");
+        srv.image = openstack::nova::Image::StringRep("image-id".to_owned());
+        assert_eq!(root_volume_discount(&cfg, &srv, &flavor()), None);
+    }
+
+    #[test]
+    fn vanished_servers_skips_already_deleted() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let previous = vec![
+            server("kept", "ACTIVE"),
+            server("gone", "ACTIVE"),
+            server("was-deleted", "DELETED"),
+            server("now-deleted", "SHUTOFF"),
+        ];
+        let current = vec![server("kept", "ACTIVE"), server("now-deleted", "DELETED")];
+        let ids: Vec<&str> = vanished_servers(&cfg, &previous, &current)
+            .iter()
+            .map(|srv| srv.id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["gone", "now-deleted"]);
+    }
+
+    #[test]
+    fn instance_statuses_normalized_and_categorized() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.billed_as("active"), BillingCategory::Active);
+        assert_eq!(cfg.billed_as("Shutoff"), BillingCategory::Inactive);
+        assert_eq!(cfg.billed_as("ERROR"), BillingCategory::Unbilled);
+        assert_eq!(cfg.billed_as("BUILD"), BillingCategory::Unbilled);
+        assert_eq!(cfg.billed_as("VERIFY_RESIZE"), BillingCategory::Active);
+        assert_eq!(cfg.billing_category("UNKNOWN"), None);
+        assert_eq!(cfg.billed_as("UNKNOWN"), BillingCategory::Active);
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "status_categories": {"error": "active", "RESCUE": "unbilled"},
+                "unknown_status_category": "unbilled",
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(cfg.billed_as("ERROR"), BillingCategory::Active);
+        assert_eq!(cfg.billed_as("rescue"), BillingCategory::Unbilled);
+        assert_eq!(cfg.billed_as("UNKNOWN"), BillingCategory::Unbilled);
+        assert_eq!(cfg.billed_as("active"), BillingCategory::Active);
+    }
+
+    #[test]
+    fn unknown_status_warned_once_per_server() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers[1].status = "UNKNOWN".to_string();
+        let Built { xml, warnings, .. } = build_xml(&cfg, &billing_costs(), &snap, None);
+        assert!(record_costs_by(&xml, "InstanceId").contains_key("s2"));
+        assert_eq!(
+            warnings.categories["servers with unknown status"],
+            ["s2 (UNKNOWN)".to_string()].iter().cloned().collect()
+        );
+    }
+
+    /// A datadir with a `logger-state` directory under the temporary directory, removed again when
+    /// dropped, also when the test fails.
+    struct TestDatadir(PathBuf);
+
+    impl TestDatadir {
+        fn new(name: &str) -> TestDatadir {
+            let dir = std::env::temp_dir().join(format!("sbl-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("logger-state")).unwrap();
+            TestDatadir(dir)
+        }
+
+        /// The base configuration using this datadir, with `overlay` merged onto it.
+        fn config(&self, overlay: serde_json::Value) -> Config {
+            let mut json = base_config_json();
+            merge_json(
+                &mut json,
+                serde_json::json!({ "datadir": self.0.to_str().unwrap() }),
+            );
+            merge_json(&mut json, overlay);
+            serde_json::from_value(json).unwrap()
+        }
+    }
+
+    impl std::ops::Deref for TestDatadir {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDatadir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn base_config_json() -> serde_json::Value {
+        serde_json::json!({
+            "username": "admin",
+            "password": "secret",
+            "domain": "default",
+            "project": "admin",
+            "keystone_url": "http://keystone.example:35357/v3",
+            "site": "HPC2N",
+            "resources": {"snic": "SE-SNIC-SSC", "local": "local.cloud"},
+            "region": "north-1",
+            "datadir": "/var/spool/sgas-cr",
+        })
+    }
+
+    #[test]
+    fn config_override_merges_onto_base() {
+        let mut merged = base_config_json();
+        merge_json(
+            &mut merged,
+            serde_json::json!({
+                "region": "south-1",
+                "resources": {"local": "south.cloud"},
+            }),
+        );
+        let cfg: Config = serde_json::from_value(merged).unwrap();
+        assert_eq!(cfg.region, "south-1");
+        assert_eq!(cfg.site, "HPC2N");
+        assert_eq!(cfg.resources["snic"], "SE-SNIC-SSC");
+        assert_eq!(cfg.resources["local"], "south.cloud");
+    }
+
+    #[test]
+    fn split_domains_fall_back_to_domain() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.user_domain(), "default");
+        assert_eq!(cfg.project_domain(), "default");
+
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "project_domain": "snic" }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(cfg.user_domain(), "default");
+        assert_eq!(cfg.project_domain(), "snic");
+    }
+
+    #[test]
+    fn validate_accepts_complete_config() {
+        let mut json = base_config_json();
+        let datadir = std::env::temp_dir();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "datadir": datadir.to_str().unwrap() }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        cfg.validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_all_problems() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "site": "",
+                "keystone_url": "ftp://keystone.example/v3",
+                "datadir": "/nonexistent/ssc-billing-logger",
+            }),
+        );
+        // merge_json merges objects key by key, so clear the resources explicitly.
+        json["resources"] = serde_json::json!({});
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let message = cfg.validate().unwrap_err().to_string();
+        assert!(message.contains("`site` is empty"), "{}", message);
+        assert!(message.contains("`keystone_url`"), "{}", message);
+        assert!(message.contains("`resources` is empty"), "{}", message);
+        assert!(message.contains("`datadir`"), "{}", message);
+    }
+
+    #[test]
+    fn cost_multiplier_halves_costs() {
+        use std::str::FromStr;
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "cost_multiplier": 0.5,
+                "resource_multipliers": {"local.cloud": 0.5},
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+
+        let mut cr = records::v1::CloudComputeRecord::example();
+        let mut sr = records::v1::CloudStorageRecord::example();
+        let mut local = records::v1::CloudStorageRecord::example();
+        local.common.resource = "local.cloud".to_owned();
+        apply_cost_multipliers(
+            &cfg,
+            vec![&mut cr.common, &mut sr.common, &mut local.common],
+        );
+        assert_eq!(cr.common.cost, Decimal::from_str("0.0625").unwrap());
+        assert_eq!(sr.common.cost, Decimal::from_str("0.0005").unwrap());
+        assert_eq!(local.common.cost, Decimal::from_str("0.00025").unwrap());
+    }
+
+    #[test]
+    fn overhead_added_after_multipliers() {
+        use std::str::FromStr;
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "overhead_percent": 10,
+                "resource_overhead_percent": {"local.cloud": 0},
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json.clone()).unwrap();
+        let mut cr = records::v1::CloudComputeRecord::example();
+        let mut local = records::v1::CloudStorageRecord::example();
+        local.common.resource = "local.cloud".to_owned();
+        apply_cost_multipliers(&cfg, vec![&mut cr.common, &mut local.common]);
+        assert_eq!(cr.common.cost, Decimal::from_str("0.1375").unwrap());
+        assert_eq!(local.common.cost, Decimal::from_str("0.001").unwrap());
+
+        merge_json(&mut json, serde_json::json!({ "cost_multiplier": 0.5 }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let mut cr = records::v1::CloudComputeRecord::example();
+        apply_cost_multipliers(&cfg, vec![&mut cr.common]);
+        assert_eq!(cr.common.cost, Decimal::from_str("0.06875").unwrap());
+
+        // 0.0000000003 * 0.5 * 1.1 is rounded half to even at the last decimal place.
+        let mut sr = records::v1::CloudStorageRecord::example();
+        sr.common.cost = Decimal::from_str("0.0000000003").unwrap();
+        apply_cost_multipliers(&cfg, vec![&mut sr.common]);
+        assert_eq!(sr.common.cost, Decimal::from_str("0.0000000002").unwrap());
+        assert!(sr.common.cost.scale() <= COST_DECIMAL_PLACES);
+    }
+
+    #[test]
+    fn default_cost_multiplier_is_one() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.cost_multiplier_for("SE-SNIC-SSC"), Decimal::from(1));
+    }
+
+    #[test]
+    fn grace_period_skips_new_instances() {
+        let mut snap = billing_snapshot();
+        snap.servers[0].created = Some(snap.datetime - chrono::Duration::minutes(5));
+        snap.servers[1].created = Some(snap.datetime - chrono::Duration::minutes(30));
+        let billed = |min_age: i64| {
+            let mut json = base_config_json();
+            merge_json(&mut json, serde_json::json!({ "compute_min_age": min_age }));
+            let cfg: Config = serde_json::from_value(json).unwrap();
+            let xml = build_xml(&cfg, &billing_costs(), &snap, None).xml;
+            record_costs_by(&xml, "InstanceId")
+                .into_keys()
+                .filter(|id| id.starts_with('s'))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(billed(600), vec!["s2"]);
+        assert_eq!(billed(0), vec!["s1", "s2"]);
+    }
+
+    #[test]
+    fn present_fraction_of_hour() {
+        use chrono::TimeZone;
+        let hour = chrono::Duration::hours(1);
+        let billed_until = Utc.ymd(2019, 2, 13).and_hms(12, 0, 0);
+
+        let (until, fraction) = present_fraction(
+            billed_until,
+            hour,
+            billed_until + chrono::Duration::minutes(15),
+        )
+        .unwrap();
+        assert_eq!(until, billed_until + chrono::Duration::minutes(15));
+        assert_eq!(fraction, Decimal::new(25, 2));
+
+        let (until, fraction) = present_fraction(
+            billed_until,
+            hour,
+            billed_until + chrono::Duration::hours(3),
+        )
+        .unwrap();
+        assert_eq!(until, billed_until + hour);
+        assert_eq!(fraction, Decimal::from(1));
+
+        assert!(present_fraction(billed_until, hour, billed_until).is_none());
+    }
+
+    #[test]
+    fn server_of_deleted_project_is_billed_as_orphan() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "orphan_resource": "SE-SNIC-SSC" }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {"ssc.small": 0.5}}}}"#.as_bytes(),
+        )
+        .unwrap();
+        let domains: openstack::keystone::Domains =
+            serde_json::from_value(serde_json::json!({"domains": [{"id": "d1", "name": "snic"}]}))
+                .unwrap();
+        let projects: openstack::NameMapping = serde_json::from_value(serde_json::json!({
+            "id_to_name": {"p1": {"name": "SNIC 2018/10-1", "domain_id": "d1"}}
+        }))
+        .unwrap();
+        let lookup = CostLookup::new(&cfg, &costs, &domains, &projects).unwrap();
+
+        let (name, known) = lookup.resolve_project("p1").unwrap();
+        assert_eq!(name, "SNIC 2018/10-1");
+        assert_eq!(
+            known.get(CostKind::Flavor("ssc.small")),
+            Some(Decimal::new(5, 1))
+        );
+
+        let (name, orphan) = lookup.resolve_project("deleted-project").unwrap();
+        assert_eq!(name, "deleted-project");
+        assert_eq!(orphan.resource, "SE-SNIC-SSC");
+        assert_eq!(
+            orphan.get(CostKind::Flavor("ssc.small")),
+            Some(Decimal::new(5, 1))
+        );
+    }
+
+    #[test]
+    fn cost_lookup_uses_pluggable_source() {
+        /// Bills every project as its own resource at a flat rate for each kind of cost.
+        struct FlatRate;
+        impl CostSource for FlatRate {
+            fn resource_for_project(&self, project_id: &str, _domain_name: &str) -> Option<String> {
+                Some(format!("res-{}", project_id))
+            }
+            fn rate_for_kind(&self, _resource: &str, kind: CostKind) -> Option<Decimal> {
+                match kind {
+                    CostKind::Flavor(_) => Some(Decimal::new(2, 0)),
+                    _ => None,
                 }
             }
-            None
+            fn rates(&self, resource: &str) -> Option<Vec<(String, Decimal)>> {
+                Some(vec![(resource.to_owned(), Decimal::new(2, 0))])
+            }
+        }
+
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let domains: openstack::keystone::Domains =
+            serde_json::from_value(serde_json::json!({"domains": [{"id": "d1", "name": "other"}]}))
+                .unwrap();
+        let projects: openstack::NameMapping = serde_json::from_value(serde_json::json!({
+            "id_to_name": {"p1": {"name": "SNIC 2018/10-1", "domain_id": "d1"}}
+        }))
+        .unwrap();
+        let lookup = CostLookup::with_source(&cfg, Box::new(FlatRate), &domains, &projects);
+
+        let (name, costs) = lookup.resolve_project("p1").unwrap();
+        assert_eq!(name, "SNIC 2018/10-1");
+        assert_eq!(costs.resource, "res-p1");
+        assert_eq!(costs.get(CostKind::Flavor("any")), Some(Decimal::new(2, 0)));
+        assert_eq!(costs.get(CostKind::BlockStorage), None);
+        assert!(lookup.resolve_project("unknown").is_none());
+    }
+
+    #[test]
+    fn timings_record_each_call() {
+        let mut timings = Timings::default();
+        let value = timings.time("first", || 42);
+        let result: Result<(), failure::Error> = timings.time("second", || bail!("failed"));
+        assert_eq!(value, 42);
+        assert!(result.is_err());
+        let names: Vec<_> = timings.calls.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["first", "second"]);
+        assert!(timings.total() >= timings.calls[0].1);
+    }
+
+    #[test]
+    fn records_layout_paths() {
+        use chrono::TimeZone;
+        let records_dir = std::path::Path::new("/var/spool/sgas-cr/records");
+        let timepoint = Utc.ymd(2019, 2, 13).and_hms(12, 0, 0);
+        assert_eq!(
+            RecordsLayout::Flat.path_for(records_dir, timepoint),
+            PathBuf::from("/var/spool/sgas-cr/records/20190213T1200Z.xml")
+        );
+        assert_eq!(
+            RecordsLayout::Dated.path_for(records_dir, timepoint),
+            PathBuf::from("/var/spool/sgas-cr/records/2019/02/13/20190213T1200Z.xml")
+        );
+    }
+
+    #[test]
+    fn flavors_file_fills_gaps() {
+        let mut small = flavor();
+        small.id = "small".to_owned();
+        let mut large = flavor();
+        large.id = "large".to_owned();
+        large.name = "ssc.large".to_owned();
+
+        let mut flavors = openstack::Flavors::new();
+        flavors.insert("small".to_owned(), small.clone());
+        let mut extra = openstack::Flavors::new();
+        let mut replacement = small;
+        replacement.name = "ssc.replaced".to_owned();
+        extra.insert("small".to_owned(), replacement);
+        extra.insert("large".to_owned(), large);
+
+        assert_eq!(merge_flavors(&mut flavors, &extra, false), 1);
+        assert_eq!(flavors["small"].name, "ssc.small");
+        assert_eq!(flavors["large"].name, "ssc.large");
+
+        assert_eq!(merge_flavors(&mut flavors, &extra, true), 2);
+        assert_eq!(flavors["small"].name, "ssc.replaced");
+    }
+
+    #[test]
+    fn cost_kind_keys() {
+        for kind in CostKind::FIXED.iter() {
+            assert_eq!(CostKind::from_key(kind.key()), *kind);
+        }
+        assert_eq!(CostKind::BlockStorage.key(), "storage.block");
+        assert_eq!(CostKind::ObjectStorage.key(), "storage.object");
+        assert_eq!(
+            CostKind::from_key("ssc.small"),
+            CostKind::Flavor("ssc.small")
+        );
+        assert_eq!(
+            CostKind::from_key("storage.blocks"),
+            CostKind::Flavor("storage.blocks")
+        );
+    }
+
+    #[test]
+    fn checksum_sidecar_matches_contents() {
+        let dir = TestDatadir::new("checksum");
+        let path = dir.join("20190213T1200Z.xml");
+        std::fs::write(&path, "<records/>\n").unwrap();
+
+        let sha256 = sha256_hex(&path).unwrap();
+        let sidecar = write_checksum_sidecar(&path, &sha256).unwrap();
+        assert_eq!(sidecar, dir.join("20190213T1200Z.xml.sha256"));
+        assert_eq!(
+            std::fs::read_to_string(&sidecar).unwrap(),
+            "ecb63e176e1a05156170a3a0c25d9cff1289d94de0aa10a12a48db3044b8a408  20190213T1200Z.xml\n"
+        );
+    }
+
+    #[test]
+    fn quota_billing_records() {
+        use chrono::TimeZone;
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "quota.core": 0.25, "quota.ram": 0.1, "quota.block": "0.01"
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let snap = Snapshot::from_json(serde_json::json!({
+            "version": 3,
+            "datetime": "2019-02-13T12:00:00Z",
+            "servers": [],
+            "flavors": {},
+            "images": [],
+            "volumes": [],
+            "object_bucket_stats": null,
+            "users": {"id_to_name": {}},
+            "projects": {"id_to_name": {
+                "p1": {"name": "SNIC 2018/10-1", "domain_id": "d1"},
+                "p2": {"name": "SNIC 2018/10-2", "domain_id": "d1"}
+            }},
+            "domains": {"domains": [{"id": "d1", "name": "snic"}]},
+            "quotas": {
+                "p1": {"cores": 8, "ram": 16384, "gigabytes": 500},
+                "p2": {"cores": -1, "ram": -1, "gigabytes": -1}
+            }
+        }))
+        .unwrap();
+        let lookup = CostLookup::new(&cfg, &costs, &snap.domains, &snap.projects).unwrap();
+        let mut warnings = WarningSummary::default();
+        let start_time = Utc.ymd(2019, 2, 13).and_hms(11, 0, 0);
+        let (computes, storages) = quota_records(
+            &cfg,
+            &snap,
+            &lookup,
+            start_time,
+            chrono::Duration::hours(1),
+            &mut warnings,
+        );
+
+        assert_eq!(computes.len(), 1);
+        let cr = &computes[0];
+        assert_eq!(cr.common.project, "SNIC 2018/10-1");
+        assert_eq!(cr.allocated_cpu, Decimal::from(8));
+        assert_eq!(cr.allocated_memory, Decimal::from(16384));
+        // 8 cores * 0.25 + 16 GB * 0.1
+        assert_eq!(cr.common.cost, Decimal::new(36, 1));
+
+        assert_eq!(storages.len(), 1);
+        let sr = &storages[0];
+        assert_eq!(sr.common.allocated_disk, Gigabytes(500).into());
+        assert_eq!(sr.common.cost, Decimal::from(5));
+        assert_ne!(cr.record_id(), sr.record_id());
+
+        assert!(warnings.categories["quotas skipped: unlimited"].contains("p2"));
+    }
+
+    #[test]
+    fn project_breakdown_totals() {
+        let active = server("a", "ACTIVE");
+        let stopped = server("b", "SHUTOFF");
+        let mut breakdown = ProjectBreakdown::default();
+        breakdown.active.push((Some(Decimal::new(5, 1)), &active));
+        breakdown.inert.push((Some(Decimal::new(25, 2)), &stopped));
+        breakdown.inert.push((None, &stopped));
+        assert_eq!(breakdown.compute_cost(), Decimal::new(75, 2));
+        assert_eq!(breakdown.volume_cost(), Decimal::from(0));
+        assert_eq!(breakdown.total_cost(), Decimal::new(75, 2));
+    }
+
+    fn billing_snapshot() -> Snapshot {
+        let server = |id: &str, tenant: &str, flavor: &str, status: &str| {
+            serde_json::json!({
+                "id": id,
+                "user_id": "u1",
+                "tenant_id": tenant,
+                "flavor": {"id": flavor},
+                "image": "",
+                "status": status,
+                "OS-EXT-AZ:availability_zone": "nova",
+                "os-extended-volumes:volumes_attached": [],
+            })
         };
-        process_image().map(|sr| v1_storage_records.push(sr));
-    }
-
-    info!("Processing object buckets");
-    for (_, (stat, gigs)) in &object_bucket_sizes {
-        use records::v1::{CloudRecordCommon, CloudStorageRecord};
-        let process_object_bucket = || -> Option<CloudStorageRecord> {
-            let project = snap.projects.get(&stat.owner)?;
-            let proj_costs = cost_lookup.project_costs_by_id(&stat.owner)?;
-            let gig_rate = proj_costs.get("storage.object")?;
-            let cost = gig_rate * gigs;
-            if cost.is_zero() {
-                return None;
-            }
-            let create_time = Utc::now();
-            let gb_to_b: Decimal = 1024u64.pow(3).into();
-            let bytes = gigs * gb_to_b;
-
-            let sr = CloudStorageRecord {
-                common: CloudRecordCommon {
-                    create_time: create_time,
-                    site: cfg.site.clone(),
-                    project: project.name,
-                    user: DEFAULT_USER.to_owned(),
-                    instance_id: stat.id.clone(),
-                    start_time,
-                    end_time,
-                    duration,
-                    region: cfg.region.clone(),
-                    resource: proj_costs.resource.clone(),
-                    zone: DEFAULT_ZONE.to_owned(),
-                    cost,
-                    allocated_disk: bytes.to_u64().unwrap(),
+        // s1 boots from v1, so the first 20 GB of the volume are covered by its flavor.
+        let mut s1 = server("s1", "p1", "f1", "ACTIVE");
+        s1["os-extended-volumes:volumes_attached"] = serde_json::json!([{"id": "v1"}]);
+        Snapshot::from_json(serde_json::json!({
+            "version": 3,
+            "datetime": "2019-02-13T12:00:00Z",
+            "servers": [
+                s1,
+                server("s2", "p1", "f1", "SHUTOFF"),
+                server("s3", "p2", "f2", "ACTIVE"),
+            ],
+            "flavors": {
+                "f1": {"id": "f1", "name": "ssc.small", "vcpus": 1, "ram": 2048, "disk": 20},
+                "f2": {"id": "f2", "name": "ssc.gpu", "vcpus": 8, "ram": 65536, "disk": 80},
+            },
+            "images": [{
+                "id": "i1",
+                "created_at": "2019-01-01T00:00:00Z",
+                "owner": "p2",
+                "size": 1073741824u64,
+                "status": "active",
+                "tags": [],
+                "visibility": "private",
+                "locations": [],
+            }],
+            "volumes": [{
+                "id": "v1",
+                "size": 30,
+                "user_id": "u1",
+                "project_id": "p1",
+                "availability_zone": "nova",
+            }],
+            "object_bucket_stats": null,
+            "users": {"id_to_name": {"u1": {"name": "s1234", "domain_id": "d1"}}},
+            "projects": {"id_to_name": {
+                "p1": {"name": "SNIC 2018/10-1", "domain_id": "d1"},
+                "p2": {"name": "SNIC 2018/10-2", "domain_id": "d1"}
+            }},
+            "domains": {"domains": [{"id": "d1", "name": "snic"}]},
+        }))
+        .unwrap()
+    }
+
+    fn billing_costs() -> CostsFile {
+        CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "ssc.small": 0.5, "storage.block": 0.01
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    /// Sums the costs of the written records by project name.
+    fn record_costs_by_project(xml: &str) -> BTreeMap<String, Decimal> {
+        record_costs_by(xml, "Project")
+    }
+
+    /// What `build_records` wrote for a snapshot, along with what it reported.
+    struct Built<'s> {
+        xml: String,
+        count: usize,
+        total_bytes: u64,
+        breakdowns: BTreeMap<String, ProjectBreakdown<'s>>,
+        warnings: WarningSummary,
+        failed: BTreeSet<Stage>,
+    }
+
+    /// Builds the records of `snap` into `sink`, failing the test if that fails.
+    fn build_into<'s>(
+        cfg: &Config,
+        costs: &CostsFile,
+        snap: &'s Snapshot,
+        previous: Option<&'s Snapshot>,
+        mut sink: RecordSink<'_, Vec<u8>>,
+    ) -> Built<'s> {
+        let mut warnings = WarningSummary::default();
+        let mut failed = BTreeSet::new();
+        let breakdowns = build_records(
+            cfg,
+            costs,
+            snap,
+            previous,
+            &mut sink,
+            &mut warnings,
+            &mut failed,
+        )
+        .unwrap();
+        let total_bytes = sink.totals().1;
+        let (xml, count) = sink.finish().unwrap();
+        Built {
+            xml: String::from_utf8(xml).unwrap(),
+            count,
+            total_bytes,
+            breakdowns,
+            warnings,
+            failed,
+        }
+    }
+
+    /// Builds the records of `snap` as the XML that `cfg` writes by default.
+    fn build_xml<'s>(
+        cfg: &Config,
+        costs: &CostsFile,
+        snap: &'s Snapshot,
+        previous: Option<&'s Snapshot>,
+    ) -> Built<'s> {
+        build_into(
+            cfg,
+            costs,
+            snap,
+            previous,
+            RecordSink::new(cfg, Vec::new()).unwrap(),
+        )
+    }
+
+    /// Sums the costs of the written records by the value of the element `key`.
+    fn record_costs_by(xml: &str, key: &str) -> BTreeMap<String, Decimal> {
+        use std::str::FromStr;
+        let element = |record: &str, name: &str| -> String {
+            let open = format!("<cr:{}>", name);
+            let start = record.find(&open).unwrap() + open.len();
+            let end = start + record[start..].find('<').unwrap();
+            record[start..end].to_owned()
+        };
+        let mut costs = BTreeMap::new();
+        for record in xml.split("<cr:RecordIdentity").skip(1) {
+            let cost = Decimal::from_str(&element(record, "Cost")).unwrap();
+            *costs
+                .entry(element(record, key))
+                .or_insert_with(Decimal::default) += cost;
+        }
+        costs
+    }
+
+    #[test]
+    fn breakdown_matches_emitted_records() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs = billing_costs();
+        let snap = billing_snapshot();
+        let Built {
+            xml,
+            count,
+            breakdowns,
+            warnings,
+            ..
+        } = build_xml(&cfg, &costs, &snap, None);
+        assert_eq!(count, 4);
+
+        let emitted = record_costs_by_project(&xml);
+        let totals: BTreeMap<String, Decimal> = breakdowns
+            .values()
+            .map(|b| (b.project.clone(), b.total_cost()))
+            .collect();
+        assert_eq!(emitted, totals);
+
+        let p1 = &breakdowns["p1"];
+        assert_eq!(p1.active.len(), 1);
+        assert_eq!(p1.inert.len(), 1);
+        assert_eq!(p1.compute_cost(), Decimal::from(1));
+        assert_eq!(p1.volume_cost(), Decimal::new(1, 1));
+        let p2 = &breakdowns["p2"];
+        assert_eq!(p2.active[0].0, None);
+        assert_eq!(p2.image_cost(), Decimal::new(1, 2));
+
+        let unbilled = &warnings.categories["projects with active instances but no compute cost"];
+        assert!(unbilled.contains("SNIC 2018/10-2"));
+        assert!(!unbilled.contains("SNIC 2018/10-1"));
+    }
+
+    #[test]
+    fn attached_volume_missing_from_listing() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs = billing_costs();
+        let mut snap = billing_snapshot();
+        assert!(unlisted_attached_volumes(&snap.servers, &snap.volumes).is_empty());
+
+        let s2 = snap.servers.iter_mut().find(|srv| srv.id == "s2").unwrap();
+        s2.attached_volumes = serde_json::from_value(serde_json::json!([{"id": "v9"}])).unwrap();
+        let unlisted = unlisted_attached_volumes(&snap.servers, &snap.volumes);
+        assert_eq!(unlisted.len(), 1);
+        assert_eq!((unlisted[0].0.id.as_str(), unlisted[0].1), ("s2", "v9"));
+
+        let Built { warnings, .. } = build_xml(&cfg, &costs, &snap, None);
+        let missing = &warnings.categories["attached volumes missing from volume listing"];
+        assert!(missing.contains("v9"));
+        assert_eq!(missing.len(), 1);
+    }
+
+    #[test]
+    fn negative_costs_fail_or_skip() {
+        let mut cr = records::v1::CloudComputeRecord::example();
+        cr.common.cost = Decimal::new(-5, 1);
+
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let mut sink = RecordSink::new(&cfg, Vec::new()).unwrap();
+        let err = sink.compute(cr).unwrap_err().to_string();
+        assert!(err.contains("ssc/HPC2N/cr/"), "{}", err);
+        assert!(err.contains("cost -0.5"), "{}", err);
+
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "negative_values": "skip" }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let mut sink = RecordSink::new(&cfg, Vec::new()).unwrap();
+        let mut sr = records::v1::CloudStorageRecord::example();
+        sr.common.cost = Decimal::new(-1, 2);
+        assert_eq!(sink.storage(StorageKind::Volumes, sr).unwrap(), None);
+        let cr = records::v1::CloudComputeRecord::example();
+        assert_eq!(sink.compute(cr).unwrap(), Some(Decimal::new(125, 3)));
+        assert_eq!(sink.finish().unwrap().1, 1);
+    }
+
+    #[test]
+    fn snapshot_summary() {
+        let summary = billing_snapshot().summary();
+        assert!(summary.contains("version:          4"), "{}", summary);
+        assert!(summary.contains("2019-02-13T12:00:00+00:00"), "{}", summary);
+        assert!(
+            summary.contains("servers:          3 (2 ACTIVE, 1 SHUTOFF)"),
+            "{}",
+            summary
+        );
+        assert!(
+            summary.contains("volumes:          1 (30 GB)"),
+            "{}",
+            summary
+        );
+        assert!(
+            summary.contains("images:           1 (1073741824 bytes)"),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn object_buckets_priced_per_object() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers.clear();
+        snap.volumes.clear();
+        snap.images.clear();
+        snap.object_bucket_stats = Some(
+            serde_json::from_value(serde_json::json!([{
+                "bucket": "data",
+                "pool": "default.rgw.buckets.data",
+                "index_pool": "default.rgw.buckets.index",
+                "id": "b1",
+                "marker": "b1",
+                "owner": "p1",
+                "ver": "0#5",
+                "master_ver": "0#0",
+                "mtime": "2019-02-13 12:00:00.000000Z",
+                "max_marker": "0#",
+                "usage": {
+                    "rgw.main": {"size_kb": 1048576, "size_kb_actual": 1048576, "num_objects": 5}
                 },
-                file_count: 0,
-                storage_type: "Block".to_owned(),
+                "bucket_quota": {"enabled": false, "max_size_kb": -1, "max_objects": -1}
+            }]))
+            .unwrap(),
+        );
+        let records = |costs: &str| {
+            let costs = CostsFile::from_reader(costs.as_bytes()).unwrap();
+            build_xml(&cfg, &costs, &snap, None).xml
+        };
+
+        let xml = records(r#"{"regions": {"north-1": {"SE-SNIC-SSC": {"storage.object": 0.01}}}}"#);
+        assert!(xml.contains("<cr:Cost>0.01</cr:Cost>"), "{}", xml);
+        assert!(xml.contains("<cr:FileCount>0</cr:FileCount>"), "{}", xml);
+
+        let xml = records(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "storage.object": 0.01, "storage.object.per_object": 0.001
+            }}}}"#,
+        );
+        assert!(xml.contains("<cr:Cost>0.015</cr:Cost>"), "{}", xml);
+        assert!(xml.contains("<cr:FileCount>5</cr:FileCount>"), "{}", xml);
+    }
+
+    #[test]
+    fn regions_checked_against_costs_file() {
+        let costs =
+            CostsFile::from_reader(r#"{"regions": {"north-1": {}, "nort-1": {}}}"#.as_bytes())
+                .unwrap();
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        cfg.check_regions(&costs).unwrap();
+
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "region": "south-1" }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let message = cfg.check_regions(&costs).unwrap_err().to_string();
+        assert!(
+            message.contains("\"south-1\" is not in the costs file, which has [nort-1, north-1]"),
+            "{}",
+            message
+        );
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "expected_regions": ["north-1", "south-1"] }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let message = cfg.check_regions(&costs).unwrap_err().to_string();
+        assert!(
+            message.contains("costs file has regions [nort-1] not among `expected_regions`"),
+            "{}",
+            message
+        );
+        assert!(!message.contains("`region`"), "{}", message);
+    }
+
+    /// Reads an API response from the `fixtures` directory.
+    fn fixture<T: serde::de::DeserializeOwned>(name: &str) -> T {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name);
+        serde_json::from_reader(File::open(&path).unwrap()).unwrap()
+    }
+
+    /// Assembles a snapshot from the fixture responses, following the volume pages the way
+    /// `Session::volumes` does.
+    fn fixture_snapshot() -> Snapshot {
+        use chrono::TimeZone;
+        let mut volumes = Vec::new();
+        let mut page: openstack::cinder::Volumes = fixture("cinder/volumes.json");
+        loop {
+            volumes.append(&mut page.volumes);
+            let next = match page.links.iter().find(|lnk| lnk.rel == "next") {
+                Some(next) => next,
+                None => break,
             };
-            Some(sr)
+            let (_, marker) = next
+                .href
+                .query_pairs()
+                .find(|(key, _)| key == "marker")
+                .unwrap();
+            page = fixture(&format!("cinder/volumes.{}.json", marker));
+        }
+        let images: openstack::glance::Images = fixture("glance/images.json");
+        let servers: openstack::nova::Servers = fixture("nova/servers.json");
+        let flavors: openstack::nova::Flavors = fixture("nova/flavors.json");
+        let users: openstack::keystone::Users = fixture("keystone/users.json");
+        let projects: openstack::keystone::Projects = fixture("keystone/projects.json");
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            datetime: Utc.ymd(2019, 2, 13).and_hms(12, 0, 0),
+            servers: servers.servers,
+            flavors: flavors.into(),
+            images: images.images,
+            volumes,
+            object_bucket_stats: Some(fixture("radosgw/bucket_stats.json")),
+            users: users.into(),
+            projects: projects.into(),
+            domains: fixture("keystone/domains.json"),
+            deleted_servers: Vec::new(),
+            quotas: BTreeMap::new(),
+            failed_stages: BTreeSet::new(),
+        }
+    }
+
+    #[test]
+    fn fixture_records() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs: CostsFile = CostsFile::from_reader(
+            File::open(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/costs.json"))
+                .unwrap(),
+        )
+        .unwrap();
+        let snap = fixture_snapshot();
+        assert_eq!(snap.volumes.len(), 3);
+
+        let xml = build_xml(&cfg, &costs, &snap, None).xml;
+
+        let expected: BTreeMap<String, Decimal> = vec![
+            ("srv-web", Decimal::new(5, 1)),
+            // Booted from vol-root, 40 GB of which are covered by the flavor.
+            ("srv-bfv", Decimal::new(2, 0)),
+            ("vol-root", Decimal::new(1, 1)),
+            // Volumes that are not the root of an instance get no discount.
+            ("vol-data", Decimal::new(1, 0)),
+            // 200 GB at the storage.block rate of the other project.
+            ("vol-scratch", Decimal::new(1, 0)),
+            // Stopped instances are still billed, at the costs of their project's resource.
+            ("srv-stopped", Decimal::new(25, 2)),
+            ("img-alpha", Decimal::new(2, 2)),
+            // Only the rgw.main usage is billed, 1 GB at the local.cloud rate.
+            ("bkt-results", Decimal::new(2, 2)),
+            // Not billed: the shelved srv-shelved, the public img-ubuntu owned by the unbilled
+            // admin project and img-queued without a size.
+        ]
+        .into_iter()
+        .map(|(id, cost)| (id.to_owned(), cost))
+        .collect();
+        assert_eq!(record_costs_by(&xml, "InstanceId"), expected);
+    }
+
+    #[test]
+    fn archive_day_packs_checked_files() {
+        use chrono::TimeZone;
+        let dir = TestDatadir::new("archive");
+        let layout = RecordsLayout::Dated;
+        let write = |hour: u32, day: u32, contents: &str| {
+            let path = layout.path_for(&dir, Utc.ymd(2019, 2, day).and_hms(hour, 0, 0));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
         };
-        process_object_bucket().map(|sr| v1_storage_records.push(sr));
+        let valid = "<?xml version=\"1.0\"?><cr:CloudRecords xmlns:cr=\"urn:x\"/>";
+        write(0, 13, valid);
+        write(23, 13, valid);
+        write(12, 14, valid);
+
+        let day = chrono::NaiveDate::from_ymd(2019, 2, 13);
+        let archive = archive_day(&dir, layout, day).unwrap();
+        assert_eq!(archive, dir.join("20190213.tar.gz"));
+        let mut listing = tar::Archive::new(GzDecoder::new(File::open(&archive).unwrap()));
+        let members: Vec<String> = listing
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                "2019/02/13/20190213T0000Z.xml",
+                "2019/02/13/20190213T2300Z.xml"
+            ]
+        );
+
+        write(5, 13, "<cr:CloudRecords>");
+        let message = archive_day(&dir, layout, day).unwrap_err().to_string();
+        assert!(message.contains("20190213T0500Z.xml"), "{}", message);
+        let message = archive_day(&dir, layout, day.succ().succ())
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("No record files"), "{}", message);
     }
 
-    debug!("total images: {}", snap.images.len());
-    debug!("total volumes: {}", snap.volumes.len());
-    debug!("used OS volumes: {}", used_os_volume_discount.len());
+    #[test]
+    fn resource_template_in_records() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "resource_template": "{resource}-{region}",
+                "resource_multipliers": {"SE-SNIC-SSC": 0.5},
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            cfg.render_resource(&cfg.region, "SE-SNIC-SSC"),
+            "SE-SNIC-SSC-north-1"
+        );
 
-    if !opt.dry_run {
-        let xml_dir = PathBuf::from(cfg.datadir).join("records");
-        info!("Writing records to {:?}", &xml_dir);
-        std::fs::create_dir_all(&xml_dir)?;
-        let xml_leaf_name = format!("{}.xml", this_run_datetime.format("%Y%m%dT%H%MZ"));
-        let xml_filename = xml_dir.join(xml_leaf_name);
-        let fh = std::fs::File::create(xml_filename)?;
-        records::v1::write_xml_to(fh, v1_compute_records.iter(), v1_storage_records.iter())?;
+        let xml = build_xml(&cfg, &billing_costs(), &billing_snapshot(), None).xml;
+        assert!(
+            xml.contains("<cr:Resource>SE-SNIC-SSC-north-1</cr:Resource>"),
+            "{}",
+            xml
+        );
+        assert!(!xml.contains("<cr:Resource>SE-SNIC-SSC</cr:Resource>"));
+        // Multipliers still apply by the resource of the costs file.
+        assert!(xml.contains("<cr:Cost>0.25</cr:Cost>"), "{}", xml);
 
-        info!("Persisting state");
-        persistent_state.state.last_timepoint = Some(this_run_datetime);
-        persistent_state.write()?;
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "resource_template": "SE-{site}-{resource}" }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(cfg.render_resource(&cfg.region, "SSC"), "SE-HPC2N-SSC");
     }
 
-    info!("All done!");
-    Ok(())
+    #[test]
+    fn run_lock_excludes_concurrent_runs() {
+        let dir = TestDatadir::new("lock");
+
+        let lock = RunLock::acquire(&*dir, OnLocked::Exit).unwrap();
+        assert!(lock.is_some());
+        let pid = std::fs::read_to_string(dir.join("logger-state/lock")).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+        assert!(RunLock::acquire(&*dir, OnLocked::Exit).unwrap().is_none());
+
+        drop(lock);
+        assert!(RunLock::acquire(&*dir, OnLocked::Exit).unwrap().is_some());
+    }
+
+    #[test]
+    fn zero_records_write_empty_document() {
+        let dir = TestDatadir::new("empty");
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&["ssc-billing-logger", "-c", "unused.conf"]);
+
+        let mut snap = billing_snapshot();
+        snap.servers.clear();
+        snap.volumes.clear();
+        snap.images.clear();
+        let mut state = PersistentStateFile::open(&*dir).unwrap();
+        process_snapshot(&opt, &cfg, &billing_costs(), &snap, None, &mut state).unwrap();
+
+        let xml = std::fs::read_to_string(dir.join("records/20190213T1200Z.xml")).unwrap();
+        assert!(xml.contains("<cr:CloudRecords"), "{}", xml);
+        assert!(!xml.contains("RecordIdentity"), "{}", xml);
+        check_well_formed_xml(&dir.join("records/20190213T1200Z.xml")).unwrap();
+        assert_eq!(
+            PersistentStateFile::open(&*dir)
+                .unwrap()
+                .state
+                .last_timepoint,
+            Some(snap.datetime)
+        );
+        assert!(dir.join("logger-state/unowned-buckets.json").exists());
+    }
+
+    #[test]
+    fn storage_types_mapped() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.storage_type(StorageKind::Volumes, Some("ssd")), "Block");
+        assert_eq!(cfg.storage_type(StorageKind::Images, None), "Image");
+        assert_eq!(cfg.storage_type(StorageKind::Objects, None), "Object");
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "storage_type_map": {"volumes": "block", "volumes.ssd": "ssd"} }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(cfg.storage_type(StorageKind::Volumes, Some("ssd")), "ssd");
+        assert_eq!(cfg.storage_type(StorageKind::Volumes, Some("hdd")), "block");
+        assert_eq!(cfg.storage_type(StorageKind::Volumes, None), "block");
+        assert_eq!(cfg.storage_type(StorageKind::Objects, None), "Object");
+
+        let mut snap = billing_snapshot();
+        snap.volumes[0].volume_type = Some("ssd".to_owned());
+        let xml = build_xml(&cfg, &billing_costs(), &snap, None).xml;
+        assert!(
+            xml.contains("<cr:StorageType>ssd</cr:StorageType>"),
+            "{}",
+            xml
+        );
+    }
+
+    #[test]
+    fn excluded_zones_not_billed() {
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "exclude_zones": ["test"] }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers[0].zone = Some("test".to_owned());
+        snap.volumes[0].availability_zone = "test".to_owned();
+
+        let xml = build_xml(&cfg, &billing_costs(), &snap, None).xml;
+        let billed: Vec<String> = record_costs_by(&xml, "InstanceId").into_keys().collect();
+        assert_eq!(billed, vec!["i1", "s2"]);
+    }
+
+    #[test]
+    fn server_tags_skip_or_rebill_instances() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "server_tags": {
+                "noncharge": "noncharge",
+                "billing:local": {"resource": "local.cloud"},
+            }}),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {
+                "SE-SNIC-SSC": {"ssc.small": 0.5},
+                "local.cloud": {"ssc.small": 0.25}
+            }}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers[0].tags = vec!["billing:local".to_owned(), "noncharge".to_owned()];
+        snap.servers[1].tags = vec!["unrelated".to_owned(), "billing:local".to_owned()];
+
+        let xml = build_xml(&cfg, &costs, &snap, None).xml;
+        let by_instance = record_costs_by(&xml, "InstanceId");
+        assert!(!by_instance.contains_key("s1"), "{}", xml);
+        assert_eq!(by_instance["s2"], Decimal::new(25, 2));
+        assert_eq!(
+            record_costs_by(&xml, "Resource")
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec!["local.cloud"]
+        );
+    }
+
+    #[test]
+    fn degenerate_periods_rejected() {
+        use chrono::TimeZone;
+        let t = Utc.ymd(2019, 3, 31).and_hms(1, 30, 0);
+        let (start, end) = billing_period(t, chrono::Duration::hours(1)).unwrap();
+        assert_eq!(start, Utc.ymd(2019, 3, 31).and_hms(1, 0, 0));
+        assert_eq!(end, Utc.ymd(2019, 3, 31).and_hms(2, 0, 0));
+        for duration in &[chrono::Duration::zero(), chrono::Duration::hours(-1)] {
+            let message = billing_period(t, *duration).unwrap_err().to_string();
+            assert!(message.contains("is not positive"), "{}", message);
+        }
+
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let mut sink = RecordSink::new(&cfg, Vec::new()).unwrap();
+        let mut cr = records::v1::CloudComputeRecord::example();
+        cr.common.end_time = cr.common.start_time;
+        cr.common.duration = chrono::Duration::zero();
+        assert_eq!(sink.compute(cr).unwrap(), None);
+        let mut sr = records::v1::CloudStorageRecord::example();
+        sr.common.start_time = sr.common.end_time + chrono::Duration::hours(1);
+        assert_eq!(sink.storage(StorageKind::Volumes, sr).unwrap(), None);
+        assert_eq!(sink.finish().unwrap().1, 0);
+    }
+
+    #[test]
+    fn flavor_id_rate_preferred_over_name() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "ssc.small": 0.5, "id:f1": 0.75, "ssc.gpu": 2
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers.truncate(1);
+        snap.servers.push(billing_snapshot().servers.remove(2));
+
+        let xml = build_xml(&cfg, &costs, &snap, None).xml;
+        let by_instance = record_costs_by(&xml, "InstanceId");
+        assert_eq!(by_instance["s1"], Decimal::new(75, 2));
+        assert_eq!(by_instance["s3"], Decimal::new(2, 0));
+    }
+
+    #[test]
+    fn build_version_in_records() {
+        assert!(BUILD_VERSION.starts_with(env!("CARGO_PKG_VERSION")));
+        assert!(BUILD_VERSION.contains(env!("SBL_GIT_COMMIT")));
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let sink = RecordSink::new(&cfg, Vec::new()).unwrap();
+        let xml = String::from_utf8(sink.finish().unwrap().0).unwrap();
+        assert!(
+            xml.contains(&format!("<!-- ssc-billing-logger {} -->", BUILD_VERSION)),
+            "{}",
+            xml
+        );
+    }
+
+    #[test]
+    fn ownerless_images_skipped_or_billed_to_catch_all() {
+        let mut snap = billing_snapshot();
+        snap.images[0].owner = None;
+        let image_costs = |json: serde_json::Value| {
+            let cfg: Config = serde_json::from_value(json).unwrap();
+            let xml = build_xml(&cfg, &billing_costs(), &snap, None).xml;
+            record_costs_by(&xml, "InstanceId").get("i1").cloned()
+        };
+
+        assert_eq!(image_costs(base_config_json()), None);
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "ownerless_image_project": "p1" }),
+        );
+        assert_eq!(image_costs(json), Some(Decimal::new(1, 2)));
+    }
+
+    #[test]
+    fn csv_format_writes_csv_file() {
+        let dir = TestDatadir::new("csv");
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--no-state",
+            "--format",
+            "csv",
+        ]);
+        let mut state = PersistentStateFile::detached();
+        process_snapshot(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut state,
+        )
+        .unwrap();
+
+        let csv = std::fs::read_to_string(dir.join("records/20190213T1200Z.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], records::v1::CSV_HEADER.join(","));
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.starts_with("compute,") && l.contains(",s1,")),
+            "{}",
+            csv
+        );
+        assert!(!dir.join("records/20190213T1200Z.xml").exists());
+    }
+
+    #[test]
+    fn attached_and_detached_volume_rates() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "ssc.small": 0.5, "storage.block": 0.01,
+                "storage.block.attached": 0.02, "storage.block.detached": 0.05
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let snap = billing_snapshot();
+        let lookup = CostLookup::new(&cfg, &costs, &snap.domains, &snap.projects).unwrap();
+        let proj_costs = lookup.project_costs_by_id("p1").unwrap();
+        assert_eq!(proj_costs.volume_rate(true), Some(Decimal::new(2, 2)));
+        assert_eq!(proj_costs.volume_rate(false), Some(Decimal::new(5, 2)));
+        let fallback = billing_costs();
+        let lookup = CostLookup::new(&cfg, &fallback, &snap.domains, &snap.projects).unwrap();
+        let proj_costs = lookup.project_costs_by_id("p1").unwrap();
+        assert_eq!(proj_costs.volume_rate(false), Some(Decimal::new(1, 2)));
+
+        let xml = build_xml(&cfg, &costs, &snap, None).xml;
+        // v1 is attached to s1, which covers the first 20 of its 30 GB.
+        let expected = gigabyte_cost(Gigabytes(10).into(), Decimal::new(2, 2));
+        assert_eq!(record_costs_by(&xml, "InstanceId")["v1"], expected);
+    }
+
+    #[test]
+    fn run_summary_of_known_run() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--no-state",
+            "--dry-run",
+        ]);
+        let mut state = PersistentStateFile::detached();
+        let summary = process_snapshot(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut state,
+        )
+        .unwrap();
+        let json = serde_json::to_value(&summary).unwrap();
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            vec![
+                "duration_ms",
+                "records",
+                "region",
+                "timepoint",
+                "total_allocated_bytes",
+                "total_cost"
+            ]
+        );
+        assert_eq!(json["records"], 4);
+        assert_eq!(json["total_cost"], "1.11");
+        assert_eq!(json["total_allocated_bytes"], 76235669504u64);
+        assert_eq!(json["region"], "north-1");
+        assert_eq!(json["timepoint"], "2019-02-13T12:00:00Z");
+    }
+
+    #[test]
+    fn project_option_bills_only_that_project() {
+        let dir = TestDatadir::new("project");
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--no-state",
+            "--project",
+            "SNIC 2018/10-2",
+        ]);
+        let mut state = PersistentStateFile::detached();
+        let summary = process_snapshot(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut state,
+        )
+        .unwrap();
+        assert_eq!(summary.records, 1);
+
+        let xml =
+            std::fs::read_to_string(dir.join("records/20190213T1200Z.partial-p2.xml")).unwrap();
+        let billed: Vec<String> = record_costs_by_project(&xml).into_keys().collect();
+        assert_eq!(billed, vec!["SNIC 2018/10-2"]);
+        assert!(!dir.join("records/20190213T1200Z.xml").exists());
+        assert!(!dir.join("logger-state/unowned-buckets.json").exists());
+
+        let unknown = Opt::from_iter(&["ssc-billing-logger", "-c", "x", "--project", "p9"]);
+        assert!(process_snapshot(
+            &unknown,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut state,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn dumped_config_round_trips_without_secrets() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "cost_multiplier": 0.5,
+                "server_tags": {"noncharge": "noncharge", "local": {"resource": "local"}},
+                "post_write_hook": {"command": ["true", "{path}"]},
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let dumped = cfg.redacted_json().unwrap();
+        assert_eq!(dumped["password"], "<redacted>");
+        assert!(!dumped.to_string().contains("secret"));
+        assert_eq!(dumped["endpoint_interface"], "admin");
+
+        let reloaded: Config = serde_json::from_value(dumped.clone()).unwrap();
+        assert_eq!(reloaded.redacted_json().unwrap(), dumped);
+        assert_eq!(reloaded.cost_multiplier, Decimal::new(5, 1));
+        assert_eq!(reloaded.server_tags["noncharge"], TagRule::NonCharge);
+    }
+
+    #[test]
+    fn gpu_flavors_billed_per_gpu() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "gpu_count_key": "resources:VGPU" }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "ssc.small": 0.5, "ssc.gpu": 2, "compute.per_gpu": 1.5
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut snap = billing_snapshot();
+        snap.servers.remove(1);
+        // s3 of project p2 runs the GPU flavor f2.
+        let gpu_flavor = snap.flavors.get_mut("f2").unwrap();
+        gpu_flavor
+            .extra_specs
+            .insert("resources:VGPU".to_owned(), "2".to_owned());
+
+        let xml = build_xml(&cfg, &costs, &snap, None).xml;
+        let by_instance = record_costs_by(&xml, "InstanceId");
+        assert_eq!(by_instance["s1"], Decimal::new(5, 1));
+        assert_eq!(by_instance["s3"], Decimal::new(5, 0));
+        assert_eq!(xml.matches("<cr:GPUCount>2</cr:GPUCount>").count(), 1);
+
+        let alias: openstack::nova::Flavor = serde_json::from_value(serde_json::json!({
+            "id": "f3", "name": "ssc.pci", "vcpus": 8, "ram": 1024, "disk": 20,
+            "extra_specs": {"pci_passthrough:alias": "a100:4"},
+        }))
+        .unwrap();
+        assert_eq!(alias.extra_spec_count("pci_passthrough:alias"), Some(4));
+        assert_eq!(alias.extra_spec_count("resources:VGPU"), None);
+    }
+
+    #[test]
+    fn replay_dir_processes_each_snapshot() {
+        use chrono::TimeZone;
+        let dir = TestDatadir::new("replay");
+        let snapshots = dir.join("snapshots");
+        std::fs::create_dir_all(&snapshots).unwrap();
+        // Named out of order, the time in the snapshot decides the order.
+        for (name, hour) in [("c.json", 12), ("a.json", 14), ("b.json.gz", 13)].iter() {
+            let mut snap = billing_snapshot();
+            snap.datetime = Utc.ymd(2019, 2, 13).and_hms(*hour, 0, 0);
+            snap.save(snapshots.join(name)).unwrap();
+        }
+        std::fs::write(snapshots.join("broken.json"), "{").unwrap();
+        std::fs::write(snapshots.join("notes.txt"), "not a snapshot").unwrap();
+
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--replay-dir",
+            snapshots.to_str().unwrap(),
+        ]);
+        let mut state = PersistentStateFile::open(&*dir).unwrap();
+        let summary = replay_dir(&opt, &cfg, &billing_costs(), &snapshots, &mut state).unwrap();
+        assert_eq!(summary.processed, 3);
+        assert_eq!(summary.failed.len(), 1);
+        assert!(summary.failed[0].0.ends_with("broken.json"));
+        for hour in ["12", "13", "14"].iter() {
+            let path = dir.join(format!("records/20190213T{}00Z.xml", hour));
+            assert!(path.exists(), "{:?}", path);
+        }
+        assert_eq!(state.state.last_timepoint, None);
+        assert_eq!(
+            PersistentStateFile::open(&*dir)
+                .unwrap()
+                .state
+                .last_timepoint,
+            None
+        );
+        assert!(!dir.join("logger-state/unowned-buckets.json").exists());
+    }
+
+    #[test]
+    fn service_status_served_as_json_and_metrics() {
+        use std::io::{Read, Write};
+        let mut status = ServiceStatus::default();
+        let summary = RunSummary {
+            records: 4,
+            total_cost: Decimal::new(111, 2),
+            total_allocated_bytes: 1024,
+            timepoint: "2019-02-13T12:00:00Z".parse().unwrap(),
+            region: "north-1".to_owned(),
+            duration_ms: 0,
+        };
+        let at: DateTime<Utc> = "2019-02-13T12:00:05Z".parse().unwrap();
+        status.record(at, Ok(Some(summary)));
+        status.record(at, Err(format_err!("Could not authenticate")));
+
+        let (code, _, body) = status.http_response("/status");
+        assert_eq!(code, "200 OK");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["runs"], 2);
+        assert_eq!(json["last_error"], "Could not authenticate");
+        assert_eq!(json["last_success"], "2019-02-13T12:00:05Z");
+        assert_eq!(json["last_run"]["records"], 4);
+
+        let (_, content_type, metrics) = status.http_response("/metrics");
+        assert!(content_type.starts_with("text/plain"));
+        assert!(metrics.contains("\nssc_billing_logger_failures_total 1\n"));
+        assert!(metrics.contains("\nssc_billing_logger_last_total_cost 1.11\n"));
+        assert!(metrics.contains("# TYPE ssc_billing_logger_runs_total counter\n"));
+        assert_eq!(status.http_response("/other").0, "404 Not Found");
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = std::sync::Arc::new(std::sync::Mutex::new(status));
+        let served = status.clone();
+        std::thread::spawn(move || serve_status(listener, served));
+        let get_metrics = || {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get_metrics();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with(&metrics), "{}", response);
+
+        let poisoner = status.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisoning the status");
+        })
+        .join();
+        assert!(status.is_poisoned());
+        let response = get_metrics();
+        assert!(response.ends_with(&metrics), "{}", response);
+    }
+
+    #[test]
+    fn volumes_in_unbilled_states_skipped() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let billed_volumes = |status: &str| {
+            let mut snap = billing_snapshot();
+            snap.volumes[0].status = status.to_owned();
+            let xml = build_xml(&cfg, &billing_costs(), &snap, None).xml;
+            record_costs_by(&xml, "InstanceId").contains_key("v1")
+        };
+        assert!(billed_volumes("in-use"));
+        assert!(billed_volumes("available"));
+        assert!(billed_volumes(""));
+        assert!(!billed_volumes("deleting"));
+        assert!(!billed_volumes("error_deleting"));
+    }
+
+    #[test]
+    fn allocated_disk_rounded_to_whole_gigabytes() {
+        let image_disk = |rounding: &str| {
+            let mut json = base_config_json();
+            merge_json(
+                &mut json,
+                serde_json::json!({ "allocated_disk_rounding": rounding }),
+            );
+            let cfg: Config = serde_json::from_value(json).unwrap();
+            let mut snap = billing_snapshot();
+            snap.images[0].size = Some(1_500_000_000);
+            let Built {
+                xml, total_bytes, ..
+            } = build_xml(&cfg, &billing_costs(), &snap, None);
+            let record = xml
+                .split("<cr:RecordIdentity")
+                .find(|r| r.contains("<cr:InstanceId>i1<"))
+                .unwrap()
+                .to_owned();
+            (record, total_bytes)
+        };
+        let (exact, exact_total) = image_disk("exact");
+        assert!(exact.contains("<cr:AllocatedDisk>1500000000</cr:AllocatedDisk>"));
+        let (rounded, rounded_total) = image_disk("ceil_gigabytes");
+        assert!(rounded.contains("<cr:AllocatedDisk>2147483648</cr:AllocatedDisk>"));
+        assert_eq!(rounded_total - exact_total, 2147483648 - 1500000000);
+    }
+
+    #[test]
+    fn object_bucket_billed_from_mtime_within_period() {
+        let mut snap = billing_snapshot();
+        snap.servers.clear();
+        snap.volumes.clear();
+        snap.images.clear();
+        snap.object_bucket_stats = Some(
+            serde_json::from_value(serde_json::json!([{
+                "bucket": "fresh",
+                "pool": "default.rgw.buckets.data",
+                "index_pool": "default.rgw.buckets.index",
+                "id": "b1",
+                "marker": "b1",
+                "owner": "p1",
+                "ver": "0#1",
+                "master_ver": "0#0",
+                "mtime": "2019-02-13 12:45:00.000000Z",
+                "max_marker": "0#",
+                "usage": {
+                    "rgw.main": {"size_kb": 1048576, "size_kb_actual": 1048576, "num_objects": 1}
+                },
+                "bucket_quota": {"enabled": false, "max_size_kb": -1, "max_objects": -1}
+            }]))
+            .unwrap(),
+        );
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {"storage.object": 0.01}}}}"#.as_bytes(),
+        )
+        .unwrap();
+        let records = |partial: bool| {
+            let mut json = base_config_json();
+            merge_json(
+                &mut json,
+                serde_json::json!({ "object_partial_hours": partial }),
+            );
+            let cfg: Config = serde_json::from_value(json).unwrap();
+            build_xml(&cfg, &costs, &snap, None).xml
+        };
+
+        let xml = records(false);
+        assert!(xml.contains("<cr:Cost>0.01</cr:Cost>"), "{}", xml);
+        assert!(
+            xml.contains("<cr:Duration>PT3600S</cr:Duration>"),
+            "{}",
+            xml
+        );
+
+        let xml = records(true);
+        assert!(xml.contains("<cr:Cost>0.0025</cr:Cost>"), "{}", xml);
+        assert!(xml.contains("<cr:Duration>PT900S</cr:Duration>"), "{}", xml);
+        assert!(xml.contains("<cr:StartTime>2019-02-13T12:45:00"), "{}", xml);
+    }
+
+    #[test]
+    fn batch_id_on_root_matches_manifest() {
+        let dir = TestDatadir::new("batch");
+        let cfg = dir.config(serde_json::json!({
+            "batch_ids": true,
+            "write_checksums": true,
+        }));
+        let opt = Opt::from_iter(&["ssc-billing-logger", "-c", "unused.conf", "--no-state"]);
+        let summary = process_snapshot(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut PersistentStateFile::detached(),
+        )
+        .unwrap();
+
+        let xml = std::fs::read_to_string(dir.join("records/20190213T1200Z.xml")).unwrap();
+        let root = xml.lines().nth(1).unwrap();
+        assert!(
+            root.contains("cr:batchId=\"north-1/20190213T1200Z\""),
+            "{}",
+            root
+        );
+        let manifest: BTreeMap<String, ManifestEntry> = serde_json::from_slice(
+            &std::fs::read(dir.join("records").join(MANIFEST_FILENAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            manifest["20190213T1200Z.xml"],
+            ManifestEntry {
+                batch_id: "north-1/20190213T1200Z".to_owned(),
+                records: summary.records,
+                total_cost: summary.total_cost,
+                sha256: Some(sha256_hex(&dir.join("records/20190213T1200Z.xml")).unwrap()),
+            }
+        );
+        let sidecar =
+            std::fs::read_to_string(dir.join("records/20190213T1200Z.xml.sha256")).unwrap();
+        assert!(sidecar.starts_with(manifest["20190213T1200Z.xml"].sha256.as_ref().unwrap()));
+        assert!(!dir.join("records/20190213T1200Z.xml.sha256.tmp").exists());
+    }
+
+    #[test]
+    fn short_lived_instance_below_min_duration() {
+        // The previous run billed s2 up to an hour after it, and s2 was deleted 10 seconds into
+        // the hour after that, before this snapshot was taken.
+        let prev = billing_snapshot();
+        let mut snap = billing_snapshot();
+        snap.datetime = prev.datetime + chrono::Duration::hours(2);
+        let pos = snap.servers.iter().position(|srv| srv.id == "s2").unwrap();
+        let mut gone = snap.servers.remove(pos);
+        gone.status = "DELETED".to_owned();
+        gone.terminated_at = Some(prev.datetime + chrono::Duration::seconds(3610));
+        snap.deleted_servers.push(gone);
+
+        let billed = |policy: serde_json::Value| {
+            let mut json = base_config_json();
+            merge_json(&mut json, policy);
+            let cfg: Config = serde_json::from_value(json).unwrap();
+            let xml = build_xml(&cfg, &billing_costs(), &snap, Some(&prev)).xml;
+            record_costs_by(&xml, "InstanceId").get("s2").cloned()
+        };
+
+        // 10 seconds of an hour at 0.5.
+        assert_eq!(
+            billed(serde_json::json!({})),
+            Some(
+                (Decimal::new(5, 1) * Decimal::from(10) / Decimal::from(3600))
+                    .round_dp(COST_DECIMAL_PLACES)
+            )
+        );
+        assert_eq!(
+            billed(serde_json::json!({ "min_billable_duration": 60 })),
+            None
+        );
+        assert_eq!(
+            billed(serde_json::json!({
+                "min_billable_duration": 900,
+                "below_min_duration": "bill_minimum",
+            })),
+            Some(Decimal::new(125, 3))
+        );
+    }
+
+    #[test]
+    fn split_by_kind_writes_one_file_per_kind() {
+        assert_eq!(
+            split_path_for(
+                std::path::Path::new("records/20190213T1200Z.partial-p2.xml"),
+                "compute"
+            ),
+            PathBuf::from("records/20190213T1200Z-compute.partial-p2.xml")
+        );
+
+        let dir = TestDatadir::new("split");
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--no-state",
+            "--split-by",
+            "kind",
+        ]);
+        let summary = process_snapshot(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            &mut PersistentStateFile::detached(),
+        )
+        .unwrap();
+
+        assert!(!dir.join("records/20190213T1200Z.xml").exists());
+        let compute_path = dir.join("records/20190213T1200Z-compute.xml");
+        let storage_path = dir.join("records/20190213T1200Z-storage.xml");
+        check_well_formed_xml(&compute_path).unwrap();
+        check_well_formed_xml(&storage_path).unwrap();
+        let compute = std::fs::read_to_string(&compute_path).unwrap();
+        let storage = std::fs::read_to_string(&storage_path).unwrap();
+        assert!(compute.contains("<cr:CloudComputeRecord>"), "{}", compute);
+        assert!(!compute.contains("<cr:CloudStorageRecord>"), "{}", compute);
+        assert!(storage.contains("<cr:CloudStorageRecord>"), "{}", storage);
+        assert!(!storage.contains("<cr:CloudComputeRecord>"), "{}", storage);
+        assert_eq!(
+            compute.matches("<cr:RecordIdentity").count()
+                + storage.matches("<cr:RecordIdentity").count(),
+            summary.records
+        );
+    }
+
+    #[test]
+    fn request_timeout_from_config() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.request_timeout(), None);
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "request_timeout_secs": 0 }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(cfg.request_timeout(), None);
+        let mut json = base_config_json();
+        merge_json(&mut json, serde_json::json!({ "request_timeout_secs": 30 }));
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            cfg.request_timeout(),
+            Some(std::time::Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn bucket_stats_source_from_config() {
+        use radosgw::admin::BucketStatsSource;
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert!(matches!(
+            cfg.bucket_stats_source().unwrap(),
+            BucketStatsSource::Subprocess
+        ));
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "radosgw_admin": {
+                "endpoint": "http://rgw:8080",
+                "access_key": "billing",
+                "secret_key": "rgw-secret",
+            }}),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            cfg.bucket_stats_source().unwrap(),
+            BucketStatsSource::AdminApi(_)
+        ));
+
+        let dumped = cfg.redacted_json().unwrap();
+        assert_eq!(dumped["radosgw_admin"]["secret_key"], "<redacted>");
+        assert_eq!(dumped["radosgw_admin"]["access_key"], "billing");
+        assert!(!dumped.to_string().contains("rgw-secret"));
+    }
+
+    #[test]
+    fn xml_v2_format_writes_v2_layout() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let opt = Opt::from_iter(&[
+            "ssc-billing-logger",
+            "-c",
+            "unused.conf",
+            "--format",
+            "xml-v2",
+        ]);
+        assert_eq!(opt.format, records::v1::RecordFormat::XmlV2);
+        assert_eq!(opt.format.extension(), "xml");
+
+        let Built { xml, count, .. } = build_into(
+            &cfg,
+            &billing_costs(),
+            &billing_snapshot(),
+            None,
+            RecordSink::with_format(&cfg, Vec::new(), opt.format, None).unwrap(),
+        );
+        assert!(count > 0);
+        assert!(xml.contains("cr:version=\"2\""), "{}", xml);
+        let disk = xml.find("<cr:AllocatedDisk>").unwrap();
+        let flavour = xml.find("<cr:Flavour>").unwrap();
+        assert!(disk < flavour, "{}", xml);
+    }
+
+    #[test]
+    fn image_records_have_image_storage_type() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let xml = build_xml(&cfg, &billing_costs(), &billing_snapshot(), None).xml;
+        let image = xml
+            .split("<cr:CloudStorageRecord>")
+            .find(|r| r.contains("<cr:InstanceId>i1</cr:InstanceId>"))
+            .unwrap();
+        assert!(
+            image.contains("<cr:StorageType>Image</cr:StorageType>"),
+            "{}",
+            image
+        );
+        let volume = xml
+            .split("<cr:CloudStorageRecord>")
+            .find(|r| r.contains("<cr:InstanceId>v1</cr:InstanceId>"))
+            .unwrap();
+        assert!(
+            volume.contains("<cr:StorageType>Block</cr:StorageType>"),
+            "{}",
+            volume
+        );
+    }
+
+    #[test]
+    fn records_reproducible_from_snapshot() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let build = || build_xml(&cfg, &billing_costs(), &billing_snapshot(), None).xml;
+        let xml = build();
+        assert_eq!(xml, build());
+        let create_times: BTreeSet<&str> = xml
+            .split("cr:createTime=\"")
+            .skip(1)
+            .map(|s| &s[..s.find('"').unwrap()])
+            .collect();
+        assert_eq!(
+            create_times,
+            std::iter::once("2019-02-13T12:00:00+00:00").collect()
+        );
+    }
+
+    #[test]
+    fn host_rewrite_from_config() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        assert_eq!(cfg.host_rewrite(), openstack::HostRewrite::default());
+        assert_eq!(cfg.host_rewrite().host, "localhost");
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "rewrite_host": "127.0.0.1",
+                "rewrite_ports": { "nova": 18774, "swift": 18080 },
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json.clone()).unwrap();
+        let rewrite = cfg.host_rewrite();
+        assert_eq!(rewrite.host, "127.0.0.1");
+        assert_eq!(rewrite.ports["nova"], 18774);
+        assert_eq!(rewrite.ports["swift"], 18080);
+        assert!(!rewrite.ports.contains_key("cinder"));
+
+        merge_json(
+            &mut json,
+            serde_json::json!({ "rewrite_ports": { "neutron": 9696 } }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let err = cfg.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown service \"neutron\""), "{}", err);
+    }
+
+    /// `billing_snapshot` with every id prefixed by `prefix`, as if taken in another region.
+    fn billing_snapshot_of_region(prefix: &str) -> Snapshot {
+        let mut snap = billing_snapshot();
+        for server in &mut snap.servers {
+            server.id = format!("{}{}", prefix, server.id);
+            for volume in &mut server.attached_volumes {
+                volume.id = format!("{}{}", prefix, volume.id);
+            }
+        }
+        for volume in &mut snap.volumes {
+            volume.id = format!("{}{}", prefix, volume.id);
+        }
+        for image in &mut snap.images {
+            image.id = format!("{}{}", prefix, image.id);
+        }
+        snap
+    }
+
+    #[test]
+    fn merged_region_configs_apply_overrides() {
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "service_region": "North",
+                "merge_regions": { "south-1": { "resources": {"local": "south.cloud"} } },
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let regions = cfg.merged_region_configs().unwrap();
+        assert_eq!(regions.len(), 1);
+        let south = &regions[0];
+        assert_eq!(south.region, "south-1");
+        assert_eq!(south.service_region(), "south-1");
+        assert_eq!(south.resources["local"], "south.cloud");
+        assert_eq!(south.resources["snic"], "SE-SNIC-SSC");
+        assert!(south.merge_regions.is_empty());
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "merge_regions": { "south-1": { "password": "south-secret" } } }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            cfg.merged_region_configs().unwrap()[0].password,
+            "south-secret"
+        );
+        let dumped = cfg.redacted_json().unwrap().to_string();
+        assert!(!dumped.contains("south-secret"), "{}", dumped);
+    }
+
+    #[test]
+    fn merge_regions_writes_one_file_with_unique_record_ids() {
+        let dir = TestDatadir::new("merge");
+        let cfg = dir.config(serde_json::json!({
+            "merge_regions": { "south-1": {} },
+        }));
+        let south_cfg = cfg.merged_region_configs().unwrap().remove(0);
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {
+                "north-1": {"SE-SNIC-SSC": {"ssc.small": 0.5, "storage.block": 0.01}},
+                "south-1": {"SE-SNIC-SSC": {"ssc.small": 0.25, "storage.block": 0.01}}
+            }}"#
+            .as_bytes(),
+        )
+        .unwrap();
+        let opt = Opt::from_iter(&["ssc-billing-logger", "-c", "unused.conf", "--no-state"]);
+        let north = billing_snapshot();
+        let south = billing_snapshot_of_region("south-");
+        let summary = process_regions(
+            &opt,
+            &costs,
+            &[(&cfg, &north), (&south_cfg, &south)],
+            None,
+            &mut PersistentStateFile::detached(),
+        )
+        .unwrap();
+        assert_eq!(summary.region, "north-1+south-1");
+
+        let xml = std::fs::read_to_string(dir.join("records/20190213T1200Z.xml")).unwrap();
+        assert_eq!(xml.matches("<cr:CloudRecords").count(), 1);
+        let record_ids: Vec<&str> = xml
+            .split("cr:recordId=\"")
+            .skip(1)
+            .map(|s| &s[..s.find('"').unwrap()])
+            .collect();
+        assert_eq!(record_ids.len(), summary.records);
+        let unique: BTreeSet<&str> = record_ids.iter().cloned().collect();
+        assert_eq!(unique.len(), record_ids.len(), "{:?}", record_ids);
+        let costs_by_region = record_costs_by(&xml, "Region");
+        assert_eq!(
+            costs_by_region.keys().collect::<Vec<_>>(),
+            vec!["north-1", "south-1"]
+        );
+        assert!(record_ids.iter().any(|id| id.contains("/south-s1/")));
+
+        // The same instances reported by both regions would clash in the combined file, failing
+        // the stages that produce them.
+        let err = process_regions(
+            &opt,
+            &costs,
+            &[(&cfg, &north), (&south_cfg, &north)],
+            None,
+            &mut PersistentStateFile::detached(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("without the failed stages: servers"),
+            "{}",
+            err
+        );
+        let mut sink = RecordSink::new(&cfg, Vec::new())
+            .unwrap()
+            .with_unique_record_ids();
+        let cr = records::v1::CloudComputeRecord::example();
+        sink.compute(cr).unwrap();
+        let err = sink
+            .compute(records::v1::CloudComputeRecord::example())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Duplicate record ID"), "{}", err);
+    }
+
+    #[test]
+    fn failed_stage_keeps_other_records() {
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        // The negative block storage rate fails the volume and image records.
+        let costs = CostsFile::from_reader(
+            r#"{"regions": {"north-1": {"SE-SNIC-SSC": {
+                "ssc.small": 0.5, "storage.block": -0.01
+            }}}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let Built { xml, failed, .. } = build_xml(&cfg, &costs, &billing_snapshot(), None);
+
+        assert_eq!(
+            failed.into_iter().collect::<Vec<_>>(),
+            vec![Stage::Volumes, Stage::Images]
+        );
+        let billed: Vec<String> = record_costs_by(&xml, "InstanceId").into_keys().collect();
+        assert_eq!(billed, vec!["s1", "s2"]);
+    }
+
+    #[test]
+    fn failed_stage_leaves_state_unchanged() {
+        let dir = TestDatadir::new("partial");
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&["ssc-billing-logger", "-c", "unused.conf"]);
+        let earlier = billing_snapshot().datetime - chrono::Duration::hours(1);
+        let mut state = PersistentStateFile::open(&*dir).unwrap();
+        state.state.last_timepoint = Some(earlier);
+        state.write().unwrap();
+
+        let mut snap = billing_snapshot();
+        snap.failed_stages.insert(Stage::Volumes);
+        let err = process_snapshot(&opt, &cfg, &billing_costs(), &snap, None, &mut state)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("failed stages: volumes"), "{}", err);
+        assert!(dir.join("records/20190213T1200Z.xml").exists());
+        assert_eq!(state.state.last_timepoint, Some(earlier));
+        assert_eq!(
+            PersistentStateFile::open(&*dir)
+                .unwrap()
+                .state
+                .last_timepoint,
+            Some(earlier)
+        );
+    }
+
+    #[test]
+    fn catch_up_reads_compressed_snapshots() {
+        let dir = TestDatadir::new("catch-up");
+        std::fs::create_dir_all(dir.join("snapshots")).unwrap();
+        let cfg = dir.config(serde_json::json!({}));
+        let opt = Opt::from_iter(&["ssc-billing-logger", "-c", "unused.conf"]);
+
+        let hour = chrono::Duration::hours(1);
+        let first = billing_snapshot();
+        let mut second = billing_snapshot();
+        second.datetime = first.datetime + hour;
+        first
+            .save(dir.join("snapshots/20190213T1200Z.json"))
+            .unwrap();
+        second
+            .save(dir.join("snapshots/20190213T1300Z.json.gz"))
+            .unwrap();
+        let mut state = PersistentStateFile::open(&*dir).unwrap();
+        state.state.last_timepoint = Some(first.datetime - hour);
+
+        catch_up(
+            &opt,
+            &cfg,
+            &billing_costs(),
+            &dir.join("snapshots"),
+            second.datetime + hour,
+            &mut state,
+        )
+        .unwrap();
+        assert!(dir.join("records/20190213T1200Z.xml").exists());
+        assert!(dir.join("records/20190213T1300Z.xml").exists());
+        assert_eq!(state.state.last_timepoint, Some(second.datetime));
+    }
+
+    #[test]
+    fn deleted_server_of_deleted_project_billed_as_orphan() {
+        let prev = billing_snapshot();
+        let mut snap = billing_snapshot();
+        snap.datetime = prev.datetime + chrono::Duration::hours(1);
+        let pos = snap.servers.iter().position(|srv| srv.id == "s2").unwrap();
+        let mut gone = snap.servers.remove(pos);
+        gone.status = "DELETED".to_owned();
+        gone.terminated_at = Some(snap.datetime + chrono::Duration::minutes(30));
+        snap.deleted_servers.push(gone);
+        let mut json = serde_json::to_value(&snap).unwrap();
+        json["projects"]["id_to_name"]
+            .as_object_mut()
+            .unwrap()
+            .remove("p1");
+        let snap = Snapshot::from_json(json).unwrap();
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({
+                "orphan_resource": "SE-SNIC-SSC",
+                "orphan_project_name": "orphans",
+            }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        let xml = build_xml(&cfg, &billing_costs(), &snap, Some(&prev)).xml;
+
+        let record = xml
+            .split("<cr:RecordIdentity")
+            .find(|record| record.contains("<cr:InstanceId>s2<"))
+            .unwrap();
+        assert!(record.contains("<cr:Project>orphans<"), "{}", record);
+        assert_eq!(
+            record_costs_by(&xml, "InstanceId")["s2"],
+            Decimal::new(25, 2)
+        );
+    }
+
+    #[test]
+    fn failed_fetch_recorded_in_snapshot() {
+        let mut failed = BTreeSet::new();
+        let volumes: Vec<openstack::cinder::Volume> = stage_data(
+            Stage::Volumes,
+            Err(format_err!("invalid JSON")),
+            &mut failed,
+        );
+        assert!(volumes.is_empty());
+        let images = stage_data(Stage::Images, Ok(vec![1]), &mut failed);
+        assert_eq!(images, vec![1]);
+
+        let mut snap = billing_snapshot();
+        snap.failed_stages = failed;
+        let json = serde_json::to_value(&snap).unwrap();
+        assert_eq!(json["failed_stages"], serde_json::json!(["volumes"]));
+        let snap = Snapshot::from_json(json).unwrap();
+        assert!(snap.failed_stages.contains(&Stage::Volumes));
+        let json = serde_json::to_value(billing_snapshot()).unwrap();
+        assert!(json.get("failed_stages").is_none());
+    }
+
+    #[test]
+    fn unowned_buckets_reported_and_billed_to_catch_all() {
+        let mut snap = fixture_snapshot();
+        let mut stray = snap.object_bucket_stats.as_ref().unwrap()[0].clone();
+        stray.id = "bkt-legacy".to_owned();
+        stray.bucket = "legacy".to_owned();
+        stray.owner = "rgw-system".to_owned();
+        snap.object_bucket_stats.as_mut().unwrap().push(stray);
+        let costs = CostsFile::from_reader(
+            File::open(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/costs.json"))
+                .unwrap(),
+        )
+        .unwrap();
+
+        let cfg: Config = serde_json::from_value(base_config_json()).unwrap();
+        let report = UnownedBucketReport::new(&[(&cfg, &snap)]);
+        assert_eq!(report.count, 1);
+        assert_eq!(report.buckets[0].owner, "rgw-system");
+        assert_eq!(report.buckets[0].region, "north-1");
+        assert_eq!(Bytes(report.total_bytes), Bytes::from(Gigabytes(1)));
+        assert_eq!(report.buckets[0].billed_to, None);
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "merge_regions": { "south-1": {} } }),
+        );
+        let south_cfg = serde_json::from_value::<Config>(json)
+            .unwrap()
+            .merged_region_configs()
+            .unwrap()
+            .remove(0);
+        let merged = UnownedBucketReport::new(&[(&cfg, &snap), (&south_cfg, &snap)]);
+        assert_eq!(merged.count, 2);
+        let regions: Vec<&str> = merged.buckets.iter().map(|b| b.region).collect();
+        assert_eq!(regions, vec!["north-1", "south-1"]);
+        let Built { xml, warnings, .. } = build_xml(&cfg, &costs, &snap, None);
+        assert!(!record_costs_by(&xml, "InstanceId").contains_key("bkt-legacy"));
+        assert!(warnings.categories["object buckets skipped: unknown owner"].contains("bkt-legacy"));
+
+        let mut json = base_config_json();
+        merge_json(
+            &mut json,
+            serde_json::json!({ "unowned_bucket_project": "p-alpha" }),
+        );
+        let cfg: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            UnownedBucketReport::new(&[(&cfg, &snap)]).buckets[0].billed_to,
+            Some("p-alpha")
+        );
+        let Built {
+            xml, breakdowns, ..
+        } = build_xml(&cfg, &costs, &snap, None);
+        // Billed at the SE-SNIC-SSC rate of p-alpha rather than that of any owner.
+        assert_eq!(
+            record_costs_by(&xml, "InstanceId")["bkt-legacy"],
+            Decimal::new(1, 2)
+        );
+        assert_eq!(breakdowns["p-alpha"].objects.len(), 1);
+    }
+
+    #[test]
+    fn gzipped_snapshot_round_trip() {
+        let dir = TestDatadir::new("gzip");
+        let snap = fixture_snapshot();
+
+        let plain = dir.join("snap.json");
+        let compressed = dir.join("snap.json.gz");
+        snap.save(&plain).unwrap();
+        snap.save(&compressed).unwrap();
+        let gzip_magic = [0x1f, 0x8b];
+        assert_eq!(std::fs::read(&compressed).unwrap()[..2], gzip_magic);
+        assert!(
+            std::fs::metadata(&compressed).unwrap().len()
+                < std::fs::metadata(&plain).unwrap().len()
+        );
+
+        let loaded = Snapshot::load(&compressed).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&snap).unwrap()
+        );
+
+        std::fs::write(&compressed, b"not gzip").unwrap();
+        let message = Snapshot::load(&compressed).unwrap_err().to_string();
+        assert!(message.contains("Could not decompress"), "{}", message);
+    }
+
+    #[test]
+    fn v3_snapshot_migrated() {
+        let mut v3 = serde_json::to_value(fixture_snapshot()).unwrap();
+        v3["version"] = 3.into();
+        let fields = v3.as_object_mut().unwrap();
+        fields.remove("deleted_servers");
+        fields.remove("quotas");
+
+        let dir = TestDatadir::new("v3");
+        let path = dir.join("snapshot.json");
+        std::fs::write(&path, serde_json::to_vec(&v3).unwrap()).unwrap();
+        let snap = Snapshot::load(&path).unwrap();
+        assert_eq!(snap.version, SNAPSHOT_VERSION);
+        assert!(snap.deleted_servers.is_empty());
+        assert!(snap.quotas.is_empty());
+        assert_eq!(snap.servers.len(), fixture_snapshot().servers.len());
+
+        let mut v2 = v3.clone();
+        v2["version"] = 2.into();
+        let message = Snapshot::from_json(v2).unwrap_err().to_string();
+        assert!(message.contains("predates domains"), "{}", message);
+
+        let mut future = v3;
+        future["version"] = (SNAPSHOT_VERSION + 1).into();
+        let message = Snapshot::from_json(future).unwrap_err().to_string();
+        assert!(message.contains("newer than the supported"), "{}", message);
+    }
 }
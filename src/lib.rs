@@ -2,6 +2,12 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_json;
 
+#[macro_use]
+pub mod strict;
+
 pub mod openstack;
 pub mod radosgw;
-pub mod records;
\ No newline at end of file
+pub mod records;
+#[cfg(test)]
+mod test_http;
+pub mod units;
\ No newline at end of file
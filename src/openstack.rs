@@ -1,29 +1,160 @@
 extern crate failure;
 extern crate serde_json;
 
+use chrono::{DateTime, Utc};
 use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use url::Url;
 
-fn should_write_debug_json() -> bool {
+#[cfg(feature = "async")]
+pub mod nonblocking;
+
+pub(crate) fn should_write_debug_json() -> bool {
     if let Ok(flag) = std::env::var("SBL_DUMP_OS_JSON") {
-        return u8::from_str_radix(&flag, 10) == Ok(1);
+        return flag.parse::<u8>() == Ok(1);
     }
     false
 }
 
+/// User-Agent sent with all OpenStack requests unless overridden.
+pub const DEFAULT_USER_AGENT: &str = concat!("ssc-billing-logger/", env!("CARGO_PKG_VERSION"));
+
+/// Path of the Keystone v3 API below the service root.
+pub const DEFAULT_KEYSTONE_API_PATH: &str = "v3";
+
+/// Catalog interface whose endpoints are used unless configured otherwise.
+pub const DEFAULT_ENDPOINT_INTERFACE: &str = "admin";
+
+/// Nova microversion requested for server listings, 2.26 being the first to include tags.
+const NOVA_SERVERS_MICROVERSION: &str = "2.26";
+
+/// Nova microversion requested for flavor listings, 2.61 being the first to include extra specs.
+const NOVA_FLAVORS_MICROVERSION: &str = "2.61";
+
+/// Seconds before its expiry at which a token is replaced before making a request with it.
+const TOKEN_EXPIRY_MARGIN_SECS: i64 = 60;
+
+/// Resolves the Keystone API root from a URL that points either at the service root or already
+/// at `api_path` below it. The result ends in a slash so that relative paths can be joined.
+pub fn keystone_api_url(keystone_url: &Url, api_path: &str) -> Url {
+    let mut url = keystone_url.clone();
+    let api_segments = api_path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let has_api_path = {
+        let segments = url
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        segments.ends_with(&api_segments)
+    };
+    {
+        let mut segments = url.path_segments_mut().unwrap();
+        segments.pop_if_empty();
+        if !has_api_path {
+            segments.extend(&api_segments);
+        }
+        segments.push(""); // ensure that the URL ends in a slash
+    }
+    url
+}
+
 #[derive(Debug)]
 pub struct Session {
-    auth_token: String,
+    client: reqwest::blocking::Client,
+    auth_token: RefCell<AuthToken>,
+    credentials: Credentials,
     keystone_url: Url,
     nova_url: Url,
     cinder_url: Url,
     glance_url: Url,
     swift_url: Option<Url>,
+    retry: RetryPolicy,
+}
+
+/// How often and after how long failed OpenStack requests are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made at most, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each further one.
+    pub base_delay: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the failed `attempt`, counted from 1.
+    fn delay(&self, attempt: u32) -> std::time::Duration {
+        self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+
+    /// Sends the request built by `request` until it neither fails to connect nor gets a 5xx
+    /// response, or the attempts run out. Other errors and responses are returned right away.
+    fn send<F>(&self, url: &Url, request: F) -> Result<reqwest::blocking::Response, failure::Error>
+    where
+        F: Fn() -> reqwest::blocking::RequestBuilder,
+    {
+        let max_attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            let failure = match request().send() {
+                Ok(res) if res.status().is_server_error() => res.status().to_string(),
+                Ok(res) => return Ok(res),
+                Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => e.to_string(),
+                Err(e) => return Err(e.into()),
+            };
+            if attempt >= max_attempts {
+                bail!(
+                    "Request to {} failed after {} attempts: {}",
+                    url,
+                    attempt,
+                    failure
+                );
+            }
+            let delay = self.delay(attempt);
+            warn!(
+                "Request to {} failed with {}, retrying in {:?} (attempt {} of {})",
+                url,
+                failure,
+                delay,
+                attempt + 1,
+                max_attempts
+            );
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+/// A token along with when it expires, if Keystone said.
+#[derive(Debug)]
+struct AuthToken {
+    token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl AuthToken {
+    fn expires_within(&self, now: DateTime<Utc>, margin: chrono::Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at - now <= margin,
+            None => false,
+        }
+    }
 }
 
 pub mod keystone {
+    use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct TokenInfo {
@@ -32,7 +163,11 @@ pub mod keystone {
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Token {
+        #[serde(default)]
         pub catalog: Vec<Service>,
+
+        #[serde(default)]
+        pub expires_at: Option<DateTime<Utc>>,
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -42,6 +177,27 @@ pub mod keystone {
         #[serde(rename = "type")]
         pub typ: String,
         pub endpoints: Vec<Endpoint>,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    impl crate::strict::KnownFields for Service {
+        const IGNORED: &'static [&'static str] = &["id"];
+
+        #[cfg(feature = "strict")]
+        fn unknown_fields(&self) -> &crate::strict::UnknownFields {
+            &self.unknown
+        }
+
+        #[cfg(feature = "strict")]
+        fn check_nested(&self, unexpected: &mut crate::strict::UnexpectedFields) {
+            for endpoint in &self.endpoints {
+                unexpected.check(endpoint);
+            }
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -49,11 +205,54 @@ pub mod keystone {
         pub region: String,
         pub interface: String,
         pub url: String,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(Endpoint, ["id", "region_id"]);
+
+    /// Pagination links of a Keystone listing.
+    #[derive(Debug, Deserialize, Serialize, Clone, Default)]
+    pub struct Links {
+        #[serde(default)]
+        pub next: Option<url::Url>,
+    }
+
+    /// A page of a Keystone listing, which may be followed by further pages.
+    pub trait Page: serde::de::DeserializeOwned {
+        fn next(&self) -> Option<&url::Url>;
+
+        /// Appends the entries of a following page.
+        fn append(&mut self, page: Self);
+
+        /// Warns about unexpected keys in the entries.
+        fn check_fields(&self);
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Users {
         pub users: Vec<User>,
+
+        #[serde(default)]
+        pub links: Links,
+    }
+
+    impl Page for Users {
+        fn next(&self) -> Option<&url::Url> {
+            self.links.next.as_ref()
+        }
+
+        fn append(&mut self, mut page: Users) {
+            self.users.append(&mut page.users);
+            self.links = page.links;
+        }
+
+        fn check_fields(&self) {
+            crate::strict::check_fields(&self.users);
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -61,11 +260,47 @@ pub mod keystone {
         pub domain_id: String,
         pub id: String,
         pub name: String,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
 
+    known_fields!(
+        User,
+        [
+            "default_project_id",
+            "description",
+            "email",
+            "enabled",
+            "links",
+            "options",
+            "password_expires_at",
+        ]
+    );
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Projects {
         pub projects: Vec<Project>,
+
+        #[serde(default)]
+        pub links: Links,
+    }
+
+    impl Page for Projects {
+        fn next(&self) -> Option<&url::Url> {
+            self.links.next.as_ref()
+        }
+
+        fn append(&mut self, mut page: Projects) {
+            self.projects.append(&mut page.projects);
+            self.links = page.links;
+        }
+
+        fn check_fields(&self) {
+            crate::strict::check_fields(&self.projects);
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -73,29 +308,263 @@ pub mod keystone {
         pub domain_id: String,
         pub id: String,
         pub name: String,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
 
+    known_fields!(
+        Project,
+        [
+            "description",
+            "enabled",
+            "is_domain",
+            "links",
+            "options",
+            "parent_id",
+            "tags",
+        ]
+    );
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Domains {
         pub domains: Vec<Domain>,
+
+        #[serde(default)]
+        pub links: Links,
+    }
+
+    impl Page for Domains {
+        fn next(&self) -> Option<&url::Url> {
+            self.links.next.as_ref()
+        }
+
+        fn append(&mut self, mut page: Domains) {
+            self.domains.append(&mut page.domains);
+            self.links = page.links;
+        }
+
+        fn check_fields(&self) {
+            crate::strict::check_fields(&self.domains);
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Domain {
         pub id: String,
         pub name: String,
+
+        /// Disabled domains are listed all the same, as their projects may still hold resources.
+        #[serde(default = "enabled_by_default")]
+        pub enabled: bool,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(Domain, ["description", "links", "options", "tags"]);
+
+    fn enabled_by_default() -> bool {
+        true
     }
 }
 
+#[derive(Clone)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
-    pub domain: String,
+    pub user_domain: String,
+    pub project_domain: String,
     pub project: String,
+    pub auth_flow: AuthFlow,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("user_domain", &self.user_domain)
+            .field("project_domain", &self.project_domain)
+            .field("project", &self.project)
+            .field("auth_flow", &self.auth_flow)
+            .finish()
+    }
+}
+
+/// How the project-scoped token is obtained from Keystone.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFlow {
+    /// Authenticate directly with a project-scoped password request.
+    #[default]
+    Scoped,
+    /// Authenticate unscoped, look up the project among those available to the user and rescope
+    /// the token to it.
+    Rescope,
+}
+
+/// Names of the services whose endpoints `HostRewrite` applies to, as keys of its `ports`.
+pub const REWRITTEN_SERVICES: [&str; 4] = ["nova", "cinder", "glance", "swift"];
+
+/// Host, and port by service, that the service endpoints of the catalog are pointed at instead,
+/// to reach them through a tunnel or proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRewrite {
+    pub host: String,
+    /// Ports by name of service in `REWRITTEN_SERVICES`, services not listed keep theirs.
+    pub ports: HashMap<String, u16>,
+}
+
+impl Default for HostRewrite {
+    fn default() -> Self {
+        HostRewrite {
+            host: "localhost".to_owned(),
+            ports: HashMap::new(),
+        }
+    }
+}
+
+impl HostRewrite {
+    fn apply(&self, service: &str, url: &mut Url) -> Result<(), failure::Error> {
+        url.set_host(Some(&self.host))?;
+        if let Some(&port) = self.ports.get(service) {
+            url.set_port(Some(port))
+                .map_err(|_| format_err!("Cannot set the port of {}", url))?;
+        }
+        Ok(())
+    }
+}
+
+/// Endpoints of the services used for billing, taken from the catalog of a token.
+struct ServiceUrls {
+    nova_url: Url,
+    cinder_url: Url,
+    glance_url: Url,
+    swift_url: Option<Url>,
+}
+
+impl ServiceUrls {
+    /// Picks the endpoints of `interface` in `region`, which need not be the region that the token
+    /// was issued in.
+    fn from_token_body(
+        body: &str,
+        region: &str,
+        interface: &str,
+        rewrite_host: Option<&HostRewrite>,
+    ) -> Result<ServiceUrls, failure::Error> {
+        let token_info: keystone::TokenInfo = serde_json::from_str(body)?;
+        trace!("{:#?}", token_info);
+        crate::strict::check_fields(&token_info.token.catalog);
+
+        // All endpoints per service, as a service can be listed more than once.
+        let mut region_endpoints: HashMap<(&str, &str), Vec<Url>> = HashMap::new();
+        for svc in &token_info.token.catalog {
+            for ep in &svc.endpoints {
+                if ep.region != region || ep.interface != interface {
+                    continue;
+                }
+                let mut url = Url::parse(&ep.url)?;
+                url.path_segments_mut().unwrap().pop_if_empty().push("");
+                let urls = region_endpoints
+                    .entry((svc.name.as_str(), svc.typ.as_str()))
+                    .or_default();
+                if !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
+
+        debug!("Region endpoints: {:#?}", region_endpoints);
+
+        // Rather than picking one of differing endpoints, which may well be a stale one.
+        let endpoint = |name: &str, typ: &str| -> Result<Option<Url>, failure::Error> {
+            match region_endpoints.get(&(name, typ)).map(Vec::as_slice) {
+                None | Some([]) => Ok(None),
+                Some([url]) => Ok(Some(url.clone())),
+                Some(urls) => {
+                    let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+                    bail!(
+                        "The catalog has conflicting {} endpoints for {} in region {}: {}",
+                        interface,
+                        name,
+                        region,
+                        urls.join(", ")
+                    )
+                }
+            }
+        };
+
+        let mut nova_url =
+            endpoint("nova", "compute")?.ok_or(format_err!("Could not find Nova endpoint"))?;
+        let mut cinder_url = endpoint("cinderv3", "volumev3")?
+            .ok_or(format_err!("Could not find Cinder endpoint"))?;
+        let mut glance_url =
+            endpoint("glance", "image")?.ok_or(format_err!("Could not find Glance endpoint"))?;
+        let mut swift_url = endpoint("swiftv1", "object-store")?;
+
+        if let Some(rewrite) = rewrite_host {
+            rewrite.apply("nova", &mut nova_url)?;
+            rewrite.apply("cinder", &mut cinder_url)?;
+            rewrite.apply("glance", &mut glance_url)?;
+            if let Some(url) = swift_url.as_mut() {
+                rewrite.apply("swift", url)?;
+            }
+        }
+
+        Ok(ServiceUrls {
+            nova_url,
+            cinder_url,
+            glance_url,
+            swift_url,
+        })
+    }
+}
+
+/// Parses a response body as it is read rather than holding all of it in memory first, except
+/// when dumping it to `debug_filename` because `SBL_DUMP_OS_JSON` is set.
+pub fn decode<T: serde::de::DeserializeOwned, R: std::io::Read>(
+    mut body: R,
+    debug_filename: &str,
+) -> Result<T, failure::Error> {
+    if should_write_debug_json() {
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf)?;
+        std::fs::write(debug_filename, &buf)?;
+        return Ok(serde_json::from_slice(&buf)?);
+    }
+    Ok(serde_json::from_reader(std::io::BufReader::new(body))?)
+}
+
+/// When the token issued with the authentication response `body` expires.
+fn token_expiry(body: &str) -> Result<Option<DateTime<Utc>>, failure::Error> {
+    let token_info: keystone::TokenInfo = serde_json::from_str(body)?;
+    Ok(token_info.token.expires_at)
+}
+
+/// The project to rescope to among those available to the user.
+fn rescope_project<'a>(
+    projects: &'a keystone::Projects,
+    creds: &Credentials,
+) -> Result<&'a keystone::Project, failure::Error> {
+    projects
+        .projects
+        .iter()
+        .find(|p| p.name == creds.project && p.domain_id == creds.project_domain)
+        .ok_or(format_err!(
+            "Project {} in domain {} is not available to user {}",
+            creds.project,
+            creds.project_domain,
+            creds.username
+        ))
 }
 
 impl Session {
-    fn auth_scoped_payload(creds: &Credentials) -> String {
+    fn auth_unscoped_payload(creds: &Credentials) -> String {
         json!({"auth": {
             "identity": {
                 "methods": ["password"],
@@ -103,104 +572,212 @@ impl Session {
                     "user": {
                         "name": creds.username,
                         "password": creds.password,
-                        "domain": {"id": creds.domain},
+                        "domain": {"id": creds.user_domain},
                     }
                 }
+            }
+        }})
+        .to_string()
+    }
+
+    fn auth_rescope_payload(unscoped_token: &str, project_id: &str) -> String {
+        json!({"auth": {
+            "identity": {
+                "methods": ["token"],
+                "token": {"id": unscoped_token}
             },
             "scope": {
-                "project": {
-                    "domain": {"id": creds.domain},
-                    "name": creds.project
-                }
+                "project": {"id": project_id}
             }
         }})
         .to_string()
     }
 
-    pub fn new(
-        creds: &Credentials,
+    /// Posts an authentication request, returning the issued token and the response body.
+    fn request_token(
+        client: &reqwest::blocking::Client,
         keystone_url: &Url,
-        region: &str,
-        rewrite_host: bool,
-    ) -> Result<Session, failure::Error> {
-        let keystone_url = {
-            let mut url = keystone_url.clone();
-            url.path_segments_mut().unwrap().pop_if_empty().push(""); // ensure that the URL ends in a slash
-            url
-        };
-        let client = reqwest::blocking::Client::new();
+        payload: String,
+    ) -> Result<(String, String), failure::Error> {
         let res = client
             .post(keystone_url.join("auth/tokens/")?.as_str())
             .header(CONTENT_TYPE, "application/json")
-            .body(Session::auth_scoped_payload(&creds))
+            .body(payload)
             .send()?;
         trace!("{:?}", res);
-        let admin_scoped_token: String = res
+        if !res.status().is_success() {
+            bail!("Could not authenticate with Keystone: {}", res.status());
+        }
+        let token = res
             .headers()
             .get("X-Subject-Token")
-            .unwrap()
-            .to_str()
-            .unwrap()
+            .ok_or(format_err!("Keystone did not return a token"))?
+            .to_str()?
             .to_owned();
-        let body = res.text()?;
-        let token_info: keystone::TokenInfo = serde_json::from_str(&body)?;
-        trace!("{:#?}", token_info);
-        trace!("Admin scoped token: {}", admin_scoped_token);
+        Ok((token, res.text()?))
+    }
 
-        let region_endpoints = token_info
-            .token
-            .catalog
-            .iter()
-            .filter_map(|svc| {
-                svc.endpoints
-                    .iter()
-                    .find(|ep| ep.region == region && ep.interface == "admin")
-                    .map(|ep| {
-                        let mut url = Url::parse(&ep.url).unwrap();
-                        url.path_segments_mut().unwrap().pop_if_empty().push("");
-                        ((svc.name.as_str(), svc.typ.as_str()), url)
-                    })
-            })
-            .collect::<HashMap<_, _>>();
+    /// Obtains a project-scoped token by way of an unscoped one, see `AuthFlow::Rescope`.
+    fn rescoped_token(
+        client: &reqwest::blocking::Client,
+        keystone_url: &Url,
+        creds: &Credentials,
+    ) -> Result<(String, String), failure::Error> {
+        let (unscoped_token, _) =
+            Session::request_token(client, keystone_url, Session::auth_unscoped_payload(creds))?;
+        let res = client
+            .get(keystone_url.join("auth/projects")?.as_str())
+            .header("X-Auth-Token", unscoped_token.as_str())
+            .send()?;
+        if !res.status().is_success() {
+            bail!("Could not list projects available for rescoping");
+        }
+        let projects: keystone::Projects = serde_json::from_str(&res.text()?)?;
+        let project = rescope_project(&projects, creds)?;
+        Session::request_token(
+            client,
+            keystone_url,
+            Session::auth_rescope_payload(&unscoped_token, &project.id),
+        )
+    }
 
-        debug!("Region endpoints: {:#?}", region_endpoints);
+    fn auth_scoped_payload(creds: &Credentials) -> String {
+        json!({"auth": {
+            "identity": {
+                "methods": ["password"],
+                "password": {
+                    "user": {
+                        "name": creds.username,
+                        "password": creds.password,
+                        "domain": {"id": creds.user_domain},
+                    }
+                }
+            },
+            "scope": {
+                "project": {
+                    "domain": {"id": creds.project_domain},
+                    "name": creds.project
+                }
+            }
+        }})
+        .to_string()
+    }
 
-        let mut nova_url = region_endpoints
-            .get(&("nova", "compute"))
-            .ok_or(format_err!("Could not find Nova endpoint"))?
-            .clone();
-        let mut cinder_url = region_endpoints
-            .get(&("cinderv3", "volumev3"))
-            .ok_or(format_err!("Could not find Cinder endpoint"))?
-            .clone();
-        let mut glance_url = region_endpoints
-            .get(&("glance", "image"))
-            .ok_or(format_err!("Could not find Glance endpoint"))?
-            .clone();
-        let mut swift_url = region_endpoints.get(&("swiftv1", "object-store")).cloned();
-
-        if rewrite_host {
-            for url in [&mut nova_url, &mut cinder_url, &mut glance_url].iter_mut() {
-                url.set_host(Some("localhost"))?;
+    /// Obtains a project-scoped token with `creds`, returning it along with the response body.
+    fn authenticate(
+        client: &reqwest::blocking::Client,
+        keystone_url: &Url,
+        creds: &Credentials,
+    ) -> Result<(String, String), failure::Error> {
+        match creds.auth_flow {
+            AuthFlow::Scoped => {
+                Session::request_token(client, keystone_url, Session::auth_scoped_payload(creds))
             }
-            swift_url
-                .as_mut()
-                .map(|url| url.set_host(Some("localhost")));
+            AuthFlow::Rescope => Session::rescoped_token(client, keystone_url, creds),
+        }
+    }
+
+    /// Replaces the token with a new one, as it has expired or is about to.
+    fn reauthenticate(&self) -> Result<(), failure::Error> {
+        let (token, body) =
+            Session::authenticate(&self.client, &self.keystone_url, &self.credentials)?;
+        *self.auth_token.borrow_mut() = AuthToken {
+            token,
+            expires_at: token_expiry(&body)?,
+        };
+        Ok(())
+    }
+
+    /// Sends an authenticated GET request, first obtaining a new token if the current one is about
+    /// to expire or otherwise doing so and retrying once if the request is rejected with a 401.
+    fn get(
+        &self,
+        url: &Url,
+        headers: &[(&str, &str)],
+    ) -> Result<reqwest::blocking::Response, failure::Error> {
+        let margin = chrono::Duration::seconds(TOKEN_EXPIRY_MARGIN_SECS);
+        let mut reauthenticated = false;
+        if self.auth_token.borrow().expires_within(Utc::now(), margin) {
+            info!("Keystone token is about to expire, reauthenticating");
+            self.reauthenticate()?;
+            reauthenticated = true;
         }
+        let send = || {
+            self.retry.send(url, || {
+                let mut req = self
+                    .client
+                    .get(url.as_str())
+                    .header("X-Auth-Token", self.auth_token.borrow().token.as_str());
+                for (name, value) in headers {
+                    req = req.header(*name, *value);
+                }
+                req
+            })
+        };
+        let res = send()?;
+        if res.status() == reqwest::StatusCode::UNAUTHORIZED && !reauthenticated {
+            info!("Keystone token was rejected by {}, reauthenticating", url);
+            self.reauthenticate()?;
+            return send();
+        }
+        Ok(res)
+    }
+
+    /// Retries failed requests according to `retry` instead of `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Session {
+        self.retry = retry;
+        self
+    }
+
+    /// Authenticates and looks up the service endpoints, pointed elsewhere by `rewrite_host` if
+    /// given. Requests, including the authentication, give up after `timeout` if one is given.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        creds: &Credentials,
+        keystone_url: &Url,
+        keystone_api_path: &str,
+        region: &str,
+        interface: &str,
+        rewrite_host: Option<&HostRewrite>,
+        user_agent: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Session, failure::Error> {
+        let keystone_url = keystone_api_url(keystone_url, keystone_api_path);
+        let mut builder = reqwest::blocking::Client::builder()
+            .user_agent(user_agent)
+            .gzip(true);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder.build()?;
+        let (admin_scoped_token, body) = Session::authenticate(&client, &keystone_url, creds)?;
+        let ServiceUrls {
+            nova_url,
+            cinder_url,
+            glance_url,
+            swift_url,
+        } = ServiceUrls::from_token_body(&body, region, interface, rewrite_host)?;
+        trace!("Admin scoped token: {}", admin_scoped_token);
 
         Ok(Session {
-            auth_token: admin_scoped_token,
-            keystone_url: keystone_url,
+            client,
+            auth_token: RefCell::new(AuthToken {
+                token: admin_scoped_token,
+                expires_at: token_expiry(&body)?,
+            }),
+            credentials: creds.clone(),
+            keystone_url,
             nova_url,
             cinder_url,
             glance_url,
             swift_url,
+            retry: RetryPolicy::default(),
         })
     }
 }
 
 pub mod cinder {
-    use serde::{Deserialize, Serialize};
+    use serde::{Deserialize, Deserializer, Serialize};
 
     #[derive(Debug, Deserialize)]
     pub struct Volumes {
@@ -216,49 +793,123 @@ pub mod cinder {
         pub size: u64,
         pub user_id: String,
 
-        #[serde(rename = "os-vol-tenant-attr:tenant_id")]
-        pub tenant_id: String,
+        #[serde(rename = "os-vol-tenant-attr:tenant_id", default)]
+        pub tenant_id: Option<String>,
+
+        #[serde(default)]
+        pub project_id: Option<String>,
 
         pub availability_zone: String,
+
+        #[serde(default)]
+        pub volume_type: Option<String>,
+
+        /// Such as `available`, `in-use` or `deleting`, empty in snapshots from before it was kept.
+        #[serde(default)]
+        pub status: String,
+
+        #[serde(default, deserialize_with = "deserialize_bootable")]
+        pub bootable: bool,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(
+        Volume,
+        [
+            "attachments",
+            "backup_id",
+            "cluster_name",
+            "consistencygroup_id",
+            "consumes_quota",
+            "created_at",
+            "description",
+            "encrypted",
+            "group_id",
+            "links",
+            "metadata",
+            "migration_status",
+            "multiattach",
+            "name",
+            "os-vol-host-attr:host",
+            "os-vol-mig-status-attr:migstat",
+            "os-vol-mig-status-attr:name_id",
+            "os-volume-replication:driver_data",
+            "os-volume-replication:extended_status",
+            "provider_id",
+            "replication_status",
+            "service_uuid",
+            "shared_targets",
+            "snapshot_id",
+            "source_volid",
+            "updated_at",
+            "volume_image_metadata",
+            "volume_type_id",
+        ]
+    );
+
+    /// Cinder reports `bootable` as the string `"true"` or `"false"`.
+    fn deserialize_bootable<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Bootable {
+            Bool(bool),
+            Text(String),
+        }
+
+        match Bootable::deserialize(deserializer)? {
+            Bootable::Bool(b) => Ok(b),
+            Bootable::Text(s) => Ok(s.eq_ignore_ascii_case("true")),
+        }
+    }
+
+    impl Volume {
+        /// The owning project, from the admin-only tenant attribute or the newer `project_id`.
+        pub fn owner(&self) -> Option<&str> {
+            self.tenant_id
+                .as_deref()
+                .or(self.project_id.as_deref())
+                .filter(|id| !id.is_empty())
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Link {
         pub rel: String,
         pub href: url::Url,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
+
+    known_fields!(Link, []);
 }
 
 impl Session {
-    fn fetch_volume_set(
-        &self,
-        client: &reqwest::blocking::Client,
-        url: &url::Url,
-    ) -> Result<cinder::Volumes, failure::Error> {
-        let res = client
-            .get(url.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+    fn fetch_volume_set(&self, url: &url::Url) -> Result<cinder::Volumes, failure::Error> {
+        let res = self.get(url, &[])?;
 
         if !res.status().is_success() {
             bail!("Could not retrieve volumes from Glance");
         }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("volumes.json", &text)?;
-        }
-        let volumes: cinder::Volumes = serde_json::from_str(&text)?;
+        let volumes: cinder::Volumes = decode(res, "volumes.json")?;
+        crate::strict::check_fields(&volumes.volumes);
+        crate::strict::check_fields(&volumes.links);
         Ok(volumes)
     }
 
     pub fn volumes(&self) -> Result<Vec<cinder::Volume>, failure::Error> {
-        let client = reqwest::blocking::Client::new();
         let mut url = self.cinder_url.join("volumes/detail?all_tenants=1")?;
 
         let mut ret = Vec::new();
         loop {
-            let mut volumes = self.fetch_volume_set(&client, &url)?;
+            let mut volumes = self.fetch_volume_set(&url)?;
             ret.append(&mut volumes.volumes);
             trace!("{:#?}", volumes.links);
             if let Some(next) = volumes.links.iter().find(|lnk| lnk.rel == "next") {
@@ -285,12 +936,16 @@ pub struct NameMapping {
 }
 
 impl NameMapping {
-    pub fn get<'a, S: AsRef<str>>(&'a self, id: S) -> Option<NameWithDomain> {
+    pub fn get<S: AsRef<str>>(&self, id: S) -> Option<NameWithDomain> {
         self.id_to_name.get(id.as_ref()).cloned()
     }
 
-    pub fn has_name_in_domain<'a, SName: AsRef<str>, SDomain: AsRef<str>>(
-        &'a self,
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &NameWithDomain)> {
+        self.id_to_name.iter()
+    }
+
+    pub fn has_name_in_domain<SName: AsRef<str>, SDomain: AsRef<str>>(
+        &self,
         name: SName,
         domain_id: SDomain,
     ) -> bool {
@@ -303,116 +958,119 @@ impl NameMapping {
     }
 }
 
-pub type Flavors = HashMap<String, nova::Flavor>;
-
-impl Session {
-    fn users(&self) -> Result<keystone::Users, failure::Error> {
-        let client = reqwest::blocking::Client::new();
-        let res = client
-            .get(self.keystone_url.join("users/")?.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
-
-        if !res.status().is_success() {
-            bail!("Could not retrieve users from Keystone");
-        }
-
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("users.json", &text)?;
-        }
-        let users: keystone::Users = serde_json::from_str(&text)?;
-        Ok(users)
+impl From<keystone::Users> for NameMapping {
+    fn from(users: keystone::Users) -> NameMapping {
+        let id_to_name = users
+            .users
+            .into_iter()
+            .map(|user| {
+                let name = NameWithDomain {
+                    name: user.name,
+                    domain_id: user.domain_id,
+                };
+                (user.id, name)
+            })
+            .collect();
+        NameMapping { id_to_name }
     }
+}
 
-    pub fn user_mappings(&self) -> Result<NameMapping, failure::Error> {
-        let users = self.users()?;
+impl From<keystone::Projects> for NameMapping {
+    fn from(projects: keystone::Projects) -> NameMapping {
+        let id_to_name = projects
+            .projects
+            .into_iter()
+            .map(|proj| {
+                let name = NameWithDomain {
+                    name: proj.name,
+                    domain_id: proj.domain_id,
+                };
+                (proj.id, name)
+            })
+            .collect();
+        NameMapping { id_to_name }
+    }
+}
 
-        let mut id_to_name = HashMap::new();
-        for user in users.users {
-            let name = NameWithDomain {
-                name: user.name,
-                domain_id: user.domain_id,
-            };
-            id_to_name.insert(user.id, name);
-        }
+pub type Flavors = HashMap<String, nova::Flavor>;
 
-        Ok(NameMapping { id_to_name })
+impl From<nova::Flavors> for Flavors {
+    fn from(flavors: nova::Flavors) -> Flavors {
+        flavors
+            .flavors
+            .into_iter()
+            .map(|flavor| (flavor.id.clone(), flavor))
+            .collect()
     }
+}
 
-    pub fn project_mappings(&self) -> Result<NameMapping, failure::Error> {
-        let client = reqwest::blocking::Client::new();
-        let res = client
-            .get(self.keystone_url.join("projects/")?.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+impl Session {
+    /// Retrieves all pages of a Keystone listing at `path`, following the `next` links.
+    fn keystone_list<T: keystone::Page>(
+        &self,
+        path: &str,
+        what: &str,
+        debug_filename: &str,
+    ) -> Result<T, failure::Error> {
+        let mut url = self.keystone_url.join(path)?;
+        let mut list: Option<T> = None;
+        loop {
+            let res = self.get(&url, &[])?;
 
-        if !res.status().is_success() {
-            bail!("Could not retrieve projects from Keystone");
-        }
+            if !res.status().is_success() {
+                bail!("Could not retrieve {} from Keystone", what);
+            }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("projects.json", &text)?;
+            let page: T = decode(res, debug_filename)?;
+            let next = page.next().cloned();
+            match &mut list {
+                Some(list) => list.append(page),
+                None => list = Some(page),
+            }
+            match next {
+                Some(next) if next != url => url = next,
+                _ => break,
+            }
         }
-        let projects: keystone::Projects = serde_json::from_str(&text)?;
-
-        let mut id_to_name = HashMap::new();
-        for proj in projects.projects {
-            let name = NameWithDomain {
-                name: proj.name,
-                domain_id: proj.domain_id,
-            };
-            id_to_name.insert(proj.id, name);
+        if let Some(list) = &list {
+            list.check_fields();
         }
+        Ok(list.unwrap())
+    }
 
-        Ok(NameMapping { id_to_name })
+    fn users(&self) -> Result<keystone::Users, failure::Error> {
+        self.keystone_list("users/", "users", "users.json")
     }
 
-    pub fn domains(&self) -> Result<keystone::Domains, failure::Error> {
-        let client = reqwest::blocking::Client::new();
-        let res = client
-            .get(self.keystone_url.join("domains/")?.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+    pub fn user_mappings(&self) -> Result<NameMapping, failure::Error> {
+        Ok(self.users()?.into())
+    }
 
-        if !res.status().is_success() {
-            bail!("Could not retrieve domains from Keystone");
-        }
+    pub fn project_mappings(&self) -> Result<NameMapping, failure::Error> {
+        let projects: keystone::Projects =
+            self.keystone_list("projects/", "projects", "projects.json")?;
+        Ok(projects.into())
+    }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("domains.json", &text)?;
-        }
-        let domains: keystone::Domains = serde_json::from_str(&text)?;
-        Ok(domains)
+    pub fn domains(&self) -> Result<keystone::Domains, failure::Error> {
+        self.keystone_list("domains/", "domains", "domains.json")
     }
 
     pub fn flavors(&self) -> Result<Flavors, failure::Error> {
-        let client = reqwest::blocking::Client::new();
         let url = self.nova_url.join("flavors/detail?is_public=None")?;
         trace!("flavor url: {:?}", url);
-        let res = client
-            .get(url.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+        let res = self.get(
+            &url,
+            &[("X-OpenStack-Nova-API-Version", NOVA_FLAVORS_MICROVERSION)],
+        )?;
 
         if !res.status().is_success() {
             bail!("Could not retrieve flavors from Nova");
         }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("flavors.json", &text)?;
-        }
-        let flavors: nova::Flavors = serde_json::from_str(&text)?;
-
-        let mut ret = HashMap::new();
-        for flavor in flavors.flavors {
-            ret.insert(flavor.id.clone(), flavor);
-        }
-
-        Ok(ret)
+        let flavors: nova::Flavors = decode(res, "flavors.json")?;
+        crate::strict::check_fields(&flavors.flavors);
+        Ok(flavors.into())
     }
 }
 
@@ -427,6 +1085,8 @@ pub mod glance {
         pub next: Option<String>,
     }
 
+    /// Not checked for unknown fields under `strict`, as Glance returns the properties set on an
+    /// image as keys of their own.
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Image {
         pub container_format: Option<String>,
@@ -453,36 +1113,24 @@ pub mod glance {
 }
 
 impl Session {
-    fn fetch_image_set(
-        &self,
-        client: &reqwest::blocking::Client,
-        url: &url::Url,
-    ) -> Result<glance::Images, failure::Error> {
-        let res = client
-            .get(url.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+    fn fetch_image_set(&self, url: &url::Url) -> Result<glance::Images, failure::Error> {
+        let res = self.get(url, &[])?;
 
         if !res.status().is_success() {
             bail!("Could not retrieve images from Glance");
         }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("images.json", &text)?;
-        }
-        let images: glance::Images = serde_json::from_str(&text)?;
+        let images: glance::Images = decode(res, "images.json")?;
         Ok(images)
     }
 
     pub fn images(&self) -> Result<Vec<glance::Image>, failure::Error> {
-        let client = reqwest::blocking::Client::new();
         let base_url = self.glance_url.join("v2/images")?;
         let mut url = base_url.clone();
 
         let mut ret = Vec::new();
         loop {
-            let mut images = self.fetch_image_set(&client, &url)?;
+            let mut images = self.fetch_image_set(&url)?;
             ret.append(&mut images.images);
             if let Some(next) = images.next {
                 url = base_url.join(&next)?;
@@ -496,7 +1144,10 @@ impl Session {
 }
 
 pub mod nova {
-    use serde::{Deserialize, Serialize};
+    use crate::units::{Bytes, Gigabytes, Megabytes};
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize};
+    use std::collections::BTreeMap;
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Servers {
@@ -512,11 +1163,78 @@ pub mod nova {
         pub image: Image,
         pub status: String,
 
+        #[serde(default)]
+        pub created: Option<DateTime<Utc>>,
+
         #[serde(rename = "OS-EXT-AZ:availability_zone")]
         pub zone: Option<String>,
 
         #[serde(rename = "os-extended-volumes:volumes_attached")]
         pub attached_volumes: Vec<AttachedVolume>,
+
+        #[serde(rename = "OS-SRV-USG:terminated_at", default)]
+        pub terminated_at: Option<DateTime<Utc>>,
+
+        #[serde(default)]
+        pub tags: Vec<String>,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    impl crate::strict::KnownFields for Server {
+        const IGNORED: &'static [&'static str] = &[
+            "OS-DCF:diskConfig",
+            "OS-EXT-SRV-ATTR:host",
+            "OS-EXT-SRV-ATTR:hostname",
+            "OS-EXT-SRV-ATTR:hypervisor_hostname",
+            "OS-EXT-SRV-ATTR:instance_name",
+            "OS-EXT-SRV-ATTR:kernel_id",
+            "OS-EXT-SRV-ATTR:launch_index",
+            "OS-EXT-SRV-ATTR:ramdisk_id",
+            "OS-EXT-SRV-ATTR:reservation_id",
+            "OS-EXT-SRV-ATTR:root_device_name",
+            "OS-EXT-SRV-ATTR:user_data",
+            "OS-EXT-STS:power_state",
+            "OS-EXT-STS:task_state",
+            "OS-EXT-STS:vm_state",
+            "OS-SRV-USG:launched_at",
+            "accessIPv4",
+            "accessIPv6",
+            "addresses",
+            "config_drive",
+            "description",
+            "fault",
+            "hostId",
+            "host_status",
+            "key_name",
+            "links",
+            "locked",
+            "locked_reason",
+            "metadata",
+            "name",
+            "pinned_availability_zone",
+            "progress",
+            "security_groups",
+            "server_groups",
+            "trusted_image_certificates",
+            "updated",
+        ];
+
+        #[cfg(feature = "strict")]
+        fn unknown_fields(&self) -> &crate::strict::UnknownFields {
+            &self.unknown
+        }
+
+        #[cfg(feature = "strict")]
+        fn check_nested(&self, unexpected: &mut crate::strict::UnexpectedFields) {
+            unexpected.check(&self.flavor);
+            for volume in &self.attached_volumes {
+                unexpected.check(volume);
+            }
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -529,13 +1247,27 @@ pub mod nova {
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct AttachedVolume {
         pub id: String,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
 
+    known_fields!(AttachedVolume, ["delete_on_termination"]);
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct ServerFlavor {
         pub id: String,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
 
+    known_fields!(ServerFlavor, ["links"]);
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Flavors {
         pub flavors: Vec<Flavor>,
@@ -548,101 +1280,906 @@ pub mod nova {
         pub vcpus: u64,
         pub ram: u64,
         pub disk: u64,
+
+        #[serde(rename = "OS-FLV-EXT-DATA:ephemeral", default)]
+        pub ephemeral: u64,
+
+        /// Swap size in megabytes, which Nova reports as an empty string when there is none.
+        #[serde(default, deserialize_with = "deserialize_swap")]
+        pub swap: u64,
+
+        #[serde(default)]
+        pub extra_specs: BTreeMap<String, String>,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(
+        Flavor,
+        [
+            "OS-FLV-DISABLED:disabled",
+            "description",
+            "links",
+            "os-flavor-access:is_public",
+            "rxtx_factor",
+        ]
+    );
+
+    impl Flavor {
+        /// Total of the root, ephemeral and swap disks.
+        pub fn allocated_disk(&self) -> Bytes {
+            Bytes::from(Gigabytes(self.disk))
+                + Bytes::from(Gigabytes(self.ephemeral))
+                + Bytes::from(Megabytes(self.swap))
+        }
+
+        /// The count in the extra spec `key`, given either as a number such as the `2` of
+        /// `resources:VGPU` or after an alias such as the `a100:2` of `pci_passthrough:alias`.
+        pub fn extra_spec_count(&self, key: &str) -> Option<u64> {
+            let value = self.extra_specs.get(key)?;
+            value.rsplit(':').next()?.trim().parse().ok()
+        }
+    }
+
+    fn deserialize_swap<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Swap {
+            Size(u64),
+            Text(String),
+        }
+
+        match Swap::deserialize(deserializer)? {
+            Swap::Size(mb) => Ok(mb),
+            Swap::Text(s) if s.is_empty() => Ok(0),
+            Swap::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
     }
 }
 
 impl Session {
     /// Obtain a list of servers from the API.
     pub fn servers(&self) -> Result<Vec<nova::Server>, failure::Error> {
-        let client = reqwest::blocking::Client::new();
         let mut req_url = self.nova_url.join("servers/detail")?;
         req_url.query_pairs_mut().append_pair("all_tenants", "True");
+        self.fetch_servers(&req_url, "servers.json")
+    }
 
-        let res = client
-            .get(req_url.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+    /// Obtain a list of servers deleted since the given time, including their termination time.
+    pub fn deleted_servers(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<nova::Server>, failure::Error> {
+        let mut req_url = self.nova_url.join("servers/detail")?;
+        req_url
+            .query_pairs_mut()
+            .append_pair("all_tenants", "True")
+            .append_pair("deleted", "True")
+            .append_pair("changes-since", &since.to_rfc3339());
+        self.fetch_servers(&req_url, "deleted_servers.json")
+    }
+
+    fn fetch_servers(
+        &self,
+        req_url: &Url,
+        debug_filename: &str,
+    ) -> Result<Vec<nova::Server>, failure::Error> {
+        let res = self.get(
+            req_url,
+            &[("X-OpenStack-Nova-API-Version", NOVA_SERVERS_MICROVERSION)],
+        )?;
 
         trace!("{:?}", &res);
         if !res.status().is_success() {
             bail!("Could not retrieve instances from Keystone");
         }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("servers.json", &text)?;
-        }
-        let servers: nova::Servers = serde_json::from_str(&text)?;
+        let servers: nova::Servers = decode(res, debug_filename)?;
+        crate::strict::check_fields(&servers.servers);
 
         Ok(servers.servers)
     }
 }
 
-pub mod swift {
-    use chrono::{DateTime, Utc};
+pub mod quota {
     use serde::{Deserialize, Serialize};
 
+    #[derive(Debug, Deserialize)]
+    pub struct QuotaSetResponse<T> {
+        pub quota_set: T,
+    }
+
+    /// Nova compute quota, negative values mean unlimited.
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ComputeQuota {
+        pub cores: i64,
+        pub ram: i64,
+    }
+
+    /// Cinder volume quota, negative values mean unlimited.
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct VolumeQuota {
+        pub gigabytes: i64,
+    }
+
+    /// Allocated quota of a project, with cores, RAM in megabytes and volume gigabytes.
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    pub struct ProjectQuota {
+        pub cores: i64,
+        pub ram: i64,
+        pub gigabytes: i64,
+    }
+}
+
+impl Session {
+    fn fetch_quota_set<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &Url,
+        debug_filename: &str,
+    ) -> Result<T, failure::Error> {
+        let res = self.get(url, &[])?;
+
+        if !res.status().is_success() {
+            bail!("Could not retrieve quota set {}", url);
+        }
+
+        let quota: quota::QuotaSetResponse<T> = decode(res, debug_filename)?;
+        Ok(quota.quota_set)
+    }
+
+    /// Retrieves the Nova and Cinder quota of a project.
+    pub fn project_quota(&self, project_id: &str) -> Result<quota::ProjectQuota, failure::Error> {
+        let compute: quota::ComputeQuota = self.fetch_quota_set(
+            &self
+                .nova_url
+                .join(&format!("os-quota-sets/{}", project_id))?,
+            "compute_quota.json",
+        )?;
+        let volume: quota::VolumeQuota = self.fetch_quota_set(
+            &self
+                .cinder_url
+                .join(&format!("os-quota-sets/{}", project_id))?,
+            "volume_quota.json",
+        )?;
+        Ok(quota::ProjectQuota {
+            cores: compute.cores,
+            ram: compute.ram,
+            gigabytes: volume.gigabytes,
+        })
+    }
+}
+
+pub mod swift {
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize};
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Container {
         pub count: u64,
         pub bytes: u64,
         pub name: String,
+        #[serde(default, deserialize_with = "deserialize_last_modified")]
         pub last_modified: Option<DateTime<Utc>>,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(Container, []);
+
+    /// Swift lists `last_modified` in UTC without saying so, as in `2019-02-13T11:20:31.460230`.
+    fn deserialize_last_modified<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        let text: Option<String> = Option::deserialize(deserializer)?;
+        let text = match text {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(&text) {
+            return Ok(Some(datetime.with_timezone(&Utc)));
+        }
+        NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%S%.f")
+            .map(|naive| Some(DateTime::from_utc(naive, Utc)))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Number of containers requested per page of a Swift account listing.
+const SWIFT_CONTAINERS_PAGE_SIZE: usize = 1000;
+
+/// The Swift account of `project` beside the account of the token's project that the catalog
+/// endpoint `swift_url` ends in, such as `/v1/AUTH_<project>/`.
+fn swift_account_url(swift_url: &Url, project: &str) -> Url {
+    let mut url = swift_url.clone();
+    {
+        let mut segments = url.path_segments_mut().unwrap();
+        segments.pop_if_empty();
+        if swift_url
+            .path_segments()
+            .and_then(|mut s| s.rfind(|s| !s.is_empty()))
+            .is_some_and(|last| last.starts_with("AUTH_"))
+        {
+            segments.pop();
+        }
+        segments.push(&format!("AUTH_{}", project));
     }
+    url
 }
 
 impl Session {
-    fn fetch_container_set(
-        &self,
-        client: &reqwest::blocking::Client,
-        url: &url::Url,
-    ) -> Result<Vec<swift::Container>, failure::Error> {
-        let res = client
-            .get(url.as_str())
-            .header("X-Auth-Token", self.auth_token.as_str())
-            .send()?;
+    fn fetch_container_set(&self, url: &url::Url) -> Result<Vec<swift::Container>, failure::Error> {
+        let res = self.get(url, &[])?;
 
+        if res.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(vec![]);
+        }
         if !res.status().is_success() {
-            bail!("Could not retrieve images from Glance");
+            bail!("Could not retrieve containers from Swift");
         }
 
-        let text = res.text()?;
-        if should_write_debug_json() {
-            std::fs::write("containers.json", &text)?;
-        }
-        let containers: Vec<swift::Container> = serde_json::from_str(&text)?;
+        let containers: Vec<swift::Container> = decode(res, "containers.json")?;
+        crate::strict::check_fields(&containers);
         Ok(containers)
     }
 
-    #[allow(unreachable_code, unused_variables)]
+    /// Lists the Swift containers of `project`, or none if the catalog has no Swift endpoint.
     pub fn containers(&self, project: &str) -> Result<Vec<swift::Container>, failure::Error> {
-        return Ok(vec![]);
-
-        if let Some(swift_url) = self.swift_url {
-            let client = reqwest::blocking::Client::new();
-            let base_url = swift_url.join(project)?;
-            let marker: Option<String> = None;
-
-            let mut ret = Vec::new();
-            loop {
-                let mut url = base_url.clone();
-                let qp = url.query_pairs_mut().append_pair("limit", "10");
-                if let Some(marker) = marker {
-                    qp.append_pair("marker", &marker);
-                }
-                drop(qp);
-                let mut containers = self.fetch_container_set(&client, &url)?;
-                let done = containers.len() == 0;
-                ret.append(&mut containers);
-                if done {
-                    break;
+        self.containers_paged(project, SWIFT_CONTAINERS_PAGE_SIZE)
+    }
+
+    fn containers_paged(
+        &self,
+        project: &str,
+        limit: usize,
+    ) -> Result<Vec<swift::Container>, failure::Error> {
+        let swift_url = match &self.swift_url {
+            Some(swift_url) => swift_url,
+            None => return Ok(vec![]),
+        };
+        let base_url = swift_account_url(swift_url, project);
+        let mut marker: Option<String> = None;
+
+        let mut ret = Vec::new();
+        loop {
+            let mut url = base_url.clone();
+            {
+                let mut qp = url.query_pairs_mut();
+                qp.append_pair("format", "json")
+                    .append_pair("limit", &limit.to_string());
+                if let Some(marker) = &marker {
+                    qp.append_pair("marker", marker);
                 }
-                marker = Some(containers.last().unwrap().name.clone());
             }
+            let mut containers = self.fetch_container_set(&url)?;
+            match containers.last() {
+                Some(last) => marker = Some(last.name.clone()),
+                None => break,
+            }
+            ret.append(&mut containers);
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::{
+        json_response, local_listener, serve_fixtures, serve_requests, NOT_FOUND,
+    };
+
+    /// A session against `keystone_url` with the other services on the same host.
+    fn test_session(keystone_url: &Url) -> Session {
+        Session {
+            client: reqwest::blocking::Client::new(),
+            auth_token: RefCell::new(AuthToken {
+                token: "token".to_owned(),
+                expires_at: None,
+            }),
+            credentials: Credentials {
+                username: "admin".to_owned(),
+                password: "secret".to_owned(),
+                user_domain: "default".to_owned(),
+                project_domain: "default".to_owned(),
+                project: "admin".to_owned(),
+                auth_flow: AuthFlow::Scoped,
+            },
+            keystone_url: keystone_url.clone(),
+            nova_url: keystone_url.clone(),
+            cinder_url: keystone_url.clone(),
+            glance_url: keystone_url.clone(),
+            swift_url: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn users_follow_pagination() {
+        let url = serve_fixtures(&[
+            ("/users/", "keystone/paged/users.json"),
+            (
+                "/users/?marker=u-alice",
+                "keystone/paged/users.u-alice.json",
+            ),
+        ]);
+        let users = test_session(&url).user_mappings().unwrap();
+        let mut names: Vec<_> = users.iter().map(|(_, user)| user.name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["admin", "bob", "s1234"]);
+    }
+
+    #[test]
+    fn projects_follow_pagination() {
+        let url = serve_fixtures(&[
+            ("/projects/", "keystone/paged/projects.json"),
+            (
+                "/projects/?marker=p-beta",
+                "keystone/paged/projects.p-beta.json",
+            ),
+        ]);
+        let projects = test_session(&url).project_mappings().unwrap();
+        assert_eq!(projects.iter().count(), 4);
+        assert_eq!(projects.get("p-local").unwrap().name, "local-proj");
+    }
+
+    #[test]
+    fn domains_follow_pagination() {
+        let url = serve_fixtures(&[
+            ("/domains/", "keystone/paged/domains.json"),
+            (
+                "/domains/?marker=d-snic",
+                "keystone/paged/domains.d-snic.json",
+            ),
+        ]);
+        let domains = test_session(&url).domains().unwrap();
+        let names: Vec<_> = domains
+            .domains
+            .iter()
+            .map(|d| (d.id.as_str(), d.name.as_str(), d.enabled))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("default", "Default", true),
+                ("d-snic", "snic", true),
+                ("d-retired", "retired", false),
+            ]
+        );
+        assert!(domains.links.next.is_none());
+    }
+
+    #[test]
+    fn service_urls_from_catalog() {
+        let endpoint = |url: &str, region: &str, interface: &str| json!({"url": url, "region": region, "interface": interface});
+        let body = json!({"token": {"catalog": [
+            {"name": "nova", "type": "compute", "endpoints": [
+                endpoint("https://nova.public/v2.1", "north-1", "public"),
+                endpoint("https://nova.admin:8774/v2.1", "north-1", "admin"),
+            ]},
+            {"name": "cinderv3", "type": "volumev3", "endpoints": [
+                endpoint("https://cinder.admin:8776/v3/p1", "north-1", "admin"),
+            ]},
+            {"name": "glance", "type": "image", "endpoints": [
+                endpoint("https://glance.south:9292", "south-1", "admin"),
+                endpoint("https://glance.admin:9292", "north-1", "admin"),
+            ]},
+        ]}})
+        .to_string();
+
+        let urls = ServiceUrls::from_token_body(&body, "north-1", "admin", None).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://nova.admin:8774/v2.1/");
+        assert_eq!(urls.cinder_url.as_str(), "https://cinder.admin:8776/v3/p1/");
+        assert_eq!(urls.glance_url.as_str(), "https://glance.admin:9292/");
+        assert!(urls.swift_url.is_none());
+
+        let rewrite = HostRewrite::default();
+        let urls = ServiceUrls::from_token_body(&body, "north-1", "admin", Some(&rewrite)).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://localhost:8774/v2.1/");
+
+        let rewrite = HostRewrite {
+            host: "127.0.0.1".to_owned(),
+            ports: vec![("cinder".to_owned(), 18776)].into_iter().collect(),
+        };
+        let urls = ServiceUrls::from_token_body(&body, "north-1", "admin", Some(&rewrite)).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://127.0.0.1:8774/v2.1/");
+        assert_eq!(urls.cinder_url.as_str(), "https://127.0.0.1:18776/v3/p1/");
+        assert_eq!(urls.glance_url.as_str(), "https://127.0.0.1:9292/");
 
-            Ok(ret)
+        assert!(ServiceUrls::from_token_body(&body, "south-1", "admin", None).is_err());
+    }
+
+    #[test]
+    fn duplicate_catalog_endpoints() {
+        let endpoint = |url: &str| json!({"url": url, "region": "north-1", "interface": "admin"});
+        let catalog = |nova: Vec<serde_json::Value>| {
+            json!({"token": {"catalog": [
+                {"name": "nova", "type": "compute", "endpoints": nova},
+                {"name": "nova", "type": "compute", "endpoints": [
+                    endpoint("https://nova.admin:8774/v2.1/"),
+                ]},
+                {"name": "cinderv3", "type": "volumev3", "endpoints": [
+                    endpoint("https://cinder.admin:8776/v3/p1"),
+                ]},
+                {"name": "glance", "type": "image", "endpoints": [
+                    endpoint("https://glance.admin:9292"),
+                ]},
+            ]}})
+            .to_string()
+        };
+
+        // The same endpoint listed twice is harmless.
+        let body = catalog(vec![endpoint("https://nova.admin:8774/v2.1")]);
+        let urls = ServiceUrls::from_token_body(&body, "north-1", "admin", None).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://nova.admin:8774/v2.1/");
+
+        let body = catalog(vec![endpoint("https://nova-old.admin:8774/v2.1")]);
+        let err = ServiceUrls::from_token_body(&body, "north-1", "admin", None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("https://nova-old.admin:8774/v2.1/"), "{}", err);
+        assert!(err.contains("https://nova.admin:8774/v2.1/"), "{}", err);
+    }
+
+    #[test]
+    fn service_urls_from_other_region() {
+        let endpoint = |url: &str, region: &str, interface: &str| json!({"url": url, "region": region, "interface": interface});
+        let service = |name: &str, typ: &str, host: &str| {
+            json!({"name": name, "type": typ, "endpoints": [
+                endpoint(&format!("https://{}.north", host), "north-1", "admin"),
+                endpoint(&format!("https://{}.north-int", host), "north-1", "internal"),
+                endpoint(&format!("https://{}.south", host), "south-1", "admin"),
+                endpoint(&format!("https://{}.south-int", host), "south-1", "internal"),
+            ]})
+        };
+        let body = json!({"token": {"catalog": [
+            service("keystone", "identity", "keystone"),
+            service("nova", "compute", "nova"),
+            service("cinderv3", "volumev3", "cinder"),
+            service("glance", "image", "glance"),
+        ]}})
+        .to_string();
+
+        let urls = ServiceUrls::from_token_body(&body, "south-1", "admin", None).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://nova.south/");
+        assert_eq!(urls.cinder_url.as_str(), "https://cinder.south/");
+        assert_eq!(urls.glance_url.as_str(), "https://glance.south/");
+
+        let urls = ServiceUrls::from_token_body(&body, "south-1", "internal", None).unwrap();
+        assert_eq!(urls.nova_url.as_str(), "https://nova.south-int/");
+        assert_eq!(urls.glance_url.as_str(), "https://glance.south-int/");
+
+        let urls = ServiceUrls::from_token_body(&body, "north-1", "internal", None).unwrap();
+        assert_eq!(urls.cinder_url.as_str(), "https://cinder.north-int/");
+    }
+
+    #[test]
+    fn keystone_api_url_with_and_without_version() {
+        for base in &[
+            "https://ks/v3",
+            "https://ks/v3/",
+            "https://ks",
+            "https://ks/",
+        ] {
+            let url = keystone_api_url(&Url::parse(base).unwrap(), DEFAULT_KEYSTONE_API_PATH);
+            assert_eq!(
+                url.join("auth/tokens/").unwrap().as_str(),
+                "https://ks/v3/auth/tokens/",
+                "{}",
+                base
+            );
+        }
+        for base in &["https://host/identity", "https://host/identity/v3/"] {
+            let url = keystone_api_url(&Url::parse(base).unwrap(), "v3");
+            assert_eq!(url.as_str(), "https://host/identity/v3/", "{}", base);
+        }
+        let url = keystone_api_url(&Url::parse("https://host:5000").unwrap(), "identity/v3");
+        assert_eq!(url.as_str(), "https://host:5000/identity/v3/");
+    }
+
+    #[test]
+    fn auth_payload_with_split_domains() {
+        let creds = Credentials {
+            username: "billing".to_owned(),
+            password: "secret".to_owned(),
+            user_domain: "users".to_owned(),
+            project_domain: "snic".to_owned(),
+            project: "admin".to_owned(),
+            auth_flow: AuthFlow::Scoped,
+        };
+        let payload: serde_json::Value =
+            serde_json::from_str(&Session::auth_scoped_payload(&creds)).unwrap();
+        assert_eq!(
+            payload,
+            json!({"auth": {
+                "identity": {
+                    "methods": ["password"],
+                    "password": {
+                        "user": {
+                            "name": "billing",
+                            "password": "secret",
+                            "domain": {"id": "users"},
+                        }
+                    }
+                },
+                "scope": {
+                    "project": {
+                        "domain": {"id": "snic"},
+                        "name": "admin"
+                    }
+                }
+            }})
+        );
+    }
+
+    #[test]
+    fn auth_payloads_for_rescope_flow() {
+        let creds = Credentials {
+            username: "billing".to_owned(),
+            password: "secret".to_owned(),
+            user_domain: "users".to_owned(),
+            project_domain: "snic".to_owned(),
+            project: "admin".to_owned(),
+            auth_flow: AuthFlow::Rescope,
+        };
+        let unscoped: serde_json::Value =
+            serde_json::from_str(&Session::auth_unscoped_payload(&creds)).unwrap();
+        assert_eq!(
+            unscoped,
+            json!({"auth": {
+                "identity": {
+                    "methods": ["password"],
+                    "password": {
+                        "user": {
+                            "name": "billing",
+                            "password": "secret",
+                            "domain": {"id": "users"},
+                        }
+                    }
+                }
+            }})
+        );
+
+        let rescope: serde_json::Value =
+            serde_json::from_str(&Session::auth_rescope_payload("unscoped-token", "p1")).unwrap();
+        assert_eq!(
+            rescope,
+            json!({"auth": {
+                "identity": {
+                    "methods": ["token"],
+                    "token": {"id": "unscoped-token"}
+                },
+                "scope": {
+                    "project": {"id": "p1"}
+                }
+            }})
+        );
+    }
+
+    #[test]
+    fn flavor_allocated_disk_includes_ephemeral_and_swap() {
+        let flavor: nova::Flavor = serde_json::from_str(
+            r#"{
+                "id": "3",
+                "name": "ssc.medium",
+                "vcpus": 2,
+                "ram": 4096,
+                "disk": 20,
+                "OS-FLV-EXT-DATA:ephemeral": 10,
+                "swap": 512
+            }"#,
+        )
+        .unwrap();
+        let expected = 30 * 1024u64.pow(3) + 512 * 1024u64.pow(2);
+        assert_eq!(flavor.allocated_disk(), crate::units::Bytes(expected));
+    }
+
+    #[test]
+    fn flavor_without_ephemeral_or_swap() {
+        let flavor: nova::Flavor = serde_json::from_str(
+            r#"{
+                "id": "1",
+                "name": "ssc.tiny",
+                "vcpus": 1,
+                "ram": 1024,
+                "disk": 10,
+                "swap": ""
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(flavor.ephemeral, 0);
+        assert_eq!(flavor.swap, 0);
+        assert_eq!(
+            flavor.allocated_disk(),
+            crate::units::Bytes(10 * 1024u64.pow(3))
+        );
+    }
+
+    #[test]
+    fn volume_owner_from_tenant_attribute() {
+        let volume: cinder::Volume = serde_json::from_str(
+            r#"{
+                "id": "41d169a8-e2e8-4e81-a8d0-6fda07316251",
+                "size": 10,
+                "user_id": "b2e8e5c3f1a84a8c9c4b1a6f0e2d3c4b",
+                "os-vol-tenant-attr:tenant_id": "7d4b838241d9486e972bf1b371cc8718",
+                "availability_zone": "nova"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(volume.owner(), Some("7d4b838241d9486e972bf1b371cc8718"));
+    }
+
+    #[test]
+    fn volume_owner_from_project_id() {
+        let volume: cinder::Volume = serde_json::from_str(
+            r#"{
+                "id": "41d169a8-e2e8-4e81-a8d0-6fda07316251",
+                "size": 10,
+                "user_id": "b2e8e5c3f1a84a8c9c4b1a6f0e2d3c4b",
+                "project_id": "7d4b838241d9486e972bf1b371cc8718",
+                "availability_zone": "nova"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(volume.owner(), Some("7d4b838241d9486e972bf1b371cc8718"));
+    }
+
+    #[test]
+    fn unknown_volume_fields_only_reported_when_strict() {
+        let volume: cinder::Volume = serde_json::from_str(
+            r#"{
+                "id": "41d169a8-e2e8-4e81-a8d0-6fda07316251",
+                "size": 10,
+                "user_id": "b2e8e5c3f1a84a8c9c4b1a6f0e2d3c4b",
+                "project_id": "7d4b838241d9486e972bf1b371cc8718",
+                "os-vol-tenant-attr:project_id": "7d4b838241d9486e972bf1b371cc8718",
+                "availability_zone": "nova",
+                "name": "data",
+                "links": []
+            }"#,
+        )
+        .unwrap();
+        let unexpected = crate::strict::check_fields(&[volume]);
+        let keys: Vec<_> = unexpected.keys().collect();
+        if cfg!(feature = "strict") {
+            assert_eq!(
+                keys,
+                [("openstack::cinder::Volume", "os-vol-tenant-attr:project_id")]
+            );
         } else {
-            Ok(vec![])
+            assert!(keys.is_empty());
         }
     }
+
+    #[test]
+    fn unknown_server_fields_reported_by_type() {
+        let server = |id: &str| {
+            json!({
+                "id": id,
+                "user_id": "u1",
+                "tenant_id": "p1",
+                "name": "web",
+                "flavor": {"id": "f1", "links": [], "original_name": "m1.small"},
+                "image": "",
+                "status": "ACTIVE",
+                "addresses": {},
+                "metadata": {},
+                "OS-EXT-STS:vm_state": "active",
+                "OS-EXT-AZ:availability_zone": "nova",
+                "os-extended-volumes:volumes_attached": [
+                    {"id": "v1", "delete_on_termination": false}
+                ],
+                "hw:cpu_policy": "dedicated",
+            })
+        };
+        let servers: nova::Servers =
+            serde_json::from_value(json!({"servers": [server("s1"), server("s2")]})).unwrap();
+        let unexpected = crate::strict::check_fields(&servers.servers);
+        let keys: Vec<_> = unexpected.keys().collect();
+        if cfg!(feature = "strict") {
+            assert_eq!(
+                keys,
+                [
+                    ("openstack::nova::Server", "hw:cpu_policy"),
+                    ("openstack::nova::ServerFlavor", "original_name"),
+                ]
+            );
+        } else {
+            assert!(keys.is_empty());
+        }
+    }
+
+    #[test]
+    fn volume_without_owner() {
+        let volume: cinder::Volume = serde_json::from_str(
+            r#"{
+                "id": "41d169a8-e2e8-4e81-a8d0-6fda07316251",
+                "size": 10,
+                "user_id": "b2e8e5c3f1a84a8c9c4b1a6f0e2d3c4b",
+                "availability_zone": "nova"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(volume.owner(), None);
+    }
+
+    #[test]
+    fn reauthenticates_on_expiry_and_rejected_token() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        let auths = Arc::new(AtomicUsize::new(0));
+        let served_auths = auths.clone();
+        let (listener, url) = local_listener();
+        serve_requests(listener, move |method, target, headers| {
+            match (method, target) {
+                ("POST", "/auth/tokens/") => {
+                    let n = served_auths.fetch_add(1, Ordering::SeqCst) + 1;
+                    let expires_at = Utc::now() + chrono::Duration::hours(1);
+                    json_response(
+                        "201 Created",
+                        &format!("X-Subject-Token: fresh-{}\r\n", n),
+                        &json!({"token": {"expires_at": expires_at, "catalog": []}}).to_string(),
+                    )
+                }
+                ("GET", "/domains/") => {
+                    if headers
+                        .iter()
+                        .any(|h| h.starts_with("x-auth-token: fresh-"))
+                    {
+                        json_response("200 OK", "", r#"{"domains": []}"#)
+                    } else {
+                        json_response("401 Unauthorized", "", "{}")
+                    }
+                }
+                _ => NOT_FOUND.to_owned(),
+            }
+        });
+
+        // A token that Keystone no longer accepts is replaced and the request retried.
+        let session = test_session(&url);
+        session.domains().unwrap();
+        assert_eq!(auths.load(Ordering::SeqCst), 1);
+        assert_eq!(session.auth_token.borrow().token, "fresh-1");
+        session.domains().unwrap();
+        assert_eq!(auths.load(Ordering::SeqCst), 1);
+
+        // A token about to expire is replaced before it is used.
+        session.auth_token.borrow_mut().expires_at =
+            Some(Utc::now() + chrono::Duration::seconds(30));
+        session.domains().unwrap();
+        assert_eq!(auths.load(Ordering::SeqCst), 2);
+        assert_eq!(session.auth_token.borrow().token, "fresh-2");
+    }
+
+    #[test]
+    fn reauthenticates_at_most_once_per_request() {
+        let (listener, url) = local_listener();
+        serve_requests(listener, |method, target, _| match (method, target) {
+            ("POST", "/auth/tokens/") => json_response(
+                "201 Created",
+                "X-Subject-Token: also-rejected\r\n",
+                r#"{"token": {"catalog": []}}"#,
+            ),
+            ("GET", "/domains/") => json_response("401 Unauthorized", "", "{}"),
+            _ => NOT_FOUND.to_owned(),
+        });
+        let session = test_session(&url);
+        assert!(session.domains().is_err());
+        assert_eq!(session.auth_token.borrow().token, "also-rejected");
+    }
+
+    #[test]
+    fn hung_keystone_times_out() {
+        let (listener, url) = local_listener();
+        std::thread::spawn(move || {
+            // Accept connections but never answer them.
+            let _held: Vec<_> = listener.incoming().collect();
+        });
+        let creds = test_session(&url).credentials.clone();
+        let started = std::time::Instant::now();
+        let res = Session::new(
+            &creds,
+            &url,
+            DEFAULT_KEYSTONE_API_PATH,
+            "north-1",
+            DEFAULT_ENDPOINT_INTERFACE,
+            None,
+            DEFAULT_USER_AGENT,
+            Some(std::time::Duration::from_millis(200)),
+        );
+        assert!(res.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn transient_failures_retried() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served_requests = requests.clone();
+        let (listener, url) = local_listener();
+        serve_requests(listener, move |_, target, _| {
+            let n = served_requests.fetch_add(1, Ordering::SeqCst) + 1;
+            match target {
+                "/domains/" if n <= 2 => json_response("503 Service Unavailable", "", "{}"),
+                "/domains/" => json_response("200 OK", "", r#"{"domains": []}"#),
+                _ => NOT_FOUND.to_owned(),
+            }
+        });
+        let retry = |max_attempts| RetryPolicy {
+            max_attempts,
+            base_delay: std::time::Duration::from_millis(1),
+        };
+
+        let session = test_session(&url).with_retry_policy(retry(2));
+        assert!(session.domains().is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        requests.store(0, Ordering::SeqCst);
+        let session = test_session(&url).with_retry_policy(retry(3));
+        session.domains().unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Client errors are not retried.
+        requests.store(0, Ordering::SeqCst);
+        assert!(session.flavors().is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        let retry = RetryPolicy::default();
+        assert_eq!(retry.delay(1), std::time::Duration::from_millis(500));
+        assert_eq!(retry.delay(2), std::time::Duration::from_millis(1000));
+        assert_eq!(retry.delay(3), std::time::Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn swift_account_of_project() {
+        let url = Url::parse("http://swift:8080/v1/AUTH_admin/").unwrap();
+        assert_eq!(
+            swift_account_url(&url, "p1").as_str(),
+            "http://swift:8080/v1/AUTH_p1"
+        );
+        let url = Url::parse("http://swift:8080/v1/").unwrap();
+        assert_eq!(
+            swift_account_url(&url, "p1").as_str(),
+            "http://swift:8080/v1/AUTH_p1"
+        );
+    }
+
+    #[test]
+    fn containers_follow_markers() {
+        let url = serve_fixtures(&[
+            ("/v1/AUTH_p1?format=json&limit=2", "swift/containers.json"),
+            (
+                "/v1/AUTH_p1?format=json&limit=2&marker=empty",
+                "swift/containers.empty.json",
+            ),
+            (
+                "/v1/AUTH_p1?format=json&limit=2&marker=results",
+                "swift/containers.results.json",
+            ),
+        ]);
+        let mut session = test_session(&url);
+        assert!(session.containers_paged("p1", 2).unwrap().is_empty());
+
+        session.swift_url = Some(url.join("v1/AUTH_admin/").unwrap());
+        let containers = session.containers_paged("p1", 2).unwrap();
+        let names: Vec<&str> = containers.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["backups", "empty", "results"]);
+        assert_eq!(containers[0].bytes, 1048576);
+        assert_eq!(
+            containers[0].last_modified.unwrap().to_rfc3339(),
+            "2019-02-13T11:20:31.460230+00:00"
+        );
+    }
 }
@@ -0,0 +1,380 @@
+//! Asynchronous counterpart of [`Session`](super::Session) built on the async `reqwest` client,
+//! for use from within an async runtime. Returns the same types as the blocking session.
+//!
+//! This is the plain fetching of the blocking session only: the token is not renewed when it
+//! expires or is rejected, failed requests are not retried, Swift containers are not listed, and
+//! responses are read into memory before they are decoded.
+
+use super::{
+    cinder, decode, glance, keystone, keystone_api_url, nova, quota, rescope_project, AuthFlow,
+    Credentials, Flavors, HostRewrite, NameMapping, ServiceUrls, NOVA_FLAVORS_MICROVERSION,
+    NOVA_SERVERS_MICROVERSION,
+};
+use chrono::{DateTime, Utc};
+use reqwest::header::CONTENT_TYPE;
+use url::Url;
+
+#[derive(Debug)]
+pub struct Session {
+    client: reqwest::Client,
+    auth_token: String,
+    keystone_url: Url,
+    nova_url: Url,
+    cinder_url: Url,
+    glance_url: Url,
+}
+
+impl Session {
+    /// Posts an authentication request, returning the issued token and the response body.
+    async fn request_token(
+        client: &reqwest::Client,
+        keystone_url: &Url,
+        payload: String,
+    ) -> Result<(String, String), failure::Error> {
+        let res = client
+            .post(keystone_url.join("auth/tokens/")?.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload)
+            .send()
+            .await?;
+        trace!("{:?}", res);
+        if !res.status().is_success() {
+            bail!("Could not authenticate with Keystone: {}", res.status());
+        }
+        let token = res
+            .headers()
+            .get("X-Subject-Token")
+            .ok_or(format_err!("Keystone did not return a token"))?
+            .to_str()?
+            .to_owned();
+        Ok((token, res.text().await?))
+    }
+
+    /// Obtains a project-scoped token by way of an unscoped one, see `AuthFlow::Rescope`.
+    async fn rescoped_token(
+        client: &reqwest::Client,
+        keystone_url: &Url,
+        creds: &Credentials,
+    ) -> Result<(String, String), failure::Error> {
+        let (unscoped_token, _) = Session::request_token(
+            client,
+            keystone_url,
+            super::Session::auth_unscoped_payload(creds),
+        )
+        .await?;
+        let res = client
+            .get(keystone_url.join("auth/projects")?.as_str())
+            .header("X-Auth-Token", unscoped_token.as_str())
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            bail!("Could not list projects available for rescoping");
+        }
+        let projects: keystone::Projects = serde_json::from_str(&res.text().await?)?;
+        let project = rescope_project(&projects, creds)?;
+        Session::request_token(
+            client,
+            keystone_url,
+            super::Session::auth_rescope_payload(&unscoped_token, &project.id),
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        creds: &Credentials,
+        keystone_url: &Url,
+        keystone_api_path: &str,
+        region: &str,
+        interface: &str,
+        rewrite_host: Option<&HostRewrite>,
+        user_agent: &str,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Session, failure::Error> {
+        let keystone_url = keystone_api_url(keystone_url, keystone_api_path);
+        let mut builder = reqwest::Client::builder().user_agent(user_agent).gzip(true);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        let client = builder.build()?;
+        let (admin_scoped_token, body) = match creds.auth_flow {
+            AuthFlow::Scoped => {
+                Session::request_token(
+                    &client,
+                    &keystone_url,
+                    super::Session::auth_scoped_payload(creds),
+                )
+                .await?
+            }
+            AuthFlow::Rescope => Session::rescoped_token(&client, &keystone_url, creds).await?,
+        };
+        let ServiceUrls {
+            nova_url,
+            cinder_url,
+            glance_url,
+            ..
+        } = ServiceUrls::from_token_body(&body, region, interface, rewrite_host)?;
+        trace!("Admin scoped token: {}", admin_scoped_token);
+
+        Ok(Session {
+            client,
+            auth_token: admin_scoped_token,
+            keystone_url,
+            nova_url,
+            cinder_url,
+            glance_url,
+        })
+    }
+
+    /// Retrieves and parses `url`, describing it as `what` if the request fails.
+    async fn fetch<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &Url,
+        what: &str,
+        debug_filename: &str,
+    ) -> Result<T, failure::Error> {
+        let res = self
+            .client
+            .get(url.as_str())
+            .header("X-Auth-Token", self.auth_token.as_str())
+            .send()
+            .await?;
+        trace!("{:?}", &res);
+        if !res.status().is_success() {
+            bail!("Could not retrieve {}: {}", what, res.status());
+        }
+        decode(&res.bytes().await?[..], debug_filename)
+    }
+
+    /// Retrieves and parses a Nova listing at the given microversion.
+    async fn fetch_nova<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &Url,
+        microversion: &str,
+        what: &str,
+        debug_filename: &str,
+    ) -> Result<T, failure::Error> {
+        let res = self
+            .client
+            .get(url.as_str())
+            .header("X-Auth-Token", self.auth_token.as_str())
+            .header("X-OpenStack-Nova-API-Version", microversion)
+            .send()
+            .await?;
+        trace!("{:?}", &res);
+        if !res.status().is_success() {
+            bail!("Could not retrieve {}: {}", what, res.status());
+        }
+        decode(&res.bytes().await?[..], debug_filename)
+    }
+
+    /// Retrieves a Nova server listing at the microversion that includes tags.
+    async fn fetch_servers(
+        &self,
+        url: &Url,
+        what: &str,
+        debug_filename: &str,
+    ) -> Result<Vec<nova::Server>, failure::Error> {
+        let servers: nova::Servers = self
+            .fetch_nova(url, NOVA_SERVERS_MICROVERSION, what, debug_filename)
+            .await?;
+        crate::strict::check_fields(&servers.servers);
+        Ok(servers.servers)
+    }
+
+    /// Retrieves all pages of a Keystone listing at `path`, following the `next` links.
+    async fn keystone_list<T: keystone::Page>(
+        &self,
+        path: &str,
+        what: &str,
+        debug_filename: &str,
+    ) -> Result<T, failure::Error> {
+        let mut url = self.keystone_url.join(path)?;
+        let mut list: Option<T> = None;
+        loop {
+            let page: T = self.fetch(&url, what, debug_filename).await?;
+            let next = page.next().cloned();
+            match &mut list {
+                Some(list) => list.append(page),
+                None => list = Some(page),
+            }
+            match next {
+                Some(next) if next != url => url = next,
+                _ => break,
+            }
+        }
+        if let Some(list) = &list {
+            list.check_fields();
+        }
+        Ok(list.unwrap())
+    }
+
+    pub async fn user_mappings(&self) -> Result<NameMapping, failure::Error> {
+        let users: keystone::Users = self
+            .keystone_list("users/", "users from Keystone", "users.json")
+            .await?;
+        Ok(users.into())
+    }
+
+    pub async fn project_mappings(&self) -> Result<NameMapping, failure::Error> {
+        let projects: keystone::Projects = self
+            .keystone_list("projects/", "projects from Keystone", "projects.json")
+            .await?;
+        Ok(projects.into())
+    }
+
+    pub async fn domains(&self) -> Result<keystone::Domains, failure::Error> {
+        self.keystone_list("domains/", "domains from Keystone", "domains.json")
+            .await
+    }
+
+    pub async fn flavors(&self) -> Result<Flavors, failure::Error> {
+        let url = self.nova_url.join("flavors/detail?is_public=None")?;
+        let flavors: nova::Flavors = self
+            .fetch_nova(
+                &url,
+                NOVA_FLAVORS_MICROVERSION,
+                "flavors from Nova",
+                "flavors.json",
+            )
+            .await?;
+        crate::strict::check_fields(&flavors.flavors);
+        Ok(flavors.into())
+    }
+
+    pub async fn images(&self) -> Result<Vec<glance::Image>, failure::Error> {
+        let base_url = self.glance_url.join("v2/images")?;
+        let mut url = base_url.clone();
+
+        let mut ret = Vec::new();
+        loop {
+            let mut images: glance::Images = self
+                .fetch(&url, "images from Glance", "images.json")
+                .await?;
+            ret.append(&mut images.images);
+            match images.next {
+                Some(next) => url = base_url.join(&next)?,
+                None => break,
+            }
+        }
+
+        Ok(ret)
+    }
+
+    pub async fn volumes(&self) -> Result<Vec<cinder::Volume>, failure::Error> {
+        let mut url = self.cinder_url.join("volumes/detail?all_tenants=1")?;
+
+        let mut ret = Vec::new();
+        loop {
+            let mut volumes: cinder::Volumes = self
+                .fetch(&url, "volumes from Cinder", "volumes.json")
+                .await?;
+            crate::strict::check_fields(&volumes.volumes);
+            crate::strict::check_fields(&volumes.links);
+            ret.append(&mut volumes.volumes);
+            match volumes.links.into_iter().find(|lnk| lnk.rel == "next") {
+                Some(next) => url = next.href,
+                None => break,
+            }
+        }
+
+        Ok(ret)
+    }
+
+    /// Obtain a list of servers from the API.
+    pub async fn servers(&self) -> Result<Vec<nova::Server>, failure::Error> {
+        let mut url = self.nova_url.join("servers/detail")?;
+        url.query_pairs_mut().append_pair("all_tenants", "True");
+        self.fetch_servers(&url, "instances from Nova", "servers.json")
+            .await
+    }
+
+    /// Obtain a list of servers deleted since the given time, including their termination time.
+    pub async fn deleted_servers(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<nova::Server>, failure::Error> {
+        let mut url = self.nova_url.join("servers/detail")?;
+        url.query_pairs_mut()
+            .append_pair("all_tenants", "True")
+            .append_pair("deleted", "True")
+            .append_pair("changes-since", &since.to_rfc3339());
+        self.fetch_servers(&url, "deleted instances from Nova", "deleted_servers.json")
+            .await
+    }
+
+    /// Retrieves the Nova and Cinder quota of a project.
+    pub async fn project_quota(
+        &self,
+        project_id: &str,
+    ) -> Result<quota::ProjectQuota, failure::Error> {
+        let path = format!("os-quota-sets/{}", project_id);
+        let compute: quota::QuotaSetResponse<quota::ComputeQuota> = self
+            .fetch(
+                &self.nova_url.join(&path)?,
+                "compute quota",
+                "compute_quota.json",
+            )
+            .await?;
+        let volume: quota::QuotaSetResponse<quota::VolumeQuota> = self
+            .fetch(
+                &self.cinder_url.join(&path)?,
+                "volume quota",
+                "volume_quota.json",
+            )
+            .await?;
+        Ok(quota::ProjectQuota {
+            cores: compute.quota_set.cores,
+            ram: compute.quota_set.ram,
+            gigabytes: volume.quota_set.gigabytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::serve_fixtures;
+
+    /// A session against `keystone_url` with the other services on the same host.
+    fn test_session(keystone_url: &Url) -> Session {
+        Session {
+            client: reqwest::Client::new(),
+            auth_token: "token".to_owned(),
+            keystone_url: keystone_url.clone(),
+            nova_url: keystone_url.clone(),
+            cinder_url: keystone_url.clone(),
+            glance_url: keystone_url.clone(),
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn users_follow_pagination() {
+        let url = serve_fixtures(&[
+            ("/users/", "keystone/paged/users.json"),
+            (
+                "/users/?marker=u-alice",
+                "keystone/paged/users.u-alice.json",
+            ),
+        ]);
+        let users = block_on(test_session(&url).user_mappings()).unwrap();
+        let mut names: Vec<_> = users.iter().map(|(_, user)| user.name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["admin", "bob", "s1234"]);
+    }
+
+    #[test]
+    fn failed_listing_is_an_error() {
+        let url = serve_fixtures(&[]);
+        let err = block_on(test_session(&url).domains()).unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+    }
+}
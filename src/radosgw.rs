@@ -1,8 +1,11 @@
 extern crate failure;
 
 pub mod admin {
+    use crate::units::{Bytes, Kilobytes};
+    use chrono::{DateTime, NaiveDateTime, Utc};
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
+    use url::Url;
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct BucketStats {
@@ -18,6 +21,75 @@ pub mod admin {
         pub max_marker: String,
         pub usage: HashMap<String, BucketStatsUsage>,
         pub bucket_quota: BucketStatsBucketQuota,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    impl crate::strict::KnownFields for BucketStats {
+        const IGNORED: &'static [&'static str] = &[
+            "creation_time",
+            "explicit_placement",
+            "index_type",
+            "mfa_enabled",
+            "num_shards",
+            "object_lock_enabled",
+            "placement_rule",
+            "tenant",
+            "versioned",
+            "versioning",
+            "zonegroup",
+        ];
+
+        #[cfg(feature = "strict")]
+        fn unknown_fields(&self) -> &crate::strict::UnknownFields {
+            &self.unknown
+        }
+
+        #[cfg(feature = "strict")]
+        fn check_nested(&self, unexpected: &mut crate::strict::UnexpectedFields) {
+            for usage in self.usage.values() {
+                unexpected.check(usage);
+            }
+            unexpected.check(&self.bucket_quota);
+        }
+    }
+
+    /// Usage categories that never hold object data, `rgw.none` only counts deleted entries.
+    pub const DEFAULT_EXCLUDED_USAGE_CATEGORIES: &[&str] = &["rgw.none"];
+
+    impl BucketStats {
+        /// Total size of the bucket's usage categories except `excluded` ones, or `None` if there
+        /// is no usage left to bill.
+        pub fn billable_size<S: AsRef<str>>(&self, excluded: &[S]) -> Option<Bytes> {
+            let mut usages = self.billable_usages(excluded).peekable();
+            usages.peek()?;
+            Some(usages.map(|u| Bytes::from(Kilobytes(u.size_kb))).sum())
+        }
+
+        /// `mtime` as a time, or `None` if it is not in the `2019-02-13 12:00:00.000000Z` format.
+        pub fn modified_at(&self) -> Option<DateTime<Utc>> {
+            NaiveDateTime::parse_from_str(&self.mtime, "%Y-%m-%d %H:%M:%S%.fZ")
+                .ok()
+                .map(|naive| DateTime::from_utc(naive, Utc))
+        }
+
+        /// Number of objects in the bucket's usage categories except `excluded` ones.
+        pub fn billable_objects<S: AsRef<str>>(&self, excluded: &[S]) -> u64 {
+            self.billable_usages(excluded).map(|u| u.num_objects).sum()
+        }
+
+        fn billable_usages<'a, S: AsRef<str>>(
+            &'a self,
+            excluded: &'a [S],
+        ) -> impl Iterator<Item = &'a BucketStatsUsage> {
+            self.usage
+                .iter()
+                .filter(move |(category, _)| !excluded.iter().any(|e| e.as_ref() == *category))
+                .map(|(_, usage)| usage)
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -25,13 +97,122 @@ pub mod admin {
         pub size_kb: u64,
         pub size_kb_actual: u64,
         pub num_objects: u64,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
     }
 
+    known_fields!(
+        BucketStatsUsage,
+        ["size", "size_actual", "size_kb_utilized", "size_utilized"]
+    );
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct BucketStatsBucketQuota {
         pub enabled: bool,
         pub max_size_kb: i64,
         pub max_objects: i64,
+
+        /// Keys not modelled above, kept to notice changes in the API.
+        #[cfg(feature = "strict")]
+        #[serde(flatten, skip_serializing)]
+        pub unknown: crate::strict::UnknownFields,
+    }
+
+    known_fields!(BucketStatsBucketQuota, ["check_on_raw", "max_size"]);
+
+    /// Where bucket statistics are read from.
+    #[derive(Debug)]
+    pub enum BucketStatsSource {
+        /// Running `radosgw-admin bucket stats`, which needs to happen on a cluster host.
+        Subprocess,
+        /// The admin ops API of a radosgw.
+        AdminApi(AdminApi),
+    }
+
+    impl BucketStatsSource {
+        pub fn bucket_stats(&self) -> Result<Vec<BucketStats>, failure::Error> {
+            match self {
+                BucketStatsSource::Subprocess => bucket_stats(),
+                BucketStatsSource::AdminApi(api) => api.bucket_stats(),
+            }
+        }
+    }
+
+    /// Client of the radosgw admin ops API, authenticating as a user with `buckets=read` caps.
+    #[derive(Debug)]
+    pub struct AdminApi {
+        client: reqwest::blocking::Client,
+        endpoint: Url,
+        access_key: String,
+        secret_key: String,
+    }
+
+    impl AdminApi {
+        pub fn new(
+            endpoint: &Url,
+            access_key: &str,
+            secret_key: &str,
+            timeout: Option<std::time::Duration>,
+        ) -> Result<AdminApi, failure::Error> {
+            let mut builder = reqwest::blocking::Client::builder();
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            let mut endpoint = endpoint.clone();
+            endpoint
+                .path_segments_mut()
+                .unwrap()
+                .pop_if_empty()
+                .push("");
+            Ok(AdminApi {
+                client: builder.build()?,
+                endpoint,
+                access_key: access_key.to_owned(),
+                secret_key: secret_key.to_owned(),
+            })
+        }
+
+        /// Statistics of all buckets, from `GET /admin/bucket?stats=true`.
+        pub fn bucket_stats(&self) -> Result<Vec<BucketStats>, failure::Error> {
+            let mut url = self.endpoint.join("admin/bucket")?;
+            url.query_pairs_mut()
+                .append_pair("stats", "true")
+                .append_pair("format", "json");
+            let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            let string_to_sign = format!("GET\n\n\n{}\n{}", date, url.path());
+            let signature = sign_v2(&self.secret_key, &string_to_sign)?;
+            let res = self
+                .client
+                .get(url.as_str())
+                .header("Date", date.as_str())
+                .header(
+                    "Authorization",
+                    format!("AWS {}:{}", self.access_key, signature),
+                )
+                .send()?;
+            if !res.status().is_success() {
+                bail!(
+                    "Could not retrieve bucket stats from radosgw: {}",
+                    res.status()
+                );
+            }
+            let output = res.text()?;
+            trace!("{}", output);
+            dump_bucket_stats(&output)?;
+            parse_bucket_stats(&output)
+        }
+    }
+
+    /// Signature of an AWS signature version 2 request, the base64 HMAC-SHA1 of `string_to_sign`.
+    pub fn sign_v2(secret_key: &str, string_to_sign: &str) -> Result<String, failure::Error> {
+        use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+        let key = PKey::hmac(secret_key.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+        signer.update(string_to_sign.as_bytes())?;
+        Ok(openssl::base64::encode_block(&signer.sign_to_vec()?))
     }
 
     pub fn bucket_stats() -> Result<Vec<BucketStats>, failure::Error> {
@@ -40,8 +221,29 @@ pub mod admin {
             .capture()?
             .stdout_str();
         trace!("{}", output);
-        // std::fs::write("bucket_stats.json", &output).unwrap();
-        let statses: Vec<BucketStats> = serde_json::from_str(&output)?;
+        dump_bucket_stats(&output)?;
+        parse_bucket_stats(&output)
+    }
+
+    /// Writes the raw statistics to `bucket_stats.json` if `SBL_DUMP_OS_JSON` is set, like the
+    /// OpenStack responses.
+    fn dump_bucket_stats(output: &str) -> Result<(), failure::Error> {
+        if crate::openstack::should_write_debug_json() {
+            std::fs::write("bucket_stats.json", output)
+                .map_err(|e| format_err!("Could not write bucket_stats.json: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Parses `radosgw-admin bucket stats` output. Some versions print nothing or an empty object
+    /// instead of an empty list when there are no buckets.
+    pub fn parse_bucket_stats(output: &str) -> Result<Vec<BucketStats>, failure::Error> {
+        let output = output.trim();
+        if output.is_empty() || output == "{}" {
+            return Ok(vec![]);
+        }
+        let statses: Vec<BucketStats> = serde_json::from_str(output)?;
+        crate::strict::check_fields(&statses);
         Ok(statses)
     }
 }
@@ -55,13 +257,137 @@ mod tests {
     use serde; // 1.0.88
     use serde::{Deserialize, Serialize};
 
+    #[allow(dead_code)]
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct Foo {
         pub mtime: DateTime<Utc>,
     }
 
+    #[test]
+    fn parse_empty_bucket_stats() {
+        for output in &["", "  \n", "{}", " {}\n", "[]"] {
+            let stats = admin::parse_bucket_stats(output).unwrap();
+            assert!(stats.is_empty(), "{:?}", output);
+        }
+    }
+
+    const MIXED_USAGE_BUCKET_STATS: &str = r#"[
+        {
+            "bucket": "backups",
+            "pool": "default.rgw.buckets.data",
+            "index_pool": "default.rgw.buckets.index",
+            "id": "b1",
+            "marker": "b1",
+            "owner": "p1",
+            "ver": "0#7",
+            "master_ver": "0#0",
+            "mtime": "2019-02-13 12:00:00.000000Z",
+            "max_marker": "0#",
+            "usage": {
+                "rgw.main": {"size_kb": 2048, "size_kb_actual": 2052, "num_objects": 3},
+                "rgw.none": {"size_kb": 4096, "size_kb_actual": 4096, "num_objects": 1}
+            },
+            "bucket_quota": {"enabled": false, "max_size_kb": -1, "max_objects": -1}
+        },
+        {
+            "bucket": "emptied",
+            "pool": "default.rgw.buckets.data",
+            "index_pool": "default.rgw.buckets.index",
+            "id": "b2",
+            "marker": "b2",
+            "owner": "p1",
+            "ver": "0#2",
+            "master_ver": "0#0",
+            "mtime": "2019-02-13 12:00:00.000000Z",
+            "max_marker": "0#",
+            "usage": {
+                "rgw.none": {"size_kb": 0, "size_kb_actual": 0, "num_objects": 0}
+            },
+            "bucket_quota": {"enabled": false, "max_size_kb": -1, "max_objects": -1}
+        }
+    ]"#;
+
+    #[test]
+    fn billable_size_skips_excluded_usage() {
+        use crate::units::{Bytes, Kilobytes};
+        let stats = admin::parse_bucket_stats(MIXED_USAGE_BUCKET_STATS).unwrap();
+        let excluded = admin::DEFAULT_EXCLUDED_USAGE_CATEGORIES;
+        assert_eq!(
+            stats[0].billable_size(excluded),
+            Some(Bytes::from(Kilobytes(2048)))
+        );
+        assert_eq!(stats[1].billable_size(excluded), None);
+        assert_eq!(stats[0].billable_objects(excluded), 3);
+        assert_eq!(stats[1].billable_objects(excluded), 0);
+
+        let nothing_excluded: &[&str] = &[];
+        assert_eq!(
+            stats[0].billable_size(nothing_excluded),
+            Some(Bytes::from(Kilobytes(2048 + 4096)))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_bucket_stats() {
+        assert!(admin::parse_bucket_stats("{\"bucket\": 1}").is_err());
+    }
+
     #[test]
     fn read_bucket_infos() {
         let _infos = admin::bucket_stats().unwrap();
     }
+
+    #[test]
+    fn bucket_modification_time() {
+        use chrono::TimeZone;
+        let mut stats = admin::parse_bucket_stats(MIXED_USAGE_BUCKET_STATS).unwrap();
+        assert_eq!(
+            stats[0].modified_at(),
+            Some(Utc.ymd(2019, 2, 13).and_hms(12, 0, 0))
+        );
+        stats[0].mtime = "yesterday".to_owned();
+        assert_eq!(stats[0].modified_at(), None);
+    }
+
+    #[test]
+    fn signature_v2_of_documented_example() {
+        let signature = admin::sign_v2(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "GET\n\n\nTue, 27 Mar 2007 19:36:42 +0000\n/johnsmith/photos/puppy.jpg",
+        )
+        .unwrap();
+        assert_eq!(signature, "bWq2s1WEIj+Ydj0vQ697zp+IXMU=");
+    }
+
+    #[test]
+    fn bucket_stats_from_admin_api() {
+        use crate::test_http::{json_response, local_listener, serve_requests};
+        let (listener, endpoint) = local_listener();
+        let (requests, received) = std::sync::mpsc::channel();
+        serve_requests(listener, move |method, target, headers| {
+            requests
+                .send((format!("{} {}", method, target), headers.to_vec()))
+                .unwrap();
+            json_response("200 OK", "", MIXED_USAGE_BUCKET_STATS)
+        });
+
+        let api = admin::AdminApi::new(&endpoint, "access", "secret", None).unwrap();
+        let stats = api.bucket_stats().unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].bucket, "backups");
+
+        let (request, headers) = received.recv().unwrap();
+        assert_eq!(request, "GET /admin/bucket?stats=true&format=json");
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+                .unwrap()
+                .to_owned()
+        };
+        let date = header("date");
+        let signature =
+            admin::sign_v2("secret", &format!("GET\n\n\n{}\n/admin/bucket", date)).unwrap();
+        assert_eq!(header("authorization"), format!("AWS access:{}", signature));
+    }
 }
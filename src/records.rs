@@ -1,6 +1,10 @@
+use crate::units::{Bytes, Gigabytes};
 use chrono::offset::TimeZone;
 use chrono::{DateTime, Duration, Utc};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::str::FromStr;
 use xml::writer::{EventWriter, XmlEvent};
@@ -9,10 +13,49 @@ trait EventWriterExt {
     fn write_simple_element(&mut self, name: &str, value: &str) -> Result<(), failure::Error>;
 }
 
+/// Replaces characters that may not appear in an XML 1.0 document, such as control characters,
+/// with U+FFFD so that a single bad name cannot invalidate a whole records file.
+pub fn sanitize_xml_text(value: &str) -> Cow<'_, str> {
+    fn is_xml_char(c: char) -> bool {
+        matches!(c, '\t' | '\n' | '\r') || (c >= ' ' && c != '\u{FFFE}' && c != '\u{FFFF}')
+    }
+    if value.chars().all(is_xml_char) {
+        return Cow::Borrowed(value);
+    }
+    warn!("Replacing characters invalid in XML in {:?}", value);
+    Cow::Owned(
+        value
+            .chars()
+            .map(|c| if is_xml_char(c) { c } else { '\u{FFFD}' })
+            .collect(),
+    )
+}
+
+/// Formats `duration` as an ISO 8601 duration in seconds, such as `PT3600S`, as the records
+/// schema expects, rather than relying on the `Display` of `chrono::Duration`.
+pub fn iso8601_duration(duration: Duration) -> String {
+    let sign = if duration < Duration::zero() { "-" } else { "" };
+    let duration = if duration < Duration::zero() {
+        -duration
+    } else {
+        duration
+    };
+    let seconds = duration.num_seconds();
+    let nanos = (duration - Duration::seconds(seconds))
+        .num_nanoseconds()
+        .unwrap_or(0);
+    if nanos == 0 {
+        format!("{}PT{}S", sign, seconds)
+    } else {
+        let fraction = format!("{:09}", nanos);
+        format!("{}PT{}.{}S", sign, seconds, fraction.trim_end_matches('0'))
+    }
+}
+
 impl<W: Write> EventWriterExt for EventWriter<W> {
     fn write_simple_element(&mut self, name: &str, value: &str) -> Result<(), failure::Error> {
         self.write(XmlEvent::start_element(name))?;
-        self.write(XmlEvent::characters(value))?;
+        self.write(XmlEvent::characters(&sanitize_xml_text(value)))?;
         self.write(XmlEvent::end_element())?;
 
         Ok(())
@@ -23,9 +66,48 @@ pub trait WriteToXML {
     fn write_to<W: Write>(&self, w: &mut EventWriter<W>) -> Result<(), failure::Error>;
 }
 
+/// Decimal separator of human-facing output, machine-readable records always use a point.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DecimalSeparator {
+    #[default]
+    #[serde(rename = ".")]
+    Point,
+    #[serde(rename = ",")]
+    Comma,
+}
+
+impl DecimalSeparator {
+    pub fn format(self, value: &Decimal) -> String {
+        match self {
+            DecimalSeparator::Point => value.to_string(),
+            DecimalSeparator::Comma => value.to_string().replace('.', ","),
+        }
+    }
+}
+
+/// The values of a record as a row of CSV fields, in the order of the header of the writer.
+pub trait ToCsvRow {
+    fn csv_row(&self, separator: DecimalSeparator) -> Vec<String>;
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// The second layout of cloud records. It carries the same elements as `v1`, but writes the
+/// elements shared by all kinds of records first and in one order, followed by the elements
+/// specific to the kind, and marks the root element with `cr:version="2"`.
 pub mod v2 {
     use super::*;
 
+    /// Value of the `cr:version` attribute on the root element.
+    pub const VERSION: &str = "2";
+
     #[derive(Debug)]
     pub struct CloudRecordCommon {
         pub create_time: DateTime<Utc>,
@@ -40,11 +122,187 @@ pub mod v2 {
         pub resource: String,
         pub zone: String,
         pub cost: Decimal,
+        pub allocated_disk: Bytes,
+        pub extensions: Vec<(String, String)>,
     }
 
     #[derive(Debug)]
     pub struct CloudComputeRecord {
         pub common: CloudRecordCommon,
+        pub flavour: String,
+        pub allocated_cpu: Decimal,
+        pub allocated_memory: Decimal,
+        pub used_cpu: Option<Decimal>,
+        pub used_memory: Option<u64>,
+        pub used_network_up: Option<u64>,
+        pub used_network_down: Option<u64>,
+        pub iops: Option<u64>,
+    }
+
+    #[derive(Debug)]
+    pub struct CloudStorageRecord {
+        pub common: CloudRecordCommon,
+        pub storage_type: String,
+        pub file_count: u64,
+    }
+
+    /// Conversion of a `v1` record to its `v2` counterpart.
+    pub trait ToV2 {
+        type Record: WriteToXML;
+
+        fn to_v2(&self) -> Self::Record;
+    }
+
+    impl From<&v1::CloudRecordCommon> for CloudRecordCommon {
+        fn from(common: &v1::CloudRecordCommon) -> Self {
+            CloudRecordCommon {
+                create_time: common.create_time,
+                site: common.site.clone(),
+                project: common.project.clone(),
+                user: common.user.clone(),
+                id: common.instance_id.clone(),
+                start_time: common.start_time,
+                end_time: common.end_time,
+                duration: common.duration,
+                region: common.region.clone(),
+                resource: common.resource.clone(),
+                zone: common.zone.clone(),
+                cost: common.cost,
+                allocated_disk: common.allocated_disk,
+                extensions: common.extensions.clone(),
+            }
+        }
+    }
+
+    impl ToV2 for v1::CloudComputeRecord {
+        type Record = CloudComputeRecord;
+
+        fn to_v2(&self) -> CloudComputeRecord {
+            CloudComputeRecord {
+                common: (&self.common).into(),
+                flavour: self.flavour.clone(),
+                allocated_cpu: self.allocated_cpu,
+                allocated_memory: self.allocated_memory,
+                used_cpu: self.used_cpu,
+                used_memory: self.used_memory,
+                used_network_up: self.used_network_up,
+                used_network_down: self.used_network_down,
+                iops: self.iops,
+            }
+        }
+    }
+
+    impl ToV2 for v1::CloudStorageRecord {
+        type Record = CloudStorageRecord;
+
+        fn to_v2(&self) -> CloudStorageRecord {
+            CloudStorageRecord {
+                common: (&self.common).into(),
+                storage_type: self.storage_type.clone(),
+                file_count: self.file_count,
+            }
+        }
+    }
+
+    impl CloudRecordCommon {
+        /// Builds the `cr:recordId`, with `kind` being `cr` for compute and `sr` for storage
+        /// records as in `v1`.
+        pub fn record_id(&self, kind: &str) -> String {
+            v1::record_id(&self.site, kind, &self.id, self.end_time)
+        }
+
+        /// Opens the record element and writes the elements shared by all kinds of records.
+        fn write_start<W: Write>(
+            &self,
+            element: &str,
+            kind: &str,
+            w: &mut EventWriter<W>,
+        ) -> Result<(), failure::Error> {
+            w.write(XmlEvent::start_element(element))?;
+
+            w.write(
+                XmlEvent::start_element("cr:RecordIdentity")
+                    .attr("cr:createTime", &self.create_time.to_rfc3339())
+                    .attr("cr:recordId", &sanitize_xml_text(&self.record_id(kind))),
+            )?;
+            w.write(XmlEvent::end_element())?;
+
+            w.write_simple_element("cr:Site", &self.site)?;
+            w.write_simple_element("cr:Project", &self.project)?;
+            w.write_simple_element("cr:User", &self.user)?;
+            w.write_simple_element("cr:InstanceId", &self.id)?;
+            w.write_simple_element("cr:StartTime", &self.start_time.to_rfc3339())?;
+            w.write_simple_element("cr:EndTime", &self.end_time.to_rfc3339())?;
+            w.write_simple_element("cr:Duration", &iso8601_duration(self.duration))?;
+            w.write_simple_element("cr:Region", &self.region)?;
+            w.write_simple_element("cr:Resource", &self.resource)?;
+            w.write_simple_element("cr:Zone", &self.zone)?;
+            w.write_simple_element("cr:Cost", &self.cost.to_string())?;
+            w.write_simple_element("cr:AllocatedDisk", &self.allocated_disk.to_string())?;
+            Ok(())
+        }
+
+        /// Writes the extensions and closes the record element.
+        fn write_end<W: Write>(&self, w: &mut EventWriter<W>) -> Result<(), failure::Error> {
+            v1::write_extensions(&self.extensions, w)?;
+            w.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+    }
+
+    impl WriteToXML for CloudComputeRecord {
+        fn write_to<W: Write>(&self, w: &mut EventWriter<W>) -> Result<(), failure::Error> {
+            self.common.write_start("cr:CloudComputeRecord", "cr", w)?;
+            w.write_simple_element("cr:Flavour", &self.flavour)?;
+            w.write_simple_element("cr:AllocatedCPU", &self.allocated_cpu.to_string())?;
+            w.write_simple_element("cr:AllocatedMemory", &self.allocated_memory.to_string())?;
+            if let Some(v) = self.used_cpu {
+                w.write_simple_element("cr:UsedCPU", &v.to_string())?;
+            }
+            if let Some(v) = self.used_memory {
+                w.write_simple_element("cr:UsedMemory", &v.to_string())?;
+            }
+            if let Some(v) = self.used_network_up {
+                w.write_simple_element("cr:UsedNetworkUp", &v.to_string())?;
+            }
+            if let Some(v) = self.used_network_down {
+                w.write_simple_element("cr:UsedNetworkDown", &v.to_string())?;
+            }
+            if let Some(v) = self.iops {
+                w.write_simple_element("cr:IOPS", &v.to_string())?;
+            }
+            self.common.write_end(w)
+        }
+    }
+
+    impl WriteToXML for CloudStorageRecord {
+        fn write_to<W: Write>(&self, w: &mut EventWriter<W>) -> Result<(), failure::Error> {
+            self.common.write_start("cr:CloudStorageRecord", "sr", w)?;
+            w.write_simple_element("cr:StorageType", &self.storage_type)?;
+            w.write_simple_element("cr:FileCount", &self.file_count.to_string())?;
+            self.common.write_end(w)
+        }
+    }
+
+    pub fn write_xml_to<'a, W, ComputeIter, StorageIter>(
+        writer: W,
+        computes: ComputeIter,
+        storages: StorageIter,
+    ) -> Result<(), failure::Error>
+    where
+        W: Write,
+        ComputeIter: IntoIterator<Item = &'a CloudComputeRecord>,
+        StorageIter: IntoIterator<Item = &'a CloudStorageRecord>,
+    {
+        let mut w = v1::RecordWriter::with_version(writer, None, Some(VERSION))?;
+        for cr in computes {
+            w.write(cr)?;
+        }
+        for sr in storages {
+            w.write(sr)?;
+        }
+        w.finish()?;
+        Ok(())
     }
 }
 
@@ -90,7 +348,22 @@ pub mod v1 {
         pub cost: Decimal,
 
         // <cr:AllocatedDisk>0</cr:AllocatedDisk>
-        pub allocated_disk: u64,
+        pub allocated_disk: Bytes,
+
+        // <cr:BenchmarkType>hepspec</cr:BenchmarkType>
+        // Additional elements written in order after the standard ones, named without the prefix.
+        pub extensions: Vec<(String, String)>,
+    }
+
+    /// Writes the `extensions` of a record as elements in the `cr` namespace, in order.
+    pub(super) fn write_extensions<W: Write>(
+        extensions: &[(String, String)],
+        w: &mut EventWriter<W>,
+    ) -> Result<(), failure::Error> {
+        for (name, value) in extensions {
+            w.write_simple_element(&format!("cr:{}", name), value)?;
+        }
+        Ok(())
     }
 
     #[derive(Debug)]
@@ -104,7 +377,8 @@ pub mod v1 {
         pub allocated_cpu: Decimal,
 
         // <cr:AllocatedMemory>2048</cr:AllocatedMemory>
-        pub allocated_memory: u64,
+        // Already converted to the configured `MemoryUnit`, megabytes unless configured otherwise.
+        pub allocated_memory: Decimal,
 
         pub used_cpu: Option<Decimal>,
         pub used_memory: Option<u64>,
@@ -113,7 +387,38 @@ pub mod v1 {
         pub iops: Option<u64>,
     }
 
+    /// Builds the `cr:recordId` of a compute or storage record, `kind` is `cr` for compute
+    /// and `sr` for storage records so that the two never share an ID.
+    ///
+    /// The trailing component is the Unix timestamp of `end_time`, the end of the
+    /// period the record covers. It is taken from the UTC instant, so it does not
+    /// shift with local daylight saving time.
+    pub(super) fn record_id(
+        site: &str,
+        kind: &str,
+        instance_id: &str,
+        end_time: DateTime<Utc>,
+    ) -> String {
+        format!(
+            "ssc/{}/{}/{}/{}",
+            site,
+            kind,
+            instance_id,
+            end_time.timestamp()
+        )
+    }
+
+    impl CloudRecordCommon {
+        fn record_id(&self, kind: &str) -> String {
+            record_id(&self.site, kind, &self.instance_id, self.end_time)
+        }
+    }
+
     impl CloudComputeRecord {
+        pub fn record_id(&self) -> String {
+            self.common.record_id("cr")
+        }
+
         pub fn example() -> Self {
             let create_time = Utc::now();
             // ssc/HPC2N/cr/1161cbd4-4c31-4052-8154-0c98881a1a69/1550059200
@@ -130,14 +435,15 @@ pub mod v1 {
                 resource: "SE-SNIC-SSC".to_owned(),
                 zone: "nova".to_owned(),
                 cost: Decimal::from_str("0.125").unwrap(),
-                allocated_disk: 0,
+                allocated_disk: Bytes(0),
+                extensions: Vec::new(),
             };
 
             CloudComputeRecord {
                 common,
                 flavour: "ssc.small".to_owned(),
                 allocated_cpu: Decimal::from_str("1.0").unwrap(),
-                allocated_memory: 2048,
+                allocated_memory: Decimal::from(2048),
                 used_cpu: None,
                 used_memory: None,
                 used_network_up: None,
@@ -155,15 +461,7 @@ pub mod v1 {
             w.write(
                 XmlEvent::start_element("cr:RecordIdentity")
                     .attr("cr:createTime", &common.create_time.to_rfc3339())
-                    .attr(
-                        "cr:recordId",
-                        &format!(
-                            "ssc/{}/cr/{}/{}",
-                            common.site,
-                            common.instance_id,
-                            common.end_time.timestamp()
-                        ),
-                    ),
+                    .attr("cr:recordId", &sanitize_xml_text(&self.record_id())),
             )?;
             w.write(XmlEvent::end_element())?;
 
@@ -173,7 +471,7 @@ pub mod v1 {
             w.write_simple_element("cr:InstanceId", &common.instance_id)?;
             w.write_simple_element("cr:StartTime", &common.start_time.to_rfc3339())?;
             w.write_simple_element("cr:EndTime", &common.end_time.to_rfc3339())?;
-            w.write_simple_element("cr:Duration", &common.duration.to_string())?;
+            w.write_simple_element("cr:Duration", &iso8601_duration(common.duration))?;
             w.write_simple_element("cr:Region", &common.region)?;
             w.write_simple_element("cr:Resource", &common.resource)?;
             w.write_simple_element("cr:Zone", &common.zone)?;
@@ -181,6 +479,9 @@ pub mod v1 {
             w.write_simple_element("cr:Cost", &common.cost.to_string())?;
             w.write_simple_element("cr:AllocatedCPU", &self.allocated_cpu.to_string())?;
             w.write_simple_element("cr:AllocatedDisk", &common.allocated_disk.to_string())?;
+            // The SAMS cloud record schema does not state a unit for AllocatedMemory. Nova reports
+            // flavor memory in megabytes and that is what has historically been sent, so the value
+            // is written as-is in whichever unit it was converted to when the record was built.
             w.write_simple_element("cr:AllocatedMemory", &self.allocated_memory.to_string())?;
 
             if let Some(v) = self.used_cpu {
@@ -198,6 +499,7 @@ pub mod v1 {
             if let Some(v) = self.iops {
                 w.write_simple_element("cr:IOPS", &v.to_string())?;
             }
+            write_extensions(&common.extensions, w)?;
 
             w.write(XmlEvent::end_element())?;
 
@@ -217,6 +519,10 @@ pub mod v1 {
     }
 
     impl CloudStorageRecord {
+        pub fn record_id(&self) -> String {
+            self.common.record_id("sr")
+        }
+
         pub fn example() -> Self {
             let create_time = Utc::now();
             let common = CloudRecordCommon {
@@ -232,7 +538,8 @@ pub mod v1 {
                 resource: "SE-SNIC-SSC".to_owned(),
                 zone: "nova".to_owned(),
                 cost: Decimal::from_str("0.001").unwrap(),
-                allocated_disk: 10737418240u64,
+                allocated_disk: Gigabytes(10).into(),
+                extensions: Vec::new(),
             };
             CloudStorageRecord {
                 common,
@@ -242,6 +549,75 @@ pub mod v1 {
         }
     }
 
+    impl ToCsvRow for CloudComputeRecord {
+        fn csv_row(&self, separator: DecimalSeparator) -> Vec<String> {
+            let mut row = self.common.csv_fields("compute", separator);
+            row.extend(vec![
+                separator.format(&self.allocated_cpu),
+                separator.format(&self.allocated_memory),
+                self.flavour.clone(),
+                String::new(),
+                String::new(),
+            ]);
+            row
+        }
+    }
+
+    impl ToCsvRow for CloudStorageRecord {
+        fn csv_row(&self, separator: DecimalSeparator) -> Vec<String> {
+            let mut row = self.common.csv_fields("storage", separator);
+            row.extend(vec![
+                String::new(),
+                String::new(),
+                String::new(),
+                self.storage_type.clone(),
+                self.file_count.to_string(),
+            ]);
+            row
+        }
+    }
+
+    impl CloudRecordCommon {
+        /// The fields of `CSV_HEADER` shared by compute and storage records.
+        fn csv_fields(&self, kind: &str, separator: DecimalSeparator) -> Vec<String> {
+            vec![
+                kind.to_owned(),
+                self.site.clone(),
+                self.project.clone(),
+                self.user.clone(),
+                self.instance_id.clone(),
+                self.region.clone(),
+                self.resource.clone(),
+                self.zone.clone(),
+                self.start_time.to_rfc3339(),
+                self.end_time.to_rfc3339(),
+                separator.format(&self.cost),
+                self.allocated_disk.0.to_string(),
+            ]
+        }
+    }
+
+    /// Columns of CSV output, fields that do not apply to a kind of record are left empty.
+    pub const CSV_HEADER: [&str; 17] = [
+        "kind",
+        "site",
+        "project",
+        "user",
+        "instance_id",
+        "region",
+        "resource",
+        "zone",
+        "start_time",
+        "end_time",
+        "cost",
+        "allocated_disk",
+        "allocated_cpu",
+        "allocated_memory",
+        "flavour",
+        "storage_type",
+        "file_count",
+    ];
+
     impl WriteToXML for CloudStorageRecord {
         fn write_to<W: Write>(&self, w: &mut EventWriter<W>) -> Result<(), failure::Error> {
             let common = &self.common;
@@ -250,15 +626,7 @@ pub mod v1 {
             w.write(
                 XmlEvent::start_element("cr:RecordIdentity")
                     .attr("cr:createTime", &common.create_time.to_rfc3339())
-                    .attr(
-                        "cr:recordId",
-                        &format!(
-                            "ssc/{}/cr/{}/{}",
-                            common.site,
-                            common.instance_id,
-                            common.end_time.timestamp()
-                        ),
-                    ),
+                    .attr("cr:recordId", &sanitize_xml_text(&self.record_id())),
             )?;
             w.write(XmlEvent::end_element())?;
 
@@ -269,13 +637,14 @@ pub mod v1 {
             w.write_simple_element("cr:StorageType", &self.storage_type)?;
             w.write_simple_element("cr:StartTime", &common.start_time.to_rfc3339())?;
             w.write_simple_element("cr:EndTime", &common.end_time.to_rfc3339())?;
-            w.write_simple_element("cr:Duration", &common.duration.to_string())?;
+            w.write_simple_element("cr:Duration", &iso8601_duration(common.duration))?;
             w.write_simple_element("cr:Region", &common.region)?;
             w.write_simple_element("cr:Resource", &common.resource)?;
             w.write_simple_element("cr:Zone", &common.zone)?;
             w.write_simple_element("cr:Cost", &common.cost.to_string())?;
             w.write_simple_element("cr:AllocatedDisk", &common.allocated_disk.to_string())?;
             w.write_simple_element("cr:FileCount", &self.file_count.to_string())?;
+            write_extensions(&common.extensions, w)?;
 
             w.write(XmlEvent::end_element())?;
 
@@ -283,6 +652,54 @@ pub mod v1 {
         }
     }
 
+    /// Collapses storage records into one record per project and resource, summing cost,
+    /// allocated disk and file count. The synthetic instance id is derived from `kind`, the
+    /// project name and the resource so that it stays stable between runs and differs between
+    /// the resources of a project.
+    pub fn aggregate_storage_per_project<I>(records: I, kind: &str) -> Vec<CloudStorageRecord>
+    where
+        I: IntoIterator<Item = CloudStorageRecord>,
+    {
+        let mut groups: BTreeMap<(String, String), CloudStorageRecord> = BTreeMap::new();
+        for record in records {
+            let key = (
+                record.common.project.clone(),
+                record.common.resource.clone(),
+            );
+            match groups.get_mut(&key) {
+                Some(agg) => {
+                    agg.common.cost += record.common.cost;
+                    agg.common.allocated_disk += record.common.allocated_disk;
+                    agg.file_count += record.file_count;
+                    if agg.common.user != record.common.user {
+                        agg.common.user = AGGREGATE_FALLBACK.to_owned();
+                    }
+                    if agg.common.zone != record.common.zone {
+                        agg.common.zone = AGGREGATE_FALLBACK.to_owned();
+                    }
+                }
+                None => {
+                    let mut agg = record;
+                    agg.common.instance_id =
+                        aggregate_instance_id(kind, &agg.common.project, &agg.common.resource);
+                    groups.insert(key, agg);
+                }
+            }
+        }
+        groups.into_values().collect()
+    }
+
+    const AGGREGATE_FALLBACK: &str = "default";
+
+    fn aggregate_instance_id(kind: &str, project: &str, resource: &str) -> String {
+        let sanitize = |s: &str| -> String {
+            s.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect()
+        };
+        format!("{}-{}-{}", kind, sanitize(project), sanitize(resource))
+    }
+
     pub fn write_xml_to<'a, W, ComputeIter, StorageIter>(
         writer: W,
         computes: ComputeIter,
@@ -293,22 +710,585 @@ pub mod v1 {
         ComputeIter: IntoIterator<Item = &'a CloudComputeRecord>,
         StorageIter: IntoIterator<Item = &'a CloudStorageRecord>,
     {
-        use xml::writer::EmitterConfig;
-        let mut w = EmitterConfig::new()
-            .perform_indent(true)
-            .create_writer(writer);
-
-        w.write(
-            XmlEvent::start_element("cr:CloudRecords")
-                .ns("cr", "http://sams.snic.se/namespaces/2016/04/cloudrecords"),
-        )?;
+        let mut w = RecordWriter::new(writer)?;
         for cr in computes {
-            cr.write_to(&mut w)?;
+            w.write(cr)?;
         }
         for sr in storages {
-            sr.write_to(&mut w)?;
+            w.write(sr)?;
         }
-        w.write(XmlEvent::end_element())?;
+        w.finish()?;
         Ok(())
     }
+
+    /// Writes a `cr:CloudRecords` document one record at a time, so that records can be written as
+    /// they are produced instead of being collected first.
+    pub struct RecordWriter<W: Write> {
+        w: EventWriter<W>,
+        count: usize,
+    }
+
+    impl<W: Write> RecordWriter<W> {
+        pub fn new(writer: W) -> Result<Self, failure::Error> {
+            RecordWriter::with_batch_id(writer, None)
+        }
+
+        /// Creates a writer whose root element carries `batch_id` as its `cr:batchId` attribute.
+        pub fn with_batch_id(writer: W, batch_id: Option<&str>) -> Result<Self, failure::Error> {
+            RecordWriter::with_version(writer, batch_id, None)
+        }
+
+        /// Creates a writer whose root element also carries `version` as its `cr:version`
+        /// attribute, for layouts other than the original one.
+        pub fn with_version(
+            writer: W,
+            batch_id: Option<&str>,
+            version: Option<&str>,
+        ) -> Result<Self, failure::Error> {
+            use xml::writer::EmitterConfig;
+            let mut w = EmitterConfig::new()
+                .perform_indent(true)
+                .create_writer(writer);
+
+            let mut root = XmlEvent::start_element("cr:CloudRecords")
+                .ns("cr", "http://sams.snic.se/namespaces/2016/04/cloudrecords");
+            if let Some(batch_id) = batch_id {
+                root = root.attr("cr:batchId", batch_id);
+            }
+            if let Some(version) = version {
+                root = root.attr("cr:version", version);
+            }
+            w.write(root)?;
+            Ok(RecordWriter { w, count: 0 })
+        }
+
+        /// Writes an XML comment, with any `--` that may not appear in comments split up.
+        pub fn comment(&mut self, text: &str) -> Result<(), failure::Error> {
+            let mut text = text.to_owned();
+            while text.contains("--") {
+                text = text.replace("--", "- -");
+            }
+            self.w.write(XmlEvent::comment(&text))?;
+            Ok(())
+        }
+
+        pub fn write<R: WriteToXML>(&mut self, record: &R) -> Result<(), failure::Error> {
+            record.write_to(&mut self.w)?;
+            self.count += 1;
+            Ok(())
+        }
+
+        /// Number of records written so far.
+        pub fn count(&self) -> usize {
+            self.count
+        }
+
+        /// Closes the document and returns the underlying writer.
+        pub fn finish(mut self) -> Result<W, failure::Error> {
+            self.w.write(XmlEvent::end_element())?;
+            Ok(self.w.into_inner())
+        }
+    }
+
+    /// Writes records as CSV with a `CSV_HEADER` line, for spreadsheet users.
+    pub struct CsvWriter<W: Write> {
+        w: W,
+        count: usize,
+        separator: DecimalSeparator,
+    }
+
+    impl<W: Write> CsvWriter<W> {
+        pub fn new(writer: W) -> Result<Self, failure::Error> {
+            CsvWriter::with_decimal_separator(writer, DecimalSeparator::default())
+        }
+
+        pub fn with_decimal_separator(
+            mut writer: W,
+            separator: DecimalSeparator,
+        ) -> Result<Self, failure::Error> {
+            writeln!(writer, "{}", CSV_HEADER.join(","))?;
+            Ok(CsvWriter {
+                w: writer,
+                count: 0,
+                separator,
+            })
+        }
+
+        pub fn write<R: ToCsvRow>(&mut self, record: &R) -> Result<(), failure::Error> {
+            let fields = record.csv_row(self.separator);
+            let row: Vec<Cow<str>> = fields.iter().map(|f| csv_field(f)).collect();
+            writeln!(self.w, "{}", row.join(","))?;
+            self.count += 1;
+            Ok(())
+        }
+
+        pub fn count(&self) -> usize {
+            self.count
+        }
+
+        pub fn finish(self) -> Result<W, failure::Error> {
+            Ok(self.w)
+        }
+    }
+
+    /// Format of the written records.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RecordFormat {
+        Xml,
+        /// XML in the `v2` layout.
+        XmlV2,
+        Csv,
+    }
+
+    impl RecordFormat {
+        /// Extension of files holding records in this format.
+        pub fn extension(self) -> &'static str {
+            match self {
+                RecordFormat::Xml | RecordFormat::XmlV2 => "xml",
+                RecordFormat::Csv => "csv",
+            }
+        }
+
+        /// Creates a writer, `separator` only applies to CSV as XML records always use a point
+        /// and `batch_id` only to XML as CSV has no room for it.
+        pub fn writer<W: Write>(
+            self,
+            writer: W,
+            separator: DecimalSeparator,
+            batch_id: Option<&str>,
+        ) -> Result<FormatWriter<W>, failure::Error> {
+            Ok(match self {
+                RecordFormat::Xml => {
+                    FormatWriter::Xml(RecordWriter::with_batch_id(writer, batch_id)?)
+                }
+                RecordFormat::XmlV2 => FormatWriter::XmlV2(RecordWriter::with_version(
+                    writer,
+                    batch_id,
+                    Some(v2::VERSION),
+                )?),
+                RecordFormat::Csv => {
+                    FormatWriter::Csv(CsvWriter::with_decimal_separator(writer, separator)?)
+                }
+            })
+        }
+    }
+
+    impl FromStr for RecordFormat {
+        type Err = failure::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "xml" => Ok(RecordFormat::Xml),
+                "xml-v2" => Ok(RecordFormat::XmlV2),
+                "csv" => Ok(RecordFormat::Csv),
+                _ => Err(format_err!("Unknown record format {:?}", s)),
+            }
+        }
+    }
+
+    /// A writer of records in one of the `RecordFormat`s.
+    pub enum FormatWriter<W: Write> {
+        Xml(RecordWriter<W>),
+        XmlV2(RecordWriter<W>),
+        Csv(CsvWriter<W>),
+    }
+
+    impl<W: Write> FormatWriter<W> {
+        /// Writes a comment, which CSV has no room for and leaves out.
+        pub fn comment(&mut self, text: &str) -> Result<(), failure::Error> {
+            match self {
+                FormatWriter::Xml(w) | FormatWriter::XmlV2(w) => w.comment(text),
+                FormatWriter::Csv(_) => Ok(()),
+            }
+        }
+
+        pub fn write<R: WriteToXML + ToCsvRow + v2::ToV2>(
+            &mut self,
+            record: &R,
+        ) -> Result<(), failure::Error> {
+            match self {
+                FormatWriter::Xml(w) => w.write(record),
+                FormatWriter::XmlV2(w) => w.write(&record.to_v2()),
+                FormatWriter::Csv(w) => w.write(record),
+            }
+        }
+
+        pub fn count(&self) -> usize {
+            match self {
+                FormatWriter::Xml(w) | FormatWriter::XmlV2(w) => w.count(),
+                FormatWriter::Csv(w) => w.count(),
+            }
+        }
+
+        pub fn finish(self) -> Result<W, failure::Error> {
+            match self {
+                FormatWriter::Xml(w) | FormatWriter::XmlV2(w) => w.finish(),
+                FormatWriter::Csv(w) => w.finish(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::v1::*;
+    use super::DecimalSeparator;
+    use crate::units::{Bytes, Gigabytes};
+    use chrono::{TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    fn volume(id: &str, gigs: u64, cost: &str) -> CloudStorageRecord {
+        let mut sr = CloudStorageRecord::example();
+        sr.common.instance_id = id.to_owned();
+        sr.common.allocated_disk = Gigabytes(gigs).into();
+        sr.common.cost = Decimal::from_str(cost).unwrap();
+        sr
+    }
+
+    #[test]
+    fn record_id_uses_period_end() {
+        let cr = CloudComputeRecord::example();
+        assert_eq!(
+            cr.record_id(),
+            "ssc/HPC2N/cr/1161cbd4-4c31-4052-8154-0c98881a1a69/1550059200"
+        );
+        let sr = CloudStorageRecord::example();
+        assert_eq!(
+            sr.record_id(),
+            "ssc/HPC2N/sr/41d169a8-e2e8-4e81-a8d0-6fda07316251/1550059200"
+        );
+
+        // The hour in which Central European clocks jump forward.
+        let mut cr = CloudComputeRecord::example();
+        cr.common.start_time = Utc.ymd(2019, 3, 31).and_hms(0, 0, 0);
+        cr.common.end_time = Utc.ymd(2019, 3, 31).and_hms(1, 0, 0);
+        assert_eq!(
+            cr.record_id(),
+            "ssc/HPC2N/cr/1161cbd4-4c31-4052-8154-0c98881a1a69/1553994000"
+        );
+    }
+
+    #[test]
+    fn invalid_xml_characters_are_replaced() {
+        let mut cr = CloudComputeRecord::example();
+        cr.common.project = "SNIC 2018/10-1\u{1b}".to_owned();
+        cr.flavour = "ssc.small\u{0}".to_owned();
+        let mut sr = CloudStorageRecord::example();
+        sr.common.instance_id = "vol\u{7}1".to_owned();
+
+        let mut xml = Vec::new();
+        let mut writer = RecordWriter::new(&mut xml).unwrap();
+        writer.write(&cr).unwrap();
+        writer.write(&sr).unwrap();
+        writer.finish().unwrap();
+
+        for event in xml::reader::EventReader::new(&xml[..]) {
+            event.unwrap();
+        }
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<cr:Project>SNIC 2018/10-1\u{FFFD}</cr:Project>"));
+        assert!(xml.contains("<cr:Flavour>ssc.small\u{FFFD}</cr:Flavour>"));
+        assert!(xml.contains("<cr:InstanceId>vol\u{FFFD}1</cr:InstanceId>"));
+        assert_eq!(super::sanitize_xml_text("ssc.small"), "ssc.small");
+    }
+
+    #[test]
+    fn extensions_follow_standard_elements() {
+        let mut sr = CloudStorageRecord::example();
+        sr.common.extensions = vec![
+            ("BenchmarkType".to_owned(), "hepspec".to_owned()),
+            ("AccountingClass".to_owned(), "standard".to_owned()),
+        ];
+        let mut xml = Vec::new();
+        let mut writer = RecordWriter::new(&mut xml).unwrap();
+        writer.write(&sr).unwrap();
+        writer.write(&CloudStorageRecord::example()).unwrap();
+        writer.finish().unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+
+        let file_count = xml.find("<cr:FileCount>").unwrap();
+        let benchmark = xml
+            .find("<cr:BenchmarkType>hepspec</cr:BenchmarkType>")
+            .unwrap();
+        let class = xml
+            .find("<cr:AccountingClass>standard</cr:AccountingClass>")
+            .unwrap();
+        assert!(file_count < benchmark && benchmark < class);
+        assert_eq!(xml.matches("<cr:BenchmarkType>").count(), 1);
+    }
+
+    #[test]
+    fn record_writer_streams_records() {
+        let mut w = RecordWriter::new(Vec::new()).unwrap();
+        w.write(&CloudComputeRecord::example()).unwrap();
+        w.write(&CloudStorageRecord::example()).unwrap();
+        assert_eq!(w.count(), 2);
+        let xml = String::from_utf8(w.finish().unwrap()).unwrap();
+        assert!(xml.contains("<cr:CloudComputeRecord>"));
+        assert!(xml.contains("<cr:CloudStorageRecord>"));
+        assert!(xml.trim_end().ends_with("</cr:CloudRecords>"));
+    }
+
+    #[test]
+    fn aggregate_storage_ids_differ_per_resource() {
+        let mut other = volume("vol-b", 5, "0.05");
+        other.common.resource = "SE-SNIC-SSC-LOCAL".to_owned();
+        let per_project =
+            aggregate_storage_per_project(vec![volume("vol-a", 10, "0.1"), other], "volumes");
+        assert_eq!(per_project.len(), 2);
+        assert_ne!(
+            per_project[0].common.instance_id,
+            per_project[1].common.instance_id
+        );
+        assert_ne!(per_project[0].record_id(), per_project[1].record_id());
+    }
+
+    #[test]
+    fn aggregate_storage_modes() {
+        let per_volume = vec![volume("vol-a", 10, "0.1"), volume("vol-b", 5, "0.05")];
+        assert_eq!(per_volume.len(), 2);
+
+        let per_project = aggregate_storage_per_project(per_volume, "volumes");
+        assert_eq!(per_project.len(), 1);
+        let agg = &per_project[0];
+        assert_eq!(
+            agg.common.instance_id,
+            "volumes-SNIC-2018-10-20-SE-SNIC-SSC"
+        );
+        assert_eq!(agg.common.allocated_disk, Bytes::from(Gigabytes(15)));
+        assert_eq!(agg.common.cost, Decimal::from_str("0.15").unwrap());
+        assert_eq!(agg.common.user, "s3245");
+    }
+
+    #[test]
+    fn special_characters_round_trip() {
+        let mut cr = CloudComputeRecord::example();
+        cr.common.project = "Dept R&D <\"Ünïcødé\"> 'quoted'".to_owned();
+        cr.common.resource = "<SE-SNIC-SSC>".to_owned();
+        cr.common.site = "H&M".to_owned();
+        cr.common.user = "åsa@example.org & co".to_owned();
+        let mut sr = CloudStorageRecord::example();
+        sr.common.instance_id = "bucket\"<&>\"".to_owned();
+        sr.storage_type = "Block > Object".to_owned();
+
+        let mut xml = Vec::new();
+        write_xml_to(&mut xml, vec![&cr], vec![&sr]).unwrap();
+
+        let mut elements = Vec::new();
+        let mut record_ids = Vec::new();
+        let mut current = String::new();
+        for event in xml::reader::EventReader::new(&xml[..]) {
+            match event.unwrap() {
+                xml::reader::XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    current = name.local_name;
+                    record_ids.extend(
+                        attributes
+                            .into_iter()
+                            .filter(|a| a.name.local_name == "recordId")
+                            .map(|a| a.value),
+                    );
+                }
+                xml::reader::XmlEvent::Characters(text) => {
+                    elements.push((current.clone(), text));
+                }
+                _ => {}
+            }
+        }
+        let has = |name: &str, value: &str| {
+            elements
+                .iter()
+                .any(|(n, v)| n.as_str() == name && v.as_str() == value)
+        };
+        assert!(has("Project", &cr.common.project), "{:?}", elements);
+        assert!(has("Resource", "<SE-SNIC-SSC>"), "{:?}", elements);
+        assert!(has("Site", "H&M"), "{:?}", elements);
+        assert!(has("User", "åsa@example.org & co"), "{:?}", elements);
+        assert!(has("InstanceId", "bucket\"<&>\""), "{:?}", elements);
+        assert!(has("StorageType", "Block > Object"), "{:?}", elements);
+        assert_eq!(record_ids, vec![cr.record_id(), sr.record_id()]);
+    }
+
+    #[test]
+    fn comments_kept_well_formed() {
+        let mut xml = Vec::new();
+        let mut writer = RecordWriter::new(&mut xml).unwrap();
+        writer.comment("ssc-billing-logger 0.3.1").unwrap();
+        writer.comment("a --- b -").unwrap();
+        writer.write(&CloudComputeRecord::example()).unwrap();
+        writer.finish().unwrap();
+
+        let mut comments = Vec::new();
+        let parser = xml::reader::ParserConfig::new().ignore_comments(false);
+        for event in parser.create_reader(&xml[..]) {
+            if let xml::reader::XmlEvent::Comment(text) = event.unwrap() {
+                comments.push(text);
+            }
+        }
+        assert_eq!(
+            comments,
+            vec![" ssc-billing-logger 0.3.1 ", " a - - - b - "]
+        );
+    }
+
+    #[test]
+    fn csv_rows_for_known_records() {
+        let mut cr = CloudComputeRecord::example();
+        cr.common.project = "Dept R&D, \"north\"".to_owned();
+        let sr = CloudStorageRecord::example();
+        let mut w = CsvWriter::new(Vec::new()).unwrap();
+        w.write(&cr).unwrap();
+        w.write(&sr).unwrap();
+        assert_eq!(w.count(), 2);
+        let csv = String::from_utf8(w.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "kind,site,project,user,instance_id,region,resource,zone,start_time,end_time,cost,\
+                 allocated_disk,allocated_cpu,allocated_memory,flavour,storage_type,file_count",
+                "compute,HPC2N,\"Dept R&D, \"\"north\"\"\",s11778,1161cbd4-4c31-4052-8154-0c98881a1a69,\
+                 HPC2N,SE-SNIC-SSC,nova,2019-02-13T11:00:00+00:00,2019-02-13T12:00:00+00:00,0.125,0,\
+                 1.0,2048,ssc.small,,",
+                "storage,HPC2N,SNIC 2018/10-20,s3245,41d169a8-e2e8-4e81-a8d0-6fda07316251,HPC2N,\
+                 SE-SNIC-SSC,nova,2019-02-13T11:00:00+00:00,2019-02-13T12:00:00+00:00,0.001,\
+                 10737418240,,,,Block,0",
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_decimal_separator() {
+        let cr = CloudComputeRecord::example();
+        let mut w = CsvWriter::with_decimal_separator(Vec::new(), DecimalSeparator::Comma).unwrap();
+        w.write(&cr).unwrap();
+        let csv = String::from_utf8(w.finish().unwrap()).unwrap();
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.contains(",\"0,125\",0,\"1,0\",2048,"), "{}", row);
+
+        let mut w = RecordFormat::Xml
+            .writer(Vec::new(), DecimalSeparator::Comma, None)
+            .unwrap();
+        w.write(&cr).unwrap();
+        let xml = String::from_utf8(w.finish().unwrap()).unwrap();
+        assert!(xml.contains("<cr:Cost>0.125</cr:Cost>"), "{}", xml);
+    }
+
+    #[test]
+    fn batch_id_on_root_element() {
+        let mut w =
+            RecordWriter::with_batch_id(Vec::new(), Some("north-1/20190213T1200Z")).unwrap();
+        w.write(&CloudComputeRecord::example()).unwrap();
+        let xml = String::from_utf8(w.finish().unwrap()).unwrap();
+        let root = xml.lines().nth(1).unwrap();
+        assert!(root.starts_with("<cr:CloudRecords"), "{}", root);
+        assert!(
+            root.contains("cr:batchId=\"north-1/20190213T1200Z\""),
+            "{}",
+            root
+        );
+
+        let xml =
+            String::from_utf8(RecordWriter::new(Vec::new()).unwrap().finish().unwrap()).unwrap();
+        assert!(!xml.contains("batchId"), "{}", xml);
+    }
+
+    #[test]
+    fn storage_record_ids_use_sr() {
+        let mut out = Vec::new();
+        write_xml_to(
+            &mut out,
+            &[CloudComputeRecord::example()],
+            &[volume("vol-1", 10, "0.01")],
+        )
+        .unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(
+            xml.contains("cr:recordId=\"ssc/HPC2N/sr/vol-1/1550059200\""),
+            "{}",
+            xml
+        );
+        assert!(
+            xml.contains(
+                "cr:recordId=\"ssc/HPC2N/cr/1161cbd4-4c31-4052-8154-0c98881a1a69/1550059200\""
+            ),
+            "{}",
+            xml
+        );
+    }
+
+    #[test]
+    fn v2_records_golden_output() {
+        use super::v2::{self, ToV2};
+
+        let created = Utc.ymd(2019, 2, 13).and_hms(12, 15, 0);
+        let mut cr = CloudComputeRecord::example();
+        cr.common.create_time = created;
+        cr.used_cpu = Some(Decimal::from_str("0.5").unwrap());
+        cr.common
+            .extensions
+            .push(("BenchmarkType".to_owned(), "hepspec".to_owned()));
+        let mut sr = CloudStorageRecord::example();
+        sr.common.create_time = created;
+
+        let mut out = Vec::new();
+        v2::write_xml_to(&mut out, &[cr.to_v2()], &[sr.to_v2()]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), V2_GOLDEN);
+    }
+
+    const V2_GOLDEN: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<cr:CloudRecords xmlns:cr="http://sams.snic.se/namespaces/2016/04/cloudrecords" cr:version="2">
+  <cr:CloudComputeRecord>
+    <cr:RecordIdentity cr:createTime="2019-02-13T12:15:00+00:00" cr:recordId="ssc/HPC2N/cr/1161cbd4-4c31-4052-8154-0c98881a1a69/1550059200" />
+    <cr:Site>HPC2N</cr:Site>
+    <cr:Project>SNIC 2018/10-30</cr:Project>
+    <cr:User>s11778</cr:User>
+    <cr:InstanceId>1161cbd4-4c31-4052-8154-0c98881a1a69</cr:InstanceId>
+    <cr:StartTime>2019-02-13T11:00:00+00:00</cr:StartTime>
+    <cr:EndTime>2019-02-13T12:00:00+00:00</cr:EndTime>
+    <cr:Duration>PT3600S</cr:Duration>
+    <cr:Region>HPC2N</cr:Region>
+    <cr:Resource>SE-SNIC-SSC</cr:Resource>
+    <cr:Zone>nova</cr:Zone>
+    <cr:Cost>0.125</cr:Cost>
+    <cr:AllocatedDisk>0</cr:AllocatedDisk>
+    <cr:Flavour>ssc.small</cr:Flavour>
+    <cr:AllocatedCPU>1.0</cr:AllocatedCPU>
+    <cr:AllocatedMemory>2048</cr:AllocatedMemory>
+    <cr:UsedCPU>0.5</cr:UsedCPU>
+    <cr:BenchmarkType>hepspec</cr:BenchmarkType>
+  </cr:CloudComputeRecord>
+  <cr:CloudStorageRecord>
+    <cr:RecordIdentity cr:createTime="2019-02-13T12:15:00+00:00" cr:recordId="ssc/HPC2N/sr/41d169a8-e2e8-4e81-a8d0-6fda07316251/1550059200" />
+    <cr:Site>HPC2N</cr:Site>
+    <cr:Project>SNIC 2018/10-20</cr:Project>
+    <cr:User>s3245</cr:User>
+    <cr:InstanceId>41d169a8-e2e8-4e81-a8d0-6fda07316251</cr:InstanceId>
+    <cr:StartTime>2019-02-13T11:00:00+00:00</cr:StartTime>
+    <cr:EndTime>2019-02-13T12:00:00+00:00</cr:EndTime>
+    <cr:Duration>PT3600S</cr:Duration>
+    <cr:Region>HPC2N</cr:Region>
+    <cr:Resource>SE-SNIC-SSC</cr:Resource>
+    <cr:Zone>nova</cr:Zone>
+    <cr:Cost>0.001</cr:Cost>
+    <cr:AllocatedDisk>10737418240</cr:AllocatedDisk>
+    <cr:StorageType>Block</cr:StorageType>
+    <cr:FileCount>0</cr:FileCount>
+  </cr:CloudStorageRecord>
+</cr:CloudRecords>"#;
+
+    #[test]
+    fn durations_in_iso8601_seconds() {
+        use super::iso8601_duration;
+        use chrono::Duration;
+
+        assert_eq!(iso8601_duration(Duration::hours(1)), "PT3600S");
+        assert_eq!(iso8601_duration(Duration::hours(3)), "PT10800S");
+        assert_eq!(iso8601_duration(Duration::days(1)), "PT86400S");
+        assert_eq!(iso8601_duration(Duration::zero()), "PT0S");
+        assert_eq!(iso8601_duration(Duration::milliseconds(1500)), "PT1.5S");
+        assert_eq!(iso8601_duration(Duration::seconds(-90)), "-PT90S");
+    }
 }
@@ -0,0 +1,88 @@
+//! Fields of OpenStack and radosgw responses that the logger does not model.
+//!
+//! Built with the `strict` feature, the response types keep the keys they do not model, and keys
+//! outside those each type is known to carry are logged, so that renamed or added attributes are
+//! noticed before they silently affect billing.
+
+use std::collections::BTreeMap;
+
+/// Keys of a response object that its type does not model.
+pub type UnknownFields = BTreeMap<String, serde_json::Value>;
+
+/// A response type along with the keys it carries that the logger deliberately ignores.
+pub trait KnownFields {
+    /// Keys the API returns for the type that the logger does not need.
+    const IGNORED: &'static [&'static str];
+
+    #[cfg(feature = "strict")]
+    fn unknown_fields(&self) -> &UnknownFields;
+
+    /// Checks the objects nested in this one.
+    #[cfg(feature = "strict")]
+    fn check_nested(&self, _unexpected: &mut UnexpectedFields) {}
+}
+
+/// Implements `KnownFields` for a type that keeps its unmodelled keys in `unknown`.
+macro_rules! known_fields {
+    ($ty:ty, [$($key:expr),* $(,)?]) => {
+        impl crate::strict::KnownFields for $ty {
+            const IGNORED: &'static [&'static str] = &[$($key),*];
+
+            #[cfg(feature = "strict")]
+            fn unknown_fields(&self) -> &crate::strict::UnknownFields {
+                &self.unknown
+            }
+        }
+    };
+}
+
+/// Unexpected keys by the name of the type they were found in, with the number of objects that
+/// had each.
+#[derive(Debug, Default)]
+pub struct UnexpectedFields(BTreeMap<(&'static str, String), usize>);
+
+impl UnexpectedFields {
+    #[cfg(feature = "strict")]
+    pub fn check<T: KnownFields>(&mut self, item: &T) {
+        let name = std::any::type_name::<T>().trim_start_matches("ssc_billing_logger::");
+        for key in item.unknown_fields().keys() {
+            if !T::IGNORED.contains(&key.as_str()) {
+                *self.0.entry((name, key.clone())).or_insert(0) += 1;
+            }
+        }
+        item.check_nested(self);
+    }
+
+    /// The unexpected keys along with the name of their type, in order.
+    pub fn keys(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.0.keys().map(|(name, key)| (*name, key.as_str()))
+    }
+}
+
+/// Warns about the keys of `items` that are neither modelled nor known to be ignored, returning
+/// them. Nothing is found without the `strict` feature.
+pub fn check_fields<'a, T, I>(items: I) -> UnexpectedFields
+where
+    T: KnownFields + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    #[cfg(feature = "strict")]
+    let unexpected = items
+        .into_iter()
+        .fold(UnexpectedFields::default(), |mut unexpected, item| {
+            unexpected.check(item);
+            unexpected
+        });
+    #[cfg(not(feature = "strict"))]
+    let unexpected = {
+        let _ = items;
+        UnexpectedFields::default()
+    };
+    for ((name, key), count) in &unexpected.0 {
+        warn!(
+            "{} {} objects have the unknown field {:?}",
+            count, name, key
+        );
+    }
+    unexpected
+}
@@ -0,0 +1,89 @@
+//! Stub HTTP servers on localhost for the tests of the OpenStack and radosgw clients.
+
+use std::collections::HashMap;
+use url::Url;
+
+/// A JSON response with `status`, such as `200 OK`, and any extra header lines.
+pub fn json_response(status: &str, extra_headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\n{}\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        extra_headers,
+        body.len(),
+        body
+    )
+}
+
+pub const NOT_FOUND: &str =
+    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// A listener on a free port of localhost along with its URL.
+pub fn local_listener() -> (std::net::TcpListener, Url) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/", listener.local_addr().unwrap());
+    (listener, Url::parse(&base).unwrap())
+}
+
+/// Answers HTTP requests on `listener` with `respond`, which is given the method, the path and
+/// query, and the header lines of each request with their names lowercased.
+pub fn serve_requests<F>(listener: std::net::TcpListener, respond: F)
+where
+    F: Fn(&str, &str, &[String]) -> String + Send + 'static,
+{
+    use std::io::{BufRead, BufReader, Read, Write};
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header).unwrap() <= 2 {
+                    break;
+                }
+                let header = header.trim_end();
+                headers.push(match header.split_once(':') {
+                    Some((name, value)) => format!("{}:{}", name.to_lowercase(), value),
+                    None => header.to_owned(),
+                });
+            }
+            let content_length = headers
+                .iter()
+                .find_map(|h| h.strip_prefix("content-length:"))
+                .and_then(|len| len.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            std::io::copy(
+                &mut reader.by_ref().take(content_length),
+                &mut std::io::sink(),
+            )
+            .unwrap();
+            let mut parts = request_line.split(' ');
+            let method = parts.next().unwrap_or_default();
+            let target = parts.next().unwrap_or_default();
+            let response = respond(method, target, &headers);
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+}
+
+/// Serves the fixture files given by request path and query over HTTP on localhost,
+/// replacing `{base}` in them with the URL of the server. Returns that URL.
+pub fn serve_fixtures(routes: &[(&str, &str)]) -> Url {
+    let (listener, url) = local_listener();
+    let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let routes: HashMap<String, String> = routes
+        .iter()
+        .map(|(target, name)| {
+            let body = std::fs::read_to_string(fixtures.join(name)).unwrap();
+            (target.to_string(), body.replace("{base}", url.as_str()))
+        })
+        .collect();
+    serve_requests(listener, move |_, target, _| match routes.get(target) {
+        Some(body) => json_response("200 OK", "", body),
+        None => NOT_FOUND.to_owned(),
+    });
+    url
+}
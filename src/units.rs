@@ -0,0 +1,253 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign};
+
+const BYTES_PER_KILOBYTE: u64 = 1024;
+const BYTES_PER_MEGABYTE: u64 = 1024 * 1024;
+const BYTES_PER_GIGABYTE: u64 = 1024 * 1024 * 1024;
+
+/// Number of decimal places storage costs are rounded to.
+pub const COST_DECIMAL_PLACES: u32 = 10;
+
+/// A size in bytes, as reported in `cr:AllocatedDisk`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(pub u64);
+
+/// A size in whole gigabytes (GiB), as used by Nova flavors and Cinder volumes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Gigabytes(pub u64);
+
+/// A size in megabytes (MiB), as used for Nova flavor swap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Megabytes(pub u64);
+
+/// A size in kilobytes (KiB), as reported by radosgw bucket statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Kilobytes(pub u64);
+
+/// Unit used for `cr:AllocatedMemory` in emitted records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MemoryUnit {
+    #[default]
+    #[serde(rename = "MB")]
+    Megabytes,
+    #[serde(rename = "GB")]
+    Gigabytes,
+}
+
+impl MemoryUnit {
+    /// Expresses an amount of memory in this unit.
+    pub fn convert(self, memory: Megabytes) -> Decimal {
+        match self {
+            MemoryUnit::Megabytes => Decimal::from(memory.0),
+            MemoryUnit::Gigabytes => Decimal::from(memory.0) / Decimal::from(1024u64),
+        }
+    }
+}
+
+/// How `cr:AllocatedDisk` is emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskRounding {
+    /// The exact number of bytes.
+    #[default]
+    Exact,
+    /// Rounded up to whole gigabytes (GiB).
+    CeilGigabytes,
+}
+
+impl DiskRounding {
+    /// Applies the rounding to a size.
+    pub fn round(self, size: Bytes) -> Bytes {
+        match self {
+            DiskRounding::Exact => size,
+            DiskRounding::CeilGigabytes => {
+                let gigs = size.0.div_ceil(BYTES_PER_GIGABYTE);
+                Bytes(gigs.saturating_mul(BYTES_PER_GIGABYTE))
+            }
+        }
+    }
+}
+
+impl Bytes {
+    /// Fractional number of gigabytes, for use in per-gigabyte cost computations.
+    pub fn as_gigabytes(self) -> Decimal {
+        Decimal::from(self.0) / Decimal::from(BYTES_PER_GIGABYTE)
+    }
+}
+
+/// Cost of storing `size` at `rate` per gigabyte, rounded half to even to
+/// `COST_DECIMAL_PLACES` decimal places.
+///
+/// The size is multiplied by the rate before dividing so that small sizes keep their precision,
+/// falling back to dividing first if the product would overflow.
+pub fn gigabyte_cost(size: Bytes, rate: Decimal) -> Decimal {
+    let bytes = Decimal::from(size.0);
+    let per_gigabyte = Decimal::from(BYTES_PER_GIGABYTE);
+    let cost = match bytes.checked_mul(rate) {
+        Some(product) => product / per_gigabyte,
+        None => size.as_gigabytes() * rate,
+    };
+    cost.round_dp(COST_DECIMAL_PLACES)
+}
+
+impl From<Gigabytes> for Bytes {
+    fn from(gb: Gigabytes) -> Bytes {
+        Bytes(gb.0.saturating_mul(BYTES_PER_GIGABYTE))
+    }
+}
+
+impl From<Megabytes> for Bytes {
+    fn from(mb: Megabytes) -> Bytes {
+        Bytes(mb.0.saturating_mul(BYTES_PER_MEGABYTE))
+    }
+}
+
+impl From<Kilobytes> for Bytes {
+    fn from(kb: Kilobytes) -> Bytes {
+        Bytes(kb.0.saturating_mul(BYTES_PER_KILOBYTE))
+    }
+}
+
+impl Add for Bytes {
+    type Output = Bytes;
+
+    fn add(self, rhs: Bytes) -> Bytes {
+        Bytes(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Bytes {
+    fn add_assign(&mut self, rhs: Bytes) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sum for Bytes {
+    fn sum<I: Iterator<Item = Bytes>>(iter: I) -> Bytes {
+        iter.fold(Bytes::default(), Add::add)
+    }
+}
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn gigabytes_to_bytes() {
+        assert_eq!(Bytes::from(Gigabytes(0)), Bytes(0));
+        assert_eq!(Bytes::from(Gigabytes(1)), Bytes(1073741824));
+        assert_eq!(Bytes::from(Gigabytes(10)), Bytes(10737418240));
+        assert_eq!(Bytes::from(Gigabytes(u64::MAX)), Bytes(u64::MAX));
+    }
+
+    #[test]
+    fn megabytes_to_bytes() {
+        assert_eq!(Bytes::from(Megabytes(1)), Bytes(1048576));
+        assert_eq!(Bytes::from(Megabytes(1024)), Bytes::from(Gigabytes(1)));
+        assert_eq!(Bytes::from(Megabytes(u64::MAX)), Bytes(u64::MAX));
+    }
+
+    #[test]
+    fn kilobytes_to_bytes() {
+        assert_eq!(Bytes::from(Kilobytes(1)), Bytes(1024));
+        assert_eq!(
+            Bytes::from(Kilobytes(1024 * 1024)),
+            Bytes::from(Gigabytes(1))
+        );
+        assert_eq!(Bytes::from(Kilobytes(u64::MAX)), Bytes(u64::MAX));
+    }
+
+    #[test]
+    fn bytes_as_gigabytes() {
+        assert_eq!(Bytes(0).as_gigabytes(), Decimal::from(0));
+        assert_eq!(Bytes::from(Gigabytes(3)).as_gigabytes(), Decimal::from(3));
+        assert_eq!(
+            Bytes(BYTES_PER_GIGABYTE / 4).as_gigabytes(),
+            Decimal::from_str("0.25").unwrap()
+        );
+    }
+
+    #[test]
+    fn gigabyte_cost_at_boundary_sizes() {
+        let rate = Decimal::from_str("0.01").unwrap();
+        assert_eq!(gigabyte_cost(Bytes(0), rate), Decimal::from(0));
+        // 0.01 / 2^30 is about 9.3e-12, below the cost precision.
+        assert!(gigabyte_cost(Bytes(1), rate).is_zero());
+        assert_eq!(
+            gigabyte_cost(Bytes(1), Decimal::from(1000)),
+            Decimal::from_str("0.0000009313").unwrap()
+        );
+        assert_eq!(gigabyte_cost(Gigabytes(1).into(), rate), rate);
+        assert_eq!(
+            gigabyte_cost(Bytes(BYTES_PER_GIGABYTE / 2), rate),
+            Decimal::from_str("0.005").unwrap()
+        );
+        // 5 TiB
+        assert_eq!(
+            gigabyte_cost(Gigabytes(5 * 1024).into(), rate),
+            Decimal::from_str("51.2").unwrap()
+        );
+        assert_eq!(
+            gigabyte_cost(Bytes(u64::MAX), Decimal::from(1_000_000_000)),
+            Decimal::from_str("17179869183999999999.068677425").unwrap()
+        );
+    }
+
+    #[test]
+    fn memory_unit_conversion() {
+        assert_eq!(MemoryUnit::default(), MemoryUnit::Megabytes);
+        assert_eq!(
+            MemoryUnit::Megabytes.convert(Megabytes(2048)),
+            Decimal::from(2048)
+        );
+        assert_eq!(
+            MemoryUnit::Gigabytes.convert(Megabytes(2048)),
+            Decimal::from(2)
+        );
+        assert_eq!(
+            MemoryUnit::Gigabytes.convert(Megabytes(512)),
+            Decimal::from_str("0.5").unwrap()
+        );
+        assert_eq!(
+            MemoryUnit::Megabytes.convert(Megabytes(2048)).to_string(),
+            "2048"
+        );
+    }
+
+    #[test]
+    fn bytes_sum_saturates() {
+        let total: Bytes = vec![Bytes(1), Bytes(2), Bytes(3)].into_iter().sum();
+        assert_eq!(total, Bytes(6));
+        assert_eq!(Bytes(u64::MAX) + Bytes(1), Bytes(u64::MAX));
+        assert_eq!(Bytes(42).to_string(), "42");
+    }
+
+    #[test]
+    fn disk_rounding_to_whole_gigabytes() {
+        let image = Bytes(1_500_000_000);
+        assert_eq!(DiskRounding::Exact.round(image), image);
+        assert_eq!(
+            DiskRounding::CeilGigabytes.round(image),
+            Gigabytes(2).into()
+        );
+        assert_eq!(
+            DiskRounding::CeilGigabytes.round(Gigabytes(3).into()),
+            Gigabytes(3).into()
+        );
+        assert_eq!(DiskRounding::CeilGigabytes.round(Bytes(0)), Bytes(0));
+        assert_eq!(
+            DiskRounding::CeilGigabytes.round(Bytes(1)),
+            Gigabytes(1).into()
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! Memory use of decoding responses, in a test binary of its own as it replaces the global
+//! allocator.
+
+use serde_json::json;
+use ssc_billing_logger::openstack::{decode, nova};
+
+/// Counts the live and peak bytes allocated by the current thread, for the test of memory use
+/// below.
+struct CountingAlloc;
+
+thread_local! {
+    static LIVE: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static PEAK: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = LIVE.try_with(|live| {
+            live.set(live.get() + layout.size());
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+        });
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        let _ = LIVE.try_with(|live| live.set(live.get().saturating_sub(layout.size())));
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Bytes allocated at most by `f` on top of what was live before it.
+fn peak_allocation<T>(f: impl FnOnce() -> T) -> usize {
+    let before = LIVE.with(|live| live.get());
+    PEAK.with(|peak| peak.set(before));
+    drop(f());
+    PEAK.with(|peak| peak.get()) - before
+}
+
+#[test]
+fn large_body_decoded_without_buffering() {
+    let server = |i: usize| {
+        json!({
+            "id": format!("server-{}", i),
+            "user_id": "u1",
+            "tenant_id": "p1",
+            "flavor": {"id": "f1"},
+            "image": "",
+            "status": "ACTIVE",
+            "OS-EXT-AZ:availability_zone": "nova",
+            "os-extended-volumes:volumes_attached": [],
+            "tags": ["x".repeat(1000)],
+        })
+    };
+    let body = json!({"servers": (0..2000).map(server).collect::<Vec<_>>()}).to_string();
+    assert!(body.len() > 2_000_000);
+
+    let streamed = peak_allocation(|| {
+        let servers: nova::Servers = decode(body.as_bytes(), "servers.json").unwrap();
+        assert_eq!(servers.servers.len(), 2000);
+    });
+    let buffered = peak_allocation(|| {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut body.as_bytes(), &mut text).unwrap();
+        let servers: nova::Servers = serde_json::from_str(&text).unwrap();
+        assert_eq!(servers.servers.len(), 2000);
+    });
+    assert!(
+        streamed + body.len() / 2 < buffered,
+        "streamed {} buffered {}",
+        streamed,
+        buffered
+    );
+}